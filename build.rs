@@ -0,0 +1,67 @@
+//! Firmware build ID, published to the APU at boot.
+//!
+//! The build ID is, in order of preference:
+//! - `QAFM_BUILD_ID` from the environment, as 8 hex digits
+//! - the abbreviated hash of the git commit, as 8 hex digits
+//! - the build time, in seconds since the UNIX epoch modulo 2^32, e.g. in a container without
+//!   git
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=QAFM_BUILD_ID");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    // a new commit on the current branch moves the branch, not HEAD
+    for path in branch_refs() {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+
+    let build_id = env::var("QAFM_BUILD_ID")
+        .ok()
+        .and_then(|id| u32::from_str_radix(id.trim_start_matches("0x"), 16).ok())
+        .or_else(git_hash)
+        .unwrap_or_else(|| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            now.as_secs() as u32
+        });
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("build_id.rs");
+    fs::write(out, format!("const BUILD_ID: u32 = {:#010x};\n", build_id)).unwrap();
+}
+
+/// First 32 bits of the hash of the current git commit, if any.
+fn git_hash() -> Option<u32> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let hash = String::from_utf8(output.stdout).ok()?;
+    u32::from_str_radix(hash.get(..8)?, 16).ok()
+}
+
+/// Files holding the commit of the current git branch, if any: the branch itself, and the
+/// packed refs once git moved it there.
+///
+/// Only files that exist are given, cargo would otherwise run the build script every time. A
+/// branch moved to the packed refs deletes its file, which cargo notices as well.
+fn branch_refs() -> Vec<String> {
+    let head = fs::read_to_string(".git/HEAD").unwrap_or_default();
+    let branch = head
+        .strip_prefix("ref: ")
+        .map(|name| format!(".git/{}", name.trim()));
+    branch
+        .into_iter()
+        .chain(Some(".git/packed-refs".to_string()))
+        .filter(|path| Path::new(path).exists())
+        .collect()
+}
@@ -1,6 +1,9 @@
+import functools
+import json
 import struct
 import sys
 import time
+from contextlib import contextmanager
 from typing import Optional, Tuple
 
 import numpy as np
@@ -8,6 +11,142 @@ import numpy as np
 from presto import lockin
 from presto.hardware import AdcMode, DacMode
 
+# handshake, slots IDENTITY and BUILD_SEQUENCE
+PARAMS_MAGIC = int.from_bytes(b"QAFM", byteorder="little")
+LAYOUT_VERSION = 5
+
+# flags of the RPU control word, slot CONTROL
+CTRL_HOLD = 1 << 0
+CTRL_DERIV_ON_ERROR = 1 << 1
+CTRL_PHYSICAL_UNITS = 1 << 2
+CTRL_LAW_SHIFT = 3
+CTRL_LAW_MASK = 0b11 << CTRL_LAW_SHIFT
+CTRL_MODE_SHIFT = 5
+CTRL_MODE_MASK = 0b11 << CTRL_MODE_SHIFT
+CTRL_TUNE = 1 << 7
+CTRL_SCHED_SHIFT = 8
+CTRL_SCHED_MASK = 0b11 << CTRL_SCHED_SHIFT
+CTRL_ANALYZER = 1 << 10
+CTRL_SIGNAL_SHIFT = 11
+CTRL_SIGNAL_MASK = 0b11 << CTRL_SIGNAL_SHIFT
+CTRL_PLL = 1 << 13
+CTRL_AGC = 1 << 14
+CTRL_IMOD = 1 << 15
+CTRL_GEN_SHIFT = 24
+CTRL_GEN_MASK = 0xFF << CTRL_GEN_SHIFT
+
+# kind of lockin, slot LOCKIN_CONFIG
+LOCKIN_MODE_MASK = 0b11
+LOCKIN = 0
+LOCKIN_SYMMETRIC = 1
+
+# averaging of the carrier on the RPU, slot LOCKIN_CONFIG
+LOCKIN_AVERAGE_SHIFT = 2
+LOCKIN_AVERAGE_MASK = 0b11 << LOCKIN_AVERAGE_SHIFT
+LOCKIN_WINDOW_SHIFT = 16
+AVERAGE_OFF = 0
+AVERAGE_BOXCAR = 1
+AVERAGE_EXPONENTIAL = 2
+AVERAGE_PIXELS = 1024
+
+# error signals of the Z feedback
+SIGNAL_AMP2 = 0
+SIGNAL_AMPLITUDE = 1
+SIGNAL_PHASE = 2
+SIGNAL_FREQUENCY_SHIFT = 3
+
+# combinations of the tones of an intermodulation spectrum
+COMBINE_AMPLITUDES = 0
+COMBINE_QUADRATURES = 1
+COMBINE_PHASES = 2
+
+# operating point of the gain schedule
+SCHED_OFF = 0
+SCHED_SETPOINT = 1
+SCHED_Z_BIAS = 2
+
+# operating modes of the Z feedback
+MODE_AUTO = 0
+MODE_HOLD = 1
+MODE_MANUAL = 2
+MODE_TRACK = 3
+
+# control laws for the Z feedback
+LAW_PID = 0
+LAW_LEAD_LAG = 1
+LAW_IIR = 2
+
+# blocks in the RPU coefficient bank
+BANK_LEAD_LAG = 0
+BANK_IIR = 8
+BANK_AMP2_FILTER = 32
+BANK_Z_FILTER = 64
+BANK_AUTOTUNE = 96
+BANK_PID_OPTIONS = 100
+BANK_SCHEDULE = 104
+SCHEDULE_ENTRIES = 8
+BANK_ANALYZER = 140
+BANK_PHASE_REFERENCE = 146
+BANK_PLL = 148
+BANK_AGC = 156
+BANK_FRAME = 164
+FRAME_GROUPS = 8
+BANK_IMOD = 176
+IMOD_TONES = 32
+BANK_SCANNER_SLEW = 243
+BANK_TELEMETRY = 244
+BANK_SCAN = 245
+BANK_DERIVATIVE_FILTER = 252
+BANK_RATE_LIMITS = 254
+
+# anti-windup strategies of the PID controller, BANK_PID_OPTIONS + 2
+ANTI_WINDUP_CLAMP = 0
+ANTI_WINDUP_BACK_CALCULATION = 1
+ANTI_WINDUP_CONDITIONAL = 2
+
+# network analyzer results in the RPU data area
+DATA_ANALYZER = 2048
+ANALYZER_MAX_POINTS = 256
+
+# commands in slot COMMAND, answered in slot RESPONSE
+CMD_RESET_INTEGRATOR = 1
+CMD_HOLD = 2
+CMD_RETRACT = 3
+CMD_START_SCAN = 4
+CMD_STOP_SCAN = 5
+CMD_SELF_TEST = 6
+# completion of a command
+CMD_DONE = 0
+CMD_RUNNING = 1
+CMD_UNKNOWN = 2
+CMD_REJECTED = 3
+CMD_FAILED = 4
+# failed checks of the self-test
+TEST_PARAMS = 1 << 0
+TEST_LOCKIN = 1 << 1
+TEST_PERIOD = 1 << 2
+TEST_Z_LIMIT = 1 << 3
+
+# reasons the RPU rejected a parameter, slot PARAM_STATUS
+PARAM_VALID = 0
+PARAM_NOT_FINITE = 1
+PARAM_OUT_OF_RANGE = 2
+PARAM_NOT_INCREASING = 3
+
+# states of the relay autotuning, slot AUTOTUNE_KD_STATE
+TUNE_IDLE = 0
+TUNE_RUNNING = 1
+TUNE_DONE = 2
+TUNE_FAILED = 3
+
+# biquad filter sections
+FILTER_SECTIONS = 4
+FILTER_BYPASS = 0
+FILTER_LOW_PASS = 1
+FILTER_NOTCH = 2
+FILTER_BAND_STOP = 3
+FILTER_CUSTOM = 4
+
 
 def main(*, address: str, port: Optional[int] = None):
     IN_PORT = 1
@@ -51,14 +190,28 @@ def main(*, address: str, port: Optional[int] = None):
         # program scaling factor for feedback
         program_scale(lck, NSW)
         program_limits(lck, 0.0, 1.0)
+        # start from a clean control word
+        param_map().clear(lck, "CONTROL")
+        # plain lockin at zero IF
+        program_lockin_mode(lck, LOCKIN, 0.0, df)
+        program_setpoint_weights(lck, 1.0, 1.0)
         # program (starting) feedback parameters
         program_feedback(lck, 0.001, 660.0, 69.0, 4200.0)
+        # forget the handshake of a previous run
+        param_map().clear(lck, "IDENTITY")
 
         with lck.stream_pixels(
             summed=True,
             nsum=NSW,
             rpu_params=("qafm", [0, 1], NSW),
         ) as rcv:
+            build_id = wait_for_firmware(lck)
+            print(f"RPU firmware build {build_id:08x}")
+            # settings in the coefficient bank, which starts cleared
+            # the RPU runs one iteration per lockin pixel
+            program_units(lck, False, sample_rate=df)
+            program_derivative(lck, 3.5, on_error=False)
+            program_rate_limits(lck, 0.0, 0.0)  # no limits
             # monitor feedback results
             while True:
                 print_pix(rcv, IN_PORT)
@@ -66,6 +219,123 @@ def main(*, address: str, port: Optional[int] = None):
                 time.sleep(1)
 
 
+def wait_for_firmware(lck: lockin.Lockin, timeout: float = 1.0) -> int:
+    """Wait for the RPU firmware to boot, and check that it has the expected parameter layout.
+
+    Clear the ``IDENTITY`` slot before starting the firmware, so that the handshake of a previous
+    run is not mistaken for the current one.
+
+    Args:
+        lck: an active instance of Lockin
+        timeout: time in seconds to wait for the firmware
+
+    Returns:
+        the build ID of the firmware
+    """
+    t_end = time.monotonic() + timeout
+    while True:
+        magic, version = param_map().read(lck, "IDENTITY")
+        if magic == PARAMS_MAGIC:
+            break
+        if time.monotonic() > t_end:
+            raise TimeoutError("RPU firmware did not publish its parameter layout")
+        time.sleep(1e-3)
+    if version != LAYOUT_VERSION:
+        raise RuntimeError(
+            f"RPU firmware has parameter layout {version}, this script is for {LAYOUT_VERSION}"
+        )
+    build_id, _ = param_map().read(lck, "BUILD_SEQUENCE")
+    return build_id
+
+
+class ParamMap:
+    """Slots of the RPU parameter map, from the descriptor embedded in the firmware.
+
+    The firmware declares its parameter map once, and stores a JSON description of it in the
+    ``.qafm.params`` section of its ELF file. Use it instead of hard-coded slot indices, e.g.::
+
+        param_map = ParamMap.from_elf("target/armv7r-none-eabihf/release/qafm")
+        param_map.write(lck, "SCANNER_XY", 0.5, 0.5)
+        error, z_bias = param_map.read(lck, "ERROR_CONTROL")
+    """
+
+    _FORMATS = {"f32x2": "<ff", "u32x2": "<II", "u32_f32": "<If", "f32_u32": "<fI"}
+
+    def __init__(self, descriptor: dict):
+        if descriptor["magic"] != "QAFM":
+            raise ValueError("not a QAFM parameter map descriptor")
+        self.version = descriptor["version"]
+        self.slots = {slot["name"]: slot for slot in descriptor["slots"]}
+
+    @classmethod
+    def from_elf(cls, path: str) -> "ParamMap":
+        """Read the descriptor from the ``.qafm.params`` section of the firmware ELF file."""
+        with open(path, "rb") as f:
+            elf = f.read()
+        if elf[:4] != b"\x7fELF":
+            raise ValueError(f"{path} is not an ELF file")
+        is_64 = elf[4] == 2
+        endian = "<" if elf[5] == 1 else ">"
+        if is_64:
+            shoff, = struct.unpack_from(endian + "Q", elf, 0x28)
+            shentsize, shnum, shstrndx = struct.unpack_from(endian + "HHH", elf, 0x3A)
+            section = endian + "IIQQQQ"
+        else:
+            shoff, = struct.unpack_from(endian + "I", elf, 0x20)
+            shentsize, shnum, shstrndx = struct.unpack_from(endian + "HHH", elf, 0x2E)
+            section = endian + "IIIIII"
+        headers = [
+            struct.unpack_from(section, elf, shoff + k * shentsize) for k in range(shnum)
+        ]
+        # name, type, flags, address, offset, size
+        strtab = headers[shstrndx]
+        for name, _, _, _, offset, size in headers:
+            start = strtab[4] + name
+            if elf[start : elf.index(b"\0", start)] == b".qafm.params":
+                return cls(json.loads(elf[offset : offset + size]))
+        raise ValueError(f"{path} has no parameter map descriptor")
+
+    def pack(self, name: str, low, high) -> Tuple[int, int]:
+        """Index of slot ``name``, and the word holding ``low`` and ``high``."""
+        slot = self.slots[name]
+        word = int.from_bytes(struct.pack(self._FORMATS[slot["type"]], low, high), "little")
+        return slot["idx"], word
+
+    def unpack(self, name: str, word: int) -> tuple:
+        """Values in the low and high half of a word of slot ``name``."""
+        slot = self.slots[name]
+        return struct.unpack(self._FORMATS[slot["type"]], word.to_bytes(8, "little"))
+
+    def write(self, lck: lockin.Lockin, name: str, low, high):
+        """Write slot ``name``, which must be read by the RPU."""
+        if self.slots[name]["dir"] != "read":
+            raise ValueError(f"slot {name} is written by the RPU")
+        lck.hardware.set_rpu_param(*self.pack(name, low, high))
+
+    def read(self, lck: lockin.Lockin, name: str) -> tuple:
+        """Read slot ``name``."""
+        return self.unpack(name, lck.hardware.get_rpu_param(self.slots[name]["idx"]))
+
+    def clear(self, lck: lockin.Lockin, name: str):
+        """Clear slot ``name`` to zero, also a slot written by the RPU, e.g. before it starts."""
+        lck.hardware.set_rpu_param(self.slots[name]["idx"], 0)
+
+
+# the firmware this script is for, as in upload_firmware.py
+FW_PATH = "../target/armv7r-none-eabihf/release/qafm"
+
+
+@functools.lru_cache(maxsize=None)
+def param_map() -> ParamMap:
+    """Slots of the parameter map of the firmware in ``FW_PATH``, read on first use."""
+    params = ParamMap.from_elf(FW_PATH)
+    if params.version != LAYOUT_VERSION:
+        raise RuntimeError(
+            f"{FW_PATH} has parameter layout {params.version}, this script is for {LAYOUT_VERSION}"
+        )
+    return params
+
+
 def program_scale(lck: lockin.Lockin, nsw: int):
     """Set the scaling factor for lockin data to the RPU.
 
@@ -76,8 +346,9 @@ def program_scale(lck: lockin.Lockin, nsw: int):
     scale_slw = 1.0 / nsw  # divide by NSW to get sliding average instead of sliding sum
     scale = scale_acc * scale_spp * scale_slw
 
-    # high bits not used, set to 0.0
-    lck.hardware.set_rpu_param(2, f32x2_to_u64(scale, 0.0))
+    # keep the feedforward term in the high bits
+    _, feedforward = param_map().read(lck, "SCALE_FEEDFORWARD")
+    param_map().write(lck, "SCALE_FEEDFORWARD", scale, feedforward)
 
 
 def program_limits(lck: lockin.Lockin, low: float, high: float):
@@ -91,7 +362,7 @@ def program_limits(lck: lockin.Lockin, low: float, high: float):
     assert low >= 0.0
     assert high <= 1.0
     assert low < high
-    lck.hardware.set_rpu_param(6, f32x2_to_u64(low, high))
+    param_map().write(lck, "Z_LIMITS", low, high)
 
 
 def program_feedback(
@@ -100,20 +371,768 @@ def program_feedback(
     kp: float,
     ki: float,
     kd: float,
+    *,
+    handover: bool = False,
 ):
     """Set the PID controller parameters to the RPU.
 
-    Can be changed while the feedback is running.
+    Can be changed while the feedback is running. Gain changes are always bumpless; with
+    ``handover=True`` the Z output is also held while the parameters are written, and the
+    feedback continues from the held value also after a set point change.
 
     Args:
         lck: an active instance of Lockin
         sp: set point
         kp: proportional gain
-        ki: integral gain
-        kd: derivative gain
+        ki: integral gain, or integral time in seconds with physical units
+        kd: derivative gain, or derivative time in seconds with physical units
+        handover: hold the Z output and re-seed the integrator during the change
+    """
+    if handover:
+        set_control_flag(lck, CTRL_HOLD, True)
+    with param_group(lck):
+        param_map().write(lck, "SETPOINT_KP", sp, kp)
+        param_map().write(lck, "KI_KD", ki, kd)
+    if handover:
+        set_control_flag(lck, CTRL_HOLD, False)
+
+
+def program_derivative(lck: lockin.Lockin, tau: float, *, on_error: bool = False):
+    """Set the derivative filter and derivative mode of the PID controller.
+
+    Can be changed while the feedback is running.
+
+    Args:
+        lck: an active instance of Lockin
+        tau: time constant of the derivative low-pass filter, in iterations or in seconds with
+            physical units. 0.5 iterations disables the filter, 0.0 is the default of 3.5
+            iterations.
+        on_error: take the derivative of the error instead of the measurement, the derivative
+            term then also reacts to set point changes
+    """
+    upload_coefficients(lck, BANK_DERIVATIVE_FILTER, [tau])
+    set_control_flag(lck, CTRL_DERIV_ON_ERROR, on_error)
+
+
+def program_units(lck: lockin.Lockin, physical: bool, *, sample_rate: float = 0.0):
+    """Select the units of the PID gains and of the derivative filter.
+
+    With physical units, ``ki`` and ``kd`` in :func:`program_feedback` are the integral and
+    derivative times Ti and Td in seconds, and ``tau`` in :func:`program_derivative` is in seconds.
+    The RPU then converts them using the iteration rate, so they don't need to be retuned when the
+    lockin ``df`` changes.
+
+    Args:
+        lck: an active instance of Lockin
+        physical: use physical units instead of per-iteration gains
+        sample_rate: iteration rate of the RPU in Hz, i.e. the lockin pixel rate. With 0.0 the RPU
+            measures it using its approximate clock frequency.
     """
-    lck.hardware.set_rpu_param(3, f32x2_to_u64(sp, kp))
-    lck.hardware.set_rpu_param(4, f32x2_to_u64(ki, kd))
+    upload_coefficients(lck, BANK_DERIVATIVE_FILTER + 1, [sample_rate])
+    set_control_flag(lck, CTRL_PHYSICAL_UNITS, physical)
+
+
+def program_setpoint_weights(lck: lockin.Lockin, b: float, c: float):
+    """Set the set point weights of the PID controller.
+
+    Can be changed while the feedback is running. Weights below 1.0 soften the reaction of the
+    feedback to set point changes, without affecting the rejection of disturbances.
+
+    Args:
+        lck: an active instance of Lockin
+        b: weight of the set point in the proportional term
+        c: weight of the set point in the derivative term, only used with derivative on error
+    """
+    param_map().write(lck, "SETPOINT_WEIGHTS", b, c)
+
+
+def program_rate_limits(
+    lck: lockin.Lockin, sp_rate: float, z_slew: float, xy_slew: float = 0.0
+):
+    """Set the rate limits enforced by the RPU, as maximum change per iteration.
+
+    Can be changed while the feedback is running. A limit of 0.0 disables that limit.
+
+    Args:
+        lck: an active instance of Lockin
+        sp_rate: maximum change of the set point, new set points are approached with a ramp
+        z_slew: maximum change of the normalized DC bias on the Z piezo, in every mode and
+            during autotuning and network analyzer sweeps
+        xy_slew: maximum change of the normalized DC bias on the X and Y scanner, also when a
+            raster scan starts or ends
+    """
+    upload_coefficients(lck, BANK_RATE_LIMITS, [sp_rate, z_slew])
+    upload_coefficients(lck, BANK_SCANNER_SLEW, [xy_slew])
+
+
+def program_control_law(lck: lockin.Lockin, law: int):
+    """Select the control law of the Z feedback.
+
+    Can be changed while the feedback is running, the new law continues from the current Z bias.
+    Upload the coefficients of the lead-lag or IIR compensator with :func:`upload_coefficients`
+    *before* selecting them.
+
+    Args:
+        lck: an active instance of Lockin
+        law: one of ``LAW_PID``, ``LAW_LEAD_LAG`` or ``LAW_IIR``
+    """
+    control, value = param_map().read(lck, "CONTROL")
+    control = (control & ~CTRL_LAW_MASK) | ((law << CTRL_LAW_SHIFT) & CTRL_LAW_MASK)
+    param_map().write(lck, "CONTROL", control, value)
+
+
+def program_error_signal(lck: lockin.Lockin, signal: int, phase_reference: float = 0.0):
+    """Select the quantity of the lockin data used as error signal of the Z feedback.
+
+    The set point and gains must be in units of the new error signal. Hold the Z output while
+    switching, see :func:`program_mode`.
+
+    Args:
+        lck: an active instance of Lockin
+        signal: one of ``SIGNAL_AMP2``, ``SIGNAL_AMPLITUDE``, ``SIGNAL_PHASE`` or
+            ``SIGNAL_FREQUENCY_SHIFT``, the latter needs :func:`start_pll`
+        phase_reference: subtracted from the phase in radians, such that the set point of the
+            phase stays well away from +-pi
+    """
+    upload_coefficients(lck, BANK_PHASE_REFERENCE, [phase_reference])
+    control, value = param_map().read(lck, "CONTROL")
+    control = (control & ~CTRL_SIGNAL_MASK) | ((signal << CTRL_SIGNAL_SHIFT) & CTRL_SIGNAL_MASK)
+    param_map().write(lck, "CONTROL", control, value)
+
+
+def program_lockin_mode(lck: lockin.Lockin, mode: int, if_freq: float, df: float):
+    """Describe the lockin data to the RPU: kind of lockin and intermediate frequency.
+
+    For ``LOCKIN_SYMMETRIC``, also upload the layout of the lockin frame with
+    :func:`program_intermodulation`, so the RPU finds the carrier in the middle of the first
+    input group.
+
+    Args:
+        lck: an active instance of Lockin
+        mode: ``LOCKIN`` or ``LOCKIN_SYMMETRIC``
+        if_freq: intermediate frequency in Hz, 0.0 when perfectly tuned; use the opposite sign
+            if the de-rotated phase still drifts
+        df: pixel rate in Hz, see ``lck.get_df()``
+    """
+    # phase advance per pixel, in units of 2**-32 cycles
+    step = round((if_freq / df) % 1.0 * 2**32) & 0xFFFF_FFFF
+    # keep the averaging
+    config, _ = param_map().read(lck, "LOCKIN_CONFIG")
+    config = (config & ~LOCKIN_MODE_MASK) | (mode & LOCKIN_MODE_MASK)
+    param_map().write(lck, "LOCKIN_CONFIG", config, step)
+
+
+def program_averaging(lck: lockin.Lockin, averaging: int, window: int):
+    """Average the carrier on the RPU over a sliding window of pixels.
+
+    Replaces the sliding sum of the lockin: stream with ``nsum=1`` and call
+    ``program_scale(lck, 1)``, since the RPU averages instead of summing. Can be changed while
+    the feedback is running.
+
+    Args:
+        lck: an active instance of Lockin
+        averaging: ``AVERAGE_OFF``, ``AVERAGE_BOXCAR`` or ``AVERAGE_EXPONENTIAL``
+        window: window length in pixels, at most ``AVERAGE_PIXELS`` for the boxcar; time
+            constant in pixels for the exponential moving average
+    """
+    if not 1 <= window < 2**16:
+        raise ValueError(f"window length {window} out of range")
+    # keep the kind of lockin and the IF phase advance
+    config, step = param_map().read(lck, "LOCKIN_CONFIG")
+    config &= LOCKIN_MODE_MASK
+    config |= (averaging << LOCKIN_AVERAGE_SHIFT) & LOCKIN_AVERAGE_MASK
+    config |= window << LOCKIN_WINDOW_SHIFT
+    param_map().write(lck, "LOCKIN_CONFIG", config, step)
+
+
+def program_intermodulation(
+    lck: lockin.Lockin, freqs_per_group, combination: int, weights, *, enable: bool = True
+):
+    """Use a combination of the tones of the lockin frame as error signal of the Z feedback.
+
+    Example, the difference of the amplitudes of the first two tones::
+
+        program_intermodulation(lck, [32], COMBINE_AMPLITUDES, [(1.0, 0.0), (-1.0, 0.0)])
+
+    Args:
+        lck: an active instance of Lockin
+        freqs_per_group: number of frequencies of each input group, in the order the groups were
+            added to the lockin
+        combination: one of ``COMBINE_AMPLITUDES``, ``COMBINE_QUADRATURES`` or
+            ``COMBINE_PHASES``
+        weights: list of ``(w, wq)`` per tone, in the order of the frame; ``wq`` is only used
+            by ``COMBINE_QUADRATURES``, as the weight of Q
+        enable: whether to switch to the intermodulation error signal
+    """
+    if len(freqs_per_group) > FRAME_GROUPS:
+        raise ValueError(f"at most {FRAME_GROUPS} input groups in lockin frame")
+    if len(weights) > IMOD_TONES:
+        raise ValueError(f"at most {IMOD_TONES} tones in intermodulation signal")
+    # unused tones get zero weight
+    padded = list(weights) + [(0.0, 0.0)] * (IMOD_TONES - len(weights))
+    values = [float(combination)]
+    for w, wq in padded:
+        values += [float(w), float(wq)]
+    upload_coefficients(lck, BANK_FRAME, [float(len(freqs_per_group))] + list(freqs_per_group))
+    upload_coefficients(lck, BANK_IMOD, values)
+    set_control_flag(lck, CTRL_IMOD, enable)
+
+
+def start_pll(
+    lck: lockin.Lockin,
+    center: float,
+    kp: float,
+    ki: float,
+    max_shift: float,
+    *,
+    setpoint: float = -np.pi / 2,
+):
+    """Start the phase-locked loop keeping the drive on the cantilever resonance.
+
+    The RPU computes the frequency shift, but does not retune the drive itself: the PLL only
+    runs as fast as :func:`follow_pll` is called.
+
+    Args:
+        lck: an active instance of Lockin
+        center: drive frequency to start from, in Hz, as set in the lockin
+        kp: proportional gain in Hz per radian
+        ki: integral gain in Hz per radian and call of :func:`follow_pll`
+        max_shift: maximum frequency shift from ``center``, in Hz
+        setpoint: phase of the lockin data to lock to, in radians
+    """
+    set_control_flag(lck, CTRL_PLL, False)
+    upload_coefficients(lck, BANK_PLL, [center, setpoint, kp, ki, max_shift, 0.0])
+    set_control_flag(lck, CTRL_PLL, True)
+
+
+def stop_pll(lck: lockin.Lockin):
+    """Stop the phase-locked loop, the frequency shift goes back to zero."""
+    set_control_flag(lck, CTRL_PLL, False)
+
+
+def follow_pll(lck: lockin.Lockin, ig, og, center: float) -> float:
+    """Apply the drive frequency computed by the phase-locked loop, and return the shift.
+
+    The RPU cannot set the frequency of the output generator itself, so this must be called
+    periodically while the PLL is running. The input group follows, so that the lockin keeps
+    demodulating at the drive frequency. The applied shift is written back to the RPU, which
+    holds the PLL until then: the PLL takes one step per call.
+
+    Args:
+        lck: an active instance of Lockin
+        ig: input group of the lockin
+        og: output group driving the cantilever
+        center: center frequency given to :func:`start_pll`
+    """
+    shift, _ = param_map().read(lck, "DRIVE")
+    ig.set_frequencies(center + shift)
+    og.set_frequencies(center + shift)
+    lck.apply_settings()
+    upload_coefficients(lck, BANK_PLL + 5, [shift])
+    return shift
+
+
+def start_agc(
+    lck: lockin.Lockin, amplitude: float, kp: float, ki: float, max_drive: float, drive: float
+):
+    """Start the amplitude control keeping the oscillation amplitude constant with the drive.
+
+    The RPU computes the drive amplitude, but does not set it itself: the amplitude control only
+    runs as fast as :func:`follow_agc` is called.
+
+    Args:
+        lck: an active instance of Lockin
+        amplitude: oscillation amplitude to keep, scaled as the square root of amp^2
+        kp: proportional gain in drive amplitude per amplitude
+        ki: integral gain in drive amplitude per amplitude and call of :func:`follow_agc`
+        max_drive: maximum drive amplitude, at most 1.0
+        drive: drive amplitude to start from, as set in the lockin
+    """
+    set_control_flag(lck, CTRL_AGC, False)
+    upload_coefficients(lck, BANK_AGC, [amplitude, kp, ki, max_drive, drive, drive])
+    set_control_flag(lck, CTRL_AGC, True)
+
+
+def stop_agc(lck: lockin.Lockin):
+    """Stop the amplitude control, the drive amplitude is left to the APU."""
+    set_control_flag(lck, CTRL_AGC, False)
+
+
+def follow_agc(lck: lockin.Lockin, og) -> float:
+    """Apply the drive amplitude computed by the amplitude control, and return it.
+
+    The RPU cannot set the amplitude of the output generator itself, so this must be called
+    periodically while the amplitude control is running. The applied drive amplitude is written
+    back to the RPU, which holds the amplitude control until then: it takes one step per call.
+    The drive amplitude is the dissipation signal.
+
+    Args:
+        lck: an active instance of Lockin
+        og: output group driving the cantilever
+    """
+    _, drive = param_map().read(lck, "DRIVE")
+    if not np.isnan(drive):
+        og.set_amplitudes(drive)
+        lck.apply_settings()
+        upload_coefficients(lck, BANK_AGC + 5, [drive])
+    return drive
+
+
+def program_deadband_leak(lck: lockin.Lockin, deadband: float, leak: float):
+    """Set the error deadband and integrator leak of the PID controller.
+
+    Args:
+        lck: an active instance of Lockin
+        deadband: errors smaller than this are ignored, in units of the error signal; 0.0 for none
+        leak: fraction of the integrator removed every iteration; 0.0 for none
+    """
+    upload_coefficients(lck, BANK_PID_OPTIONS, [deadband, leak])
+
+
+def program_anti_windup(lck: lockin.Lockin, strategy: int, kt: float = 0.5):
+    """Select the anti-windup strategy of the PID controller.
+
+    Args:
+        lck: an active instance of Lockin
+        strategy: one of ``ANTI_WINDUP_CLAMP`` (the default), ``ANTI_WINDUP_BACK_CALCULATION``
+            or ``ANTI_WINDUP_CONDITIONAL``
+        kt: tracking gain of the back-calculation, between 0.0 and 1.0
+    """
+    upload_coefficients(lck, BANK_PID_OPTIONS + 2, [float(strategy), kt])
+
+
+def program_feedforward(lck: lockin.Lockin, feedforward: float):
+    """Set the feedforward term added to the output of the PID controller, as normalized Z bias."""
+    scale, _ = param_map().read(lck, "SCALE_FEEDFORWARD")
+    param_map().write(lck, "SCALE_FEEDFORWARD", scale, feedforward)
+
+
+def program_gain_schedule(lck: lockin.Lockin, source: int, entries=()):
+    """Configure the gain schedule of the PID controller.
+
+    Example, softer gains at high Z bias::
+
+        program_gain_schedule(
+            lck, SCHED_Z_BIAS, [(0.2, 0.001, 660.0, 69.0), (0.8, 0.0005, 330.0, 35.0)]
+        )
+
+    Args:
+        lck: an active instance of Lockin
+        source: one of ``SCHED_OFF``, ``SCHED_SETPOINT`` or ``SCHED_Z_BIAS``
+        entries: list of ``(x, kp, ki, kd)`` with increasing ``x``, gains in the same units as
+            for :func:`program_feedback`; not uploaded if empty
+    """
+    if len(entries) > SCHEDULE_ENTRIES:
+        raise ValueError(f"at most {SCHEDULE_ENTRIES} entries in gain schedule")
+    xs = [entry[0] for entry in entries]
+    if any(x1 <= x0 for x0, x1 in zip(xs, xs[1:])):
+        raise ValueError("gain schedule entries must have increasing x")
+    if entries:
+        # disable the schedule while the table is inconsistent
+        set_schedule_source(lck, SCHED_OFF)
+        values = [float(len(entries))]
+        for entry in entries:
+            values += [float(v) for v in entry]
+        upload_coefficients(lck, BANK_SCHEDULE, values)
+    set_schedule_source(lck, source)
+
+
+def set_schedule_source(lck: lockin.Lockin, source: int):
+    """Select the operating point of the gain schedule, leaving the other fields untouched."""
+    control, value = param_map().read(lck, "CONTROL")
+    control = (control & ~CTRL_SCHED_MASK) | ((source << CTRL_SCHED_SHIFT) & CTRL_SCHED_MASK)
+    param_map().write(lck, "CONTROL", control, value)
+
+
+def program_mode(lck: lockin.Lockin, mode: int, z_bias: Optional[float] = None):
+    """Select the operating mode of the Z feedback.
+
+    Example, retract Z at the slew-rate limit, then resume feedback from there::
+
+        program_mode(lck, MODE_MANUAL, 0.0)
+        ...
+        program_mode(lck, MODE_AUTO)
+
+    Args:
+        lck: an active instance of Lockin
+        mode: one of ``MODE_AUTO``, ``MODE_HOLD``, ``MODE_MANUAL`` or ``MODE_TRACK``
+        z_bias: normalized Z bias for ``MODE_MANUAL`` and ``MODE_TRACK``, written before the
+            mode is changed; ``None`` to keep the current value
+    """
+    control, value = param_map().read(lck, "CONTROL")
+    if z_bias is not None:
+        value = z_bias
+        param_map().write(lck, "CONTROL", control, value)
+    control = (control & ~CTRL_MODE_MASK) | ((mode << CTRL_MODE_SHIFT) & CTRL_MODE_MASK)
+    param_map().write(lck, "CONTROL", control, value)
+
+
+def upload_coefficients(lck: lockin.Lockin, start: int, values, timeout: float = 1.0):
+    """Write coefficients to the RPU coefficient bank, one at a time.
+
+    The bank is in RPU memory, so the feedback firmware must be running. Each write is
+    acknowledged by the RPU before the next one is made.
+
+    Args:
+        lck: an active instance of Lockin
+        start: index in the bank of the first coefficient, e.g. ``BANK_LEAD_LAG``
+        values: the coefficients, e.g. ``[k, zero, pole]`` for the lead-lag compensator, or
+            ``[b0, b1, b2, a1, a2]`` for each biquad section of the IIR compensator
+        timeout: time in seconds to wait for each acknowledgement
+    """
+    tag_index, _ = param_map().read(lck, "BANK_PORT")
+    tag = tag_index >> 16
+    for index, value in enumerate(values, start=start):
+        tag = (tag + 1) & 0xFFFF
+        low = (tag << 16) | (index & 0xFFFF)
+        param_map().write(lck, "BANK_PORT", low, value)
+        t_end = time.monotonic() + timeout
+        while True:
+            ack_low, stored = param_map().read(lck, "BANK_ACK")
+            if ack_low == low:
+                break
+            if time.monotonic() > t_end:
+                raise TimeoutError(f"RPU did not acknowledge coefficient {index}")
+            time.sleep(1e-3)
+        if np.isnan(stored):
+            raise ValueError(
+                f"coefficient {index} rejected by the RPU: outside of the bank or not finite"
+            )
+
+
+def send_command(
+    lck: lockin.Lockin,
+    command: int,
+    argument: float = 0.0,
+    *,
+    wait: bool = True,
+    timeout: float = 1.0,
+) -> Tuple[int, float]:
+    """Send a one-shot command to the RPU, and wait for the answer.
+
+    Each command has the next sequence number, and the RPU answers with the same sequence
+    number. A command that takes a while, e.g. ``CMD_RETRACT``, is first answered as running,
+    and again once done unless another command is sent in the meantime.
+
+    Example, retract Z and resume the feedback from there::
+
+        send_command(lck, CMD_RETRACT, 0.0, timeout=10.0)
+        ...
+        send_command(lck, CMD_HOLD, 0.0)
+
+    Args:
+        lck: an active instance of Lockin
+        command: one of the ``CMD_*`` command codes, e.g. ``CMD_SELF_TEST``
+        argument: the argument of the command
+        wait: wait until the command is done, instead of only until it is accepted
+        timeout: time in seconds to wait for the answer
+
+    Returns:
+        the completion, one of ``CMD_DONE``, ``CMD_RUNNING``, ``CMD_UNKNOWN``, ``CMD_REJECTED``
+        or ``CMD_FAILED``, and the result of the command
+    """
+    seq_request, _ = param_map().read(lck, "COMMAND")
+    seq = ((seq_request >> 16) + 1) & 0xFFFF
+    param_map().write(lck, "COMMAND", (seq << 16) | command, argument)
+    t_end = time.monotonic() + timeout
+    while True:
+        seq_completion, result = param_map().read(lck, "RESPONSE")
+        if seq_completion >> 16 == seq:
+            completion = seq_completion & 0xFFFF
+            if not (wait and completion == CMD_RUNNING):
+                break
+        if time.monotonic() > t_end:
+            raise TimeoutError(f"RPU did not complete command {command}")
+        time.sleep(1e-3)
+    return completion, result
+
+
+def start_scan(
+    lck: lockin.Lockin,
+    x0: float,
+    y0: float,
+    width: float,
+    height: float,
+    pixels: int,
+    lines: int,
+    dwell: int = 1,
+) -> int:
+    """Start a raster scan of the X and Y scanner on the RPU.
+
+    The lines are scanned back and forth, in normalized scanner bias. The RPU ignores the
+    ``SCANNER_XY`` slot until the scan is done, or stopped with
+    ``send_command(lck, CMD_STOP_SCAN)``. The scanner moves to the first pixel, and back to
+    ``SCANNER_XY`` once done, no faster than the ``xy_slew`` of :func:`program_rate_limits`.
+
+    Args:
+        lck: an active instance of Lockin
+        x0: X bias of the first pixel of each line
+        y0: Y bias of the first line
+        width: X bias from the first to the last pixel of a line
+        height: Y bias from the first to the last line
+        pixels: number of pixels per line
+        lines: number of lines
+        dwell: number of RPU iterations at each pixel
+
+    Returns:
+        the completion of the command, ``CMD_RUNNING`` if the scan started
+    """
+    upload_coefficients(
+        lck,
+        BANK_SCAN,
+        [x0, y0, width, height, float(pixels), float(lines), float(dwell)],
+    )
+    completion, _ = send_command(lck, CMD_START_SCAN, wait=False)
+    return completion
+
+
+def program_filter(lck: lockin.Lockin, start: int, sections, sample_rate: float):
+    """Configure the amp^2 or Z bias filter, a cascade of biquad sections.
+
+    Sections not given are bypassed. Example, notch at a Z piezo resonance of 1.2 kHz::
+
+        program_filter(lck, BANK_Z_FILTER, [(FILTER_NOTCH, 1.2e3, 5.0)], df)
+
+    Args:
+        lck: an active instance of Lockin
+        start: ``BANK_AMP2_FILTER`` or ``BANK_Z_FILTER``
+        sections: list of ``(kind, p1, p2, ...)``; frequencies in Hz for ``FILTER_LOW_PASS``
+            ``(f, q)``, ``FILTER_NOTCH`` ``(f, q)`` and ``FILTER_BAND_STOP`` ``(f_lo, f_hi)``,
+            or ``(b0, b1, b2, a1, a2)`` for ``FILTER_CUSTOM``
+        sample_rate: RPU iteration rate in Hz, i.e. the lockin pixel rate
+    """
+    if len(sections) > FILTER_SECTIONS:
+        raise ValueError(f"at most {FILTER_SECTIONS} filter sections")
+    values = []
+    for kind, *p in sections:
+        if kind == FILTER_LOW_PASS or kind == FILTER_NOTCH:
+            p = [p[0] / sample_rate, p[1]]
+        elif kind == FILTER_BAND_STOP:
+            p = [p[0] / sample_rate, p[1] / sample_rate]
+        p = list(p) + [0.0] * (5 - len(p))
+        values += [float(kind)] + p
+    values += [0.0] * (6 * FILTER_SECTIONS - len(values))
+    upload_coefficients(lck, start, values)
+
+
+def autotune(
+    lck: lockin.Lockin,
+    amplitude: float,
+    hysteresis: float,
+    *,
+    cycles: int = 4,
+    max_iterations: int = 100_000,
+    timeout: float = 60.0,
+) -> Tuple[float, float, float]:
+    """Run a relay autotuning around the current set point and Z bias.
+
+    The feedback must be running and close to the set point. The suggested gains are returned,
+    not applied; they are per iteration, as for :func:`program_feedback`.
+
+    Args:
+        lck: an active instance of Lockin
+        amplitude: relay amplitude, as normalized Z bias
+        hysteresis: hysteresis of the relay on amp^2, somewhat above the noise level
+        cycles: number of oscillation cycles to average over
+        max_iterations: RPU gives up after this many iterations
+        timeout: time in seconds to wait for the result
+
+    Returns:
+        suggested proportional, integral and derivative gain
+    """
+    upload_coefficients(
+        lck, BANK_AUTOTUNE, [amplitude, hysteresis, float(cycles), float(max_iterations)]
+    )
+    set_control_flag(lck, CTRL_TUNE, True)
+    try:
+        t_end = time.monotonic() + timeout
+        while True:
+            kd, state = param_map().read(lck, "AUTOTUNE_KD_STATE")
+            if state == TUNE_DONE:
+                break
+            if state == TUNE_FAILED:
+                raise RuntimeError("autotuning failed, try a larger relay amplitude")
+            if time.monotonic() > t_end:
+                raise TimeoutError("autotuning did not finish")
+            time.sleep(0.1)
+        kp, ki = param_map().read(lck, "AUTOTUNE_KP_KI")
+        return kp, ki, kd
+    finally:
+        set_control_flag(lck, CTRL_TUNE, False)
+
+
+def start_analyzer(
+    lck: lockin.Lockin,
+    f_start: float,
+    f_stop: float,
+    points: int,
+    amplitude: float,
+    sample_rate: float,
+    *,
+    settle_cycles: float = 4.0,
+    measure_cycles: float = 8.0,
+):
+    """Start a sweep of the network analyzer, measuring the transfer functions of the Z loop.
+
+    The feedback must be running in auto mode. The results are written by the RPU to its data
+    area, see :func:`decode_analyzer`. Call :func:`stop_analyzer` when done, also before the
+    next sweep.
+
+    Args:
+        lck: an active instance of Lockin
+        f_start: first frequency in Hz
+        f_stop: last frequency in Hz, at most half the sample rate
+        points: number of frequencies, logarithmically spaced
+        amplitude: amplitude of the perturbation, as normalized Z bias
+        sample_rate: RPU iteration rate in Hz, i.e. the lockin pixel rate
+        settle_cycles: cycles to wait at each frequency before measuring
+        measure_cycles: cycles to measure at each frequency
+    """
+    if not 0 < points <= ANALYZER_MAX_POINTS:
+        raise ValueError(f"between 1 and {ANALYZER_MAX_POINTS} points")
+    upload_coefficients(
+        lck,
+        BANK_ANALYZER,
+        [
+            f_start / sample_rate,
+            f_stop / sample_rate,
+            float(points),
+            amplitude,
+            settle_cycles,
+            measure_cycles,
+        ],
+    )
+    set_control_flag(lck, CTRL_ANALYZER, False)
+    set_control_flag(lck, CTRL_ANALYZER, True)
+
+
+def stop_analyzer(lck: lockin.Lockin):
+    """Stop the network analyzer, removing the perturbation."""
+    set_control_flag(lck, CTRL_ANALYZER, False)
+
+
+def decode_analyzer(words, sample_rate: float):
+    """Decode the network analyzer results from the RPU data area.
+
+    Reading the RPU data area is not part of the ``lockin`` API used in this example, the words
+    must be read from the RPU memory by other means.
+
+    Args:
+        words: the 64-bit words of the data area, starting at index ``DATA_ANALYZER``
+        sample_rate: RPU iteration rate in Hz
+
+    Returns:
+        number of points measured and arrays of frequency in Hz, open-loop, closed-loop and
+        amp^2 transfer function
+    """
+    done, total = u64_to_u32x2(words[0])
+    if total == 0:
+        raise ValueError("RPU rejected the network analyzer settings")
+    points = np.array([u64_to_f32x2(w) for w in words[1 : 1 + 4 * done]]).reshape((done, 4, 2))
+    freq = points[:, 0, 0] * sample_rate
+    open_loop = points[:, 1, 0] + 1j * points[:, 1, 1]
+    closed_loop = points[:, 2, 0] + 1j * points[:, 2, 1]
+    amp2 = points[:, 3, 0] + 1j * points[:, 3, 1]
+    return done, freq, open_loop, closed_loop, amp2
+
+
+def set_control_flag(lck: lockin.Lockin, flag: int, enable: bool):
+    """Set or clear ``flag`` in the RPU control word, leaving the other flags untouched."""
+    control, value = param_map().read(lck, "CONTROL")
+    if enable:
+        control |= flag
+    else:
+        control &= ~flag
+    param_map().write(lck, "CONTROL", control, value)
+
+
+def bump_generation(lck: lockin.Lockin):
+    """Increment the generation in the RPU control word, leaving the other flags untouched."""
+    control, value = param_map().read(lck, "CONTROL")
+    generation = ((control >> CTRL_GEN_SHIFT) + 1) & 0xFF
+    control = (control & ~CTRL_GEN_MASK) | (generation << CTRL_GEN_SHIFT)
+    param_map().write(lck, "CONTROL", control, value)
+
+
+@contextmanager
+def param_group(lck: lockin.Lockin):
+    """Write a group of parameters that the RPU applies all at once.
+
+    The generation in the control word is odd while the group is written, and the RPU keeps
+    using the previous values until it is even again. Do not change the control word itself
+    within the group.
+
+    Example:
+        with param_group(lck):
+            param_map().write(lck, "SETPOINT_KP", sp, kp)
+            param_map().write(lck, "KI_KD", ki, kd)
+    """
+    bump_generation(lck)
+    try:
+        yield
+    finally:
+        bump_generation(lck)
+
+
+def read_param_status(lck: lockin.Lockin) -> Tuple[int, int]:
+    """Read which parameter the RPU rejected, if any.
+
+    A rejected slot keeps its last good value on the RPU. Note that the RPU validates the
+    parameters once per iteration, so read the status only after an iteration has passed.
+    Unless a slot is rejected, the ``BANK_PORT`` slot is reported while the last coefficient
+    written to the bank was rejected: as not finite for a NaN or infinite value, or as out of
+    range for an index outside the bank. It is also reported while the coefficients of the
+    control law are rejected, e.g. a negative gain from the gain schedule or an unstable IIR
+    compensator; the RPU then keeps the last coefficients that were accepted.
+
+    Returns:
+        the reason, one of ``PARAM_VALID``, ``PARAM_NOT_FINITE``, ``PARAM_OUT_OF_RANGE`` or
+        ``PARAM_NOT_INCREASING``, and the index of the first slot rejected, see
+        ``param_map().slots``
+    """
+    return param_map().read(lck, "PARAM_STATUS")
+
+
+def program_telemetry_period(lck: lockin.Lockin, iterations: int):
+    """Publish telemetry only every ``iterations`` iterations of the RPU.
+
+    A reader that is slower than the telemetry, e.g. over the network, otherwise never gets a
+    consistent read with :func:`read_telemetry`.
+    """
+    upload_coefficients(lck, BANK_TELEMETRY, [float(iterations)])
+
+
+def read_telemetry(lck: lockin.Lockin, timeout: float = 1.0) -> dict:
+    """Read the telemetry of the RPU, all from the same iteration.
+
+    Args:
+        lck: an active instance of Lockin
+        timeout: time in seconds to retry for a consistent read
+
+    Returns:
+        the telemetry, by name of the value
+    """
+    t_end = time.monotonic() + timeout
+    while True:
+        _, before = param_map().read(lck, "BUILD_SEQUENCE")
+        names = ("ERROR_CONTROL", "AUTOTUNE_KP_KI", "AUTOTUNE_KD_STATE", "DRIVE")
+        slots = [param_map().read(lck, name) for name in names]
+        _, after = param_map().read(lck, "BUILD_SEQUENCE")
+        if before == after and before % 2 == 0:
+            break
+        if time.monotonic() > t_end:
+            raise TimeoutError("no consistent read of the RPU telemetry, decimate it")
+    (error, z_bias), (kp, ki), (kd, state), (shift, drive) = slots
+    return {
+        "error": error,
+        "z_bias": z_bias,
+        "tune_kp": kp,
+        "tune_ki": ki,
+        "tune_kd": kd,
+        "tune_state": state,
+        "pll_shift": shift,
+        "drive": drive,
+    }
 
 
 def print_all(lck: lockin.Lockin):
@@ -128,8 +1147,8 @@ def print_all(lck: lockin.Lockin):
         lck: an active instance of Lockin
     """
     # read number of processed iterations
-    nr_irq, _ = u64_to_u32x2(lck.hardware.get_rpu_param(0))
-    amp2, z_bias = u64_to_f32x2(lck.hardware.get_rpu_param(1))
+    nr_irq, _ = param_map().read(lck, "IRQ_COUNT")
+    amp2, z_bias = param_map().read(lck, "ERROR_CONTROL")
     print(f"Amp2 (RPU): {amp2:.2e}")
     print(f"Bias: {z_bias:.4f}")
     print(f"IRQs: {nr_irq:d}")
@@ -169,15 +1188,6 @@ def u64_to_u32x2(val: int) -> Tuple[int, int]:
     return (low, high)
 
 
-def f32x2_to_u64(low: float, high: float) -> int:
-    """Convenience function to pack two f32 values into one u64 value"""
-    low = int.from_bytes(struct.pack("<f", low), byteorder="little")
-    high = int.from_bytes(struct.pack("<f", high), byteorder="little")
-    val = low & 0xFFFF_FFFF
-    val |= (high & 0xFFFF_FFFF) << 32
-    return val
-
-
 if __name__ == "__main__":
     if len(sys.argv) == 2:
         address = sys.argv[1]
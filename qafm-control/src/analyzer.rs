@@ -0,0 +1,399 @@
+//! Measurement of the loop transfer function with a stepped-sine perturbation.
+
+use core::f32::consts::TAU;
+
+use crate::coefficients::count;
+use crate::math::{cos, exp, ln, round, sin};
+
+/// Longest measurement at one frequency, settling included, in iterations.
+pub const MAX_POINT_ITERATIONS: f32 = 1e9;
+
+/// Settings of a frequency sweep of the [`NetworkAnalyzer`].
+///
+/// Frequencies are normalized to the iteration rate, i.e. in cycles per iteration, and must be
+/// between 0.0 and 0.5.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sweep {
+    /// first frequency
+    pub f_start: f32,
+    /// last frequency
+    pub f_stop: f32,
+    /// number of frequencies, logarithmically spaced
+    pub points: u32,
+    /// amplitude of the perturbation added to the controller output
+    pub amplitude: f32,
+    /// number of cycles to wait at each frequency before measuring
+    pub settle_cycles: f32,
+    /// number of cycles to measure at each frequency
+    pub measure_cycles: f32,
+}
+impl Sweep {
+    /// Number of coefficients describing a sweep, see [`Sweep::from_coefficients`].
+    pub const NR_COEFFICIENTS: usize = 6;
+
+    /// Sweep from the coefficients `[f_start, f_stop, points, amplitude, settle_cycles,
+    /// measure_cycles]`, with the number of points a whole number and 0 if less than 1.
+    ///
+    /// Panics if there are fewer than [`Sweep::NR_COEFFICIENTS`].
+    pub fn from_coefficients(coefficients: &[f32]) -> Self {
+        Sweep {
+            f_start: coefficients[0],
+            f_stop: coefficients[1],
+            points: count(coefficients[2]).unwrap_or(0),
+            amplitude: coefficients[3],
+            settle_cycles: coefficients[4],
+            measure_cycles: coefficients[5],
+        }
+    }
+
+    /// Whether the settings describe a sweep that can be run.
+    ///
+    /// Settling and measuring at the lowest frequency must take no more than
+    /// [`MAX_POINT_ITERATIONS`].
+    pub fn is_valid(&self) -> bool {
+        let f_min = self.f_start.min(self.f_stop);
+        self.f_start > 0.0
+            && self.f_start <= 0.5
+            && self.f_stop > 0.0
+            && self.f_stop <= 0.5
+            && self.points > 0
+            && self.amplitude > 0.0
+            && self.settle_cycles >= 0.0
+            && self.measure_cycles >= 1.0
+            && (self.settle_cycles + self.measure_cycles + 1.0) / f_min <= MAX_POINT_ITERATIONS
+    }
+}
+
+/// Transfer functions measured at one frequency, as complex numbers `[re, im]`.
+///
+/// With `d` the perturbation, `u` the controller output and `y` the error signal, the signal
+/// applied to the actuator is `v = u + d` and:
+/// - `open_loop` is the loop transfer function `L = -U / V`
+/// - `closed_loop` is the complementary sensitivity `T = L / (1 + L) = -U / D`
+/// - `error` is the response of the error signal `Y / D`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point {
+    /// frequency of the perturbation, in cycles per iteration
+    pub f: f32,
+    /// loop transfer function
+    pub open_loop: [f32; 2],
+    /// closed-loop transfer function
+    pub closed_loop: [f32; 2],
+    /// from perturbation to error signal
+    pub error: [f32; 2],
+}
+
+/// Network analyzer measuring the transfer functions of the closed feedback loop.
+///
+/// A sine perturbation is added to the controller output, stepping through the frequencies of
+/// a [`Sweep`]. At each frequency the loop first settles, then the perturbation, the controller
+/// output and the error signal are demodulated over a whole number of cycles; the frequency is
+/// adjusted slightly to make that possible. The result is a [`Point`] per frequency.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use qafm_control::analyzer::{NetworkAnalyzer, Sweep};
+/// # use qafm_control::pid::PidController;
+/// # fn make_a_new_measurement() -> f32 { 0.0 }
+/// # fn apply_new_output_value(_: f32) {}
+/// # let mut pid_c = PidController::builder().build();
+/// let mut analyzer = NetworkAnalyzer::new();
+/// analyzer.start(Sweep {
+///     f_start: 1e-3,
+///     f_stop: 0.1,
+///     points: 20,
+///     amplitude: 0.001,
+///     settle_cycles: 4.0,
+///     measure_cycles: 8.0,
+/// });
+///
+/// while analyzer.is_running() {
+///     let meas = make_a_new_measurement();
+///     let out = pid_c.update(meas);
+///     apply_new_output_value(out + analyzer.update(meas, out));
+///     if let Some((index, point)) = analyzer.completed() {
+///         // store the point
+///     }
+/// }
+/// ```
+pub struct NetworkAnalyzer {
+    sweep: Sweep,
+    running: bool,
+    ratio: f32,
+
+    // current frequency
+    index: u32,
+    f: f32,
+    phase: f32,
+    settle: u32,
+    measure: u32,
+    iteration: u32,
+
+    // demodulated perturbation, output and error
+    d: [f32; 2],
+    u: [f32; 2],
+    y: [f32; 2],
+
+    completed: Option<(u32, Point)>,
+}
+impl NetworkAnalyzer {
+    /// Create a new, idle network analyzer.
+    pub fn new() -> Self {
+        NetworkAnalyzer {
+            sweep: Sweep {
+                f_start: 0.0,
+                f_stop: 0.0,
+                points: 0,
+                amplitude: 0.0,
+                settle_cycles: 0.0,
+                measure_cycles: 0.0,
+            },
+            running: false,
+            ratio: 1.0,
+            index: 0,
+            f: 0.0,
+            phase: 0.0,
+            settle: 0,
+            measure: 0,
+            iteration: 0,
+            d: [0.0; 2],
+            u: [0.0; 2],
+            y: [0.0; 2],
+            completed: None,
+        }
+    }
+
+    /// Start a new sweep. Invalid settings leave the analyzer idle, see [`Sweep::is_valid`].
+    pub fn start(&mut self, sweep: Sweep) {
+        self.stop();
+        if !sweep.is_valid() {
+            return;
+        }
+        self.sweep = sweep;
+        self.ratio = if sweep.points > 1 {
+            exp(ln(sweep.f_stop / sweep.f_start) / (sweep.points - 1) as f32)
+        } else {
+            1.0
+        };
+        self.running = true;
+        self.phase = 0.0;
+        self.enter(0, sweep.f_start);
+    }
+
+    /// Stop the sweep, the perturbation is removed immediately.
+    pub fn stop(&mut self) {
+        self.running = false;
+        self.completed = None;
+    }
+
+    /// Whether a sweep is running.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Number of frequencies measured so far, and in total.
+    pub fn progress(&self) -> (u32, u32) {
+        if self.running {
+            (self.index, self.sweep.points)
+        } else {
+            (self.sweep.points, self.sweep.points)
+        }
+    }
+
+    /// Provide the error signal and controller output of this iteration, and get the
+    /// perturbation to add to the output.
+    ///
+    /// Returns 0.0 when not running.
+    pub fn update(&mut self, error: f32, output: f32) -> f32 {
+        if !self.running {
+            return 0.0;
+        }
+        let (s, c) = (sin(TAU * self.phase), cos(TAU * self.phase));
+        let d = self.sweep.amplitude * s;
+
+        if self.iteration >= self.settle {
+            // multiply by exp(-j phase)
+            for (acc, x) in [
+                (&mut self.d, d),
+                (&mut self.u, output),
+                (&mut self.y, error),
+            ] {
+                acc[0] += x * c;
+                acc[1] -= x * s;
+            }
+        }
+
+        self.phase += self.f;
+        self.phase -= round(self.phase);
+        self.iteration += 1;
+        if self.iteration == self.settle.saturating_add(self.measure) {
+            self.finish_point();
+        }
+        d
+    }
+
+    /// The last measured point and its index, once after it has been measured.
+    pub fn completed(&mut self) -> Option<(u32, Point)> {
+        self.completed.take()
+    }
+
+    /// Prepare to measure point `index` at about frequency `f`.
+    fn enter(&mut self, index: u32, f: f32) {
+        // whole number of cycles in the measurement
+        let measure = round(self.sweep.measure_cycles / f).max(1.0);
+        self.f = self.sweep.measure_cycles / measure;
+        self.measure = measure as u32;
+        self.settle = round(self.sweep.settle_cycles / self.f) as u32;
+        self.index = index;
+        self.iteration = 0;
+        self.d = [0.0; 2];
+        self.u = [0.0; 2];
+        self.y = [0.0; 2];
+    }
+
+    fn finish_point(&mut self) {
+        let v = [self.u[0] + self.d[0], self.u[1] + self.d[1]];
+        let minus_u = [-self.u[0], -self.u[1]];
+        let point = Point {
+            f: self.f,
+            open_loop: div(minus_u, v),
+            closed_loop: div(minus_u, self.d),
+            error: div(self.y, self.d),
+        };
+        self.completed = Some((self.index, point));
+
+        let next = self.index + 1;
+        if next < self.sweep.points {
+            let f = self.sweep.f_start * exp(ln(self.ratio) * next as f32);
+            self.enter(next, f);
+        } else {
+            self.running = false;
+            self.index = next;
+        }
+    }
+}
+impl Default for NetworkAnalyzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Complex division `a / b`.
+fn div(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    let den = b[0] * b[0] + b[1] * b[1];
+    [
+        (a[0] * b[0] + a[1] * b[1]) / den,
+        (a[1] * b[0] - a[0] * b[1]) / den,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::abs;
+
+    fn sweep(points: u32) -> Sweep {
+        Sweep {
+            f_start: 0.01,
+            f_stop: 0.1,
+            points,
+            amplitude: 0.01,
+            settle_cycles: 2.0,
+            measure_cycles: 4.0,
+        }
+    }
+
+    fn assert_close(a: [f32; 2], b: [f32; 2]) {
+        assert!(
+            abs(a[0] - b[0]) < 1e-3 && abs(a[1] - b[1]) < 1e-3,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn proportional_loop_with_delay() {
+        // plant y[n] = v[n-1], controller u = u0 - k * y: L = k exp(-j w)
+        let k = 0.5;
+        let mut analyzer = NetworkAnalyzer::new();
+        analyzer.start(sweep(5));
+        let (mut v, mut points) = (0.0, 0);
+        while analyzer.is_running() {
+            let y = v;
+            let u = 0.3 - k * y;
+            v = u + analyzer.update(y, u);
+            if let Some((index, point)) = analyzer.completed() {
+                assert_eq!(index, points);
+                let delay = [cos(TAU * point.f), -sin(TAU * point.f)];
+                let l = [k * delay[0], k * delay[1]];
+                assert_close(point.open_loop, l);
+                assert_close(point.closed_loop, div(l, [1.0 + l[0], l[1]]));
+                assert_close(point.error, div(delay, [1.0 + l[0], l[1]]));
+                points += 1;
+            }
+        }
+        assert_eq!(points, 5);
+        assert_eq!(analyzer.progress(), (5, 5));
+    }
+
+    #[test]
+    fn frequency_is_adjusted_to_whole_cycles() {
+        let mut analyzer = NetworkAnalyzer::new();
+        analyzer.start(sweep(3));
+        let mut fs = [0.0; 3];
+        while analyzer.is_running() {
+            analyzer.update(0.0, 0.0);
+            if let Some((index, point)) = analyzer.completed() {
+                fs[index as usize] = point.f;
+            }
+        }
+        assert_eq!(fs[0], 0.01);
+        assert!(abs(fs[1] - 0.0316) < 0.001);
+        // 4 cycles in 40 iterations
+        assert_eq!(fs[2], 0.1);
+    }
+
+    #[test]
+    fn sweep_from_coefficients() {
+        let coefficients = [0.01, 0.1, 5.0, 0.01, 2.0, 4.0];
+        assert_eq!(Sweep::from_coefficients(&coefficients), sweep(5));
+        let sweep = Sweep::from_coefficients(&[0.01, 0.1, 0.5, 0.01, 2.0, 4.0]);
+        assert_eq!(sweep.points, 0);
+        assert!(!sweep.is_valid());
+    }
+
+    #[test]
+    fn invalid_sweep_stays_idle() {
+        let mut analyzer = NetworkAnalyzer::new();
+        analyzer.start(Sweep {
+            amplitude: 0.0,
+            ..sweep(5)
+        });
+        assert!(!analyzer.is_running());
+        assert_eq!(analyzer.update(0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn overlong_sweep_is_invalid() {
+        assert!(sweep(5).is_valid());
+        for settle_cycles in [1e9, f32::INFINITY, f32::NAN] {
+            assert!(!Sweep {
+                settle_cycles,
+                ..sweep(5)
+            }
+            .is_valid());
+        }
+        assert!(!Sweep {
+            measure_cycles: f32::INFINITY,
+            ..sweep(5)
+        }
+        .is_valid());
+        assert!(!Sweep {
+            f_start: 1e-12,
+            ..sweep(5)
+        }
+        .is_valid());
+    }
+}
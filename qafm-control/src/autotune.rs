@@ -0,0 +1,335 @@
+use core::f32::consts::PI;
+
+use crate::coefficients::count;
+use crate::math::sqrt;
+
+/// State of a [`RelayAutotune`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TuneState {
+    /// Not started, or stopped.
+    Idle,
+    /// Relay is driving the output, measuring the oscillation.
+    Running,
+    /// Oscillation measured, see [`RelayAutotune::result`].
+    Done,
+    /// No usable oscillation within the allowed number of iterations, or invalid settings.
+    Failed,
+}
+impl TuneState {
+    /// Numeric code for reporting: 0 idle, 1 running, 2 done, 3 failed.
+    pub fn code(self) -> u32 {
+        match self {
+            TuneState::Idle => 0,
+            TuneState::Running => 1,
+            TuneState::Done => 2,
+            TuneState::Failed => 3,
+        }
+    }
+}
+
+/// Ultimate gain and period measured by a [`RelayAutotune`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TuneResult {
+    /// ultimate gain
+    pub ku: f32,
+    /// ultimate period, in iterations
+    pub tu: f32,
+}
+impl TuneResult {
+    /// Per-iteration PID gains `[kp, ki, kd]` with the classic Ziegler–Nichols rule:
+    /// `Kp = 0.6 Ku`, `Ti = Tu / 2`, `Td = Tu / 8`.
+    pub fn pid_gains(&self) -> [f32; 3] {
+        let kp = 0.6 * self.ku;
+        [kp, 2.0 * kp / self.tu, kp * self.tu / 8.0]
+    }
+}
+
+/// Relay-feedback autotuning after Åström and Hägglund.
+///
+/// The output is switched between `center + amplitude` and `center - amplitude` depending on
+/// the sign of the error, with some hysteresis, which makes the loop oscillate close to its
+/// ultimate period. The first cycle is discarded, then the period and the peak-to-peak
+/// amplitude of the measurement are averaged over the requested number of cycles. A positive
+/// process gain is assumed, i.e. a larger output gives a larger measurement, as for
+/// [`PidController`](crate::pid::PidController) with positive gains.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use qafm_control::autotune::{RelayAutotune, TuneState};
+/// # fn make_a_new_measurement() -> f32 { 0.0 }
+/// # fn apply_new_output_value(_: f32) {}
+/// let mut tuner = RelayAutotune::new(0.05, 0.001, 4, 100_000);
+/// tuner.start(0.5, 0.3);
+///
+/// while tuner.state() == TuneState::Running {
+///     let meas = make_a_new_measurement();
+///     apply_new_output_value(tuner.update(meas));
+/// }
+/// if let Some(result) = tuner.result() {
+///     let [kp, ki, kd] = result.pid_gains();
+/// }
+/// ```
+pub struct RelayAutotune {
+    // settings
+    amplitude: f32,
+    hysteresis: f32,
+    cycles: u32,
+    max_iterations: u32,
+
+    setpoint: f32,
+    center: f32,
+    state: TuneState,
+    result: Option<TuneResult>,
+
+    // measurement
+    high: bool,
+    iterations: u32,
+    last_switch: Option<u32>,
+    measured: u32,
+    sum_period: f32,
+    sum_amplitude: f32,
+    min: f32,
+    max: f32,
+}
+impl RelayAutotune {
+    /// Number of settings, see [`RelayAutotune::load_settings`].
+    pub const NR_SETTINGS: usize = 4;
+
+    /// Create a new, idle autotuner.
+    ///
+    /// - `amplitude`: relay amplitude, the output swings by this much around its center
+    /// - `hysteresis`: error needed to switch the relay, to reject noise
+    /// - `cycles`: number of oscillation cycles to average over, at least 1
+    /// - `max_iterations`: give up after this many iterations
+    pub fn new(amplitude: f32, hysteresis: f32, cycles: u32, max_iterations: u32) -> Self {
+        RelayAutotune {
+            amplitude,
+            hysteresis,
+            cycles,
+            max_iterations,
+            setpoint: 0.0,
+            center: 0.0,
+            state: TuneState::Idle,
+            result: None,
+            high: true,
+            iterations: 0,
+            last_switch: None,
+            measured: 0,
+            sum_period: 0.0,
+            sum_amplitude: 0.0,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+        }
+    }
+
+    /// Change the settings, see [`RelayAutotune::new`]. Used at the next start.
+    pub fn configure(
+        &mut self,
+        amplitude: f32,
+        hysteresis: f32,
+        cycles: u32,
+        max_iterations: u32,
+    ) {
+        self.amplitude = amplitude;
+        self.hysteresis = hysteresis;
+        self.cycles = cycles;
+        self.max_iterations = max_iterations;
+    }
+
+    /// Change the settings from the coefficients `[amplitude, hysteresis, cycles,
+    /// max_iterations]`, see [`RelayAutotune::configure`]. Counts less than 1 are 4 cycles and
+    /// 100 000 iterations.
+    ///
+    /// Panics if there are fewer than [`RelayAutotune::NR_SETTINGS`].
+    pub fn load_settings(&mut self, settings: &[f32]) {
+        let cycles = count(settings[2]).unwrap_or(4);
+        let max_iterations = count(settings[3]).unwrap_or(100_000);
+        self.configure(settings[0], settings[1], cycles, max_iterations);
+    }
+
+    /// Start tuning around `setpoint`, with the relay centered on `center`.
+    ///
+    /// Fails immediately if the relay amplitude is not positive, or the hysteresis negative.
+    pub fn start(&mut self, setpoint: f32, center: f32) {
+        let valid = self.amplitude > 0.0 && self.hysteresis >= 0.0 && self.cycles > 0;
+        *self = RelayAutotune {
+            setpoint,
+            center,
+            state: if valid {
+                TuneState::Running
+            } else {
+                TuneState::Failed
+            },
+            ..RelayAutotune::new(
+                self.amplitude,
+                self.hysteresis,
+                self.cycles,
+                self.max_iterations,
+            )
+        };
+    }
+
+    /// Stop tuning, keeping the result.
+    pub fn stop(&mut self) {
+        self.state = TuneState::Idle;
+    }
+
+    /// Current state.
+    pub fn state(&self) -> TuneState {
+        self.state
+    }
+
+    /// Result of the tuning, once done.
+    pub fn result(&self) -> Option<TuneResult> {
+        self.result
+    }
+
+    /// Provide a new measurement and generate a new output value.
+    ///
+    /// Unless running, the output is the center of the relay.
+    pub fn update(&mut self, measurement: f32) -> f32 {
+        if self.state != TuneState::Running {
+            return self.center;
+        }
+
+        self.min = self.min.min(measurement);
+        self.max = self.max.max(measurement);
+
+        let error = self.setpoint - measurement;
+        if self.high && error < -self.hysteresis {
+            self.high = false;
+        } else if !self.high && error > self.hysteresis {
+            // a low to high switch starts a new cycle
+            self.high = true;
+            self.end_cycle();
+        }
+
+        self.iterations += 1;
+        if self.state == TuneState::Running && self.iterations >= self.max_iterations {
+            self.state = TuneState::Failed;
+        }
+
+        if self.state != TuneState::Running {
+            self.center
+        } else if self.high {
+            self.center + self.amplitude
+        } else {
+            self.center - self.amplitude
+        }
+    }
+
+    fn end_cycle(&mut self) {
+        if let Some(last) = self.last_switch {
+            self.measured += 1;
+            // first cycle is still settling
+            if self.measured > 1 {
+                self.sum_period += (self.iterations - last) as f32;
+                self.sum_amplitude += 0.5 * (self.max - self.min);
+            }
+            if self.measured > self.cycles {
+                self.finish();
+            }
+        }
+        self.last_switch = Some(self.iterations);
+        self.min = f32::INFINITY;
+        self.max = f32::NEG_INFINITY;
+    }
+
+    fn finish(&mut self) {
+        let n = self.cycles as f32;
+        let tu = self.sum_period / n;
+        let a = self.sum_amplitude / n;
+        // describing function of a relay with hysteresis
+        let a2 = a * a - self.hysteresis * self.hysteresis;
+        if a2 > 0.0 {
+            let ku = 4.0 * self.amplitude / (PI * sqrt(a2));
+            self.result = Some(TuneResult { ku, tu });
+            self.state = TuneState::Done;
+        } else {
+            self.state = TuneState::Failed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pid::PidController;
+
+    /// First-order lag with dead time, unit static gain.
+    struct Plant {
+        y: f32,
+        delay: [f32; 8],
+    }
+    impl Plant {
+        fn new(u: f32) -> Self {
+            Plant {
+                y: u,
+                delay: [u; 8],
+            }
+        }
+        fn step(&mut self, u: f32) -> f32 {
+            self.delay.rotate_left(1);
+            self.delay[7] = u;
+            self.y += 0.05 * (self.delay[0] - self.y);
+            self.y
+        }
+    }
+
+    #[test]
+    fn tuned_gains_stabilize_plant() {
+        let mut plant = Plant::new(0.5);
+        let mut tuner = RelayAutotune::new(0.1, 0.005, 4, 10_000);
+        tuner.start(0.5, 0.5);
+        let mut meas = plant.y;
+        while tuner.state() == TuneState::Running {
+            meas = plant.step(tuner.update(meas));
+        }
+        assert_eq!(tuner.state(), TuneState::Done);
+        let result = tuner.result().unwrap();
+        assert!(result.tu > 16.0 && result.tu < 64.0);
+
+        let [kp, ki, kd] = result.pid_gains();
+        let mut pid_c = PidController::builder()
+            .setpoint(0.6)
+            .gain_p(kp)
+            .gain_i(ki)
+            .gain_d(kd)
+            .limit_output(0.0, 1.0)
+            .build();
+        for _ in 0..2000 {
+            meas = plant.step(pid_c.update(meas));
+        }
+        assert!((meas - 0.6).abs() < 1e-3);
+    }
+
+    #[test]
+    fn fails_without_oscillation() {
+        let mut tuner = RelayAutotune::new(0.1, 0.005, 4, 1000);
+        tuner.start(0.5, 0.5);
+        for _ in 1..1000 {
+            assert_eq!(tuner.update(0.0), 0.6);
+        }
+        assert_eq!(tuner.update(0.0), 0.5);
+        assert_eq!(tuner.state(), TuneState::Failed);
+        assert!(tuner.result().is_none());
+    }
+
+    #[test]
+    fn invalid_settings_fail() {
+        let mut tuner = RelayAutotune::new(0.0, 0.005, 4, 1000);
+        tuner.start(0.5, 0.5);
+        assert_eq!(tuner.state(), TuneState::Failed);
+    }
+
+    #[test]
+    fn settings_with_default_counts() {
+        let mut tuner = RelayAutotune::new(0.0, 0.0, 0, 0);
+        tuner.load_settings(&[0.1, 0.005, 8.0, 5e3]);
+        assert_eq!((tuner.amplitude, tuner.hysteresis), (0.1, 0.005));
+        assert_eq!((tuner.cycles, tuner.max_iterations), (8, 5000));
+        tuner.load_settings(&[0.1, 0.005, 0.0, f32::NAN]);
+        assert_eq!((tuner.cycles, tuner.max_iterations), (4, 100_000));
+    }
+}
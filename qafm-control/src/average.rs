@@ -0,0 +1,266 @@
+//! Averaging of lockin data over a sliding window of pixels.
+
+/// How a [`SlidingAverage`] averages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Averaging {
+    /// no averaging, the data passes through
+    #[default]
+    Off,
+    /// mean of the last `length` pixels
+    Boxcar,
+    /// exponential moving average with a time constant of `length` pixels
+    Exponential,
+}
+impl Averaging {
+    /// Averaging from its code: 0 off, 1 boxcar or 2 exponential.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Averaging::Off),
+            1 => Some(Averaging::Boxcar),
+            2 => Some(Averaging::Exponential),
+            _ => None,
+        }
+    }
+}
+
+/// Average of I and Q over a sliding window of up to `N` pixels.
+///
+/// The last `N` pixels are kept in a ring buffer whatever the averaging, so the averaging and
+/// the window length can be changed at any time without starting over. Until the buffer holds
+/// `length` pixels, the boxcar averages over the pixels so far.
+///
+/// The boxcar keeps a running sum in `f64`, so each pixel costs one addition and one
+/// subtraction, and the rounding errors stay negligible; a change of the window length sums
+/// the window once. The exponential moving average starts from the first pixel after it is
+/// selected.
+///
+/// A pixel that is NaN or infinite makes the average NaN or infinite for as long as it is in
+/// the boxcar window, and for one pixel with the exponential moving average, which then starts
+/// over from the next pixel.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::average::{Averaging, SlidingAverage};
+/// let mut average = SlidingAverage::<16>::new();
+/// average.configure(Averaging::Boxcar, 2);
+/// assert_eq!(average.update(1.0, 0.0), (1.0, 0.0));
+/// assert_eq!(average.update(3.0, 2.0), (2.0, 1.0));
+/// assert_eq!(average.update(5.0, 0.0), (4.0, 1.0));
+/// ```
+pub struct SlidingAverage<const N: usize> {
+    averaging: Averaging,
+    length: usize,
+
+    // ring buffer, with the index of the next pixel and the number of pixels so far
+    buffer: [(f32, f32); N],
+    next: usize,
+    filled: usize,
+
+    // boxcar sum over the last min(length, filled) pixels
+    sum: (f64, f64),
+
+    // exponential moving average, restarted from the next pixel if None
+    ema: Option<(f32, f32)>,
+}
+impl<const N: usize> SlidingAverage<N> {
+    /// Create a new sliding average, off until configured.
+    pub fn new() -> Self {
+        SlidingAverage {
+            averaging: Averaging::Off,
+            length: 1,
+            buffer: [(0.0, 0.0); N],
+            next: 0,
+            filled: 0,
+            sum: (0.0, 0.0),
+            ema: None,
+        }
+    }
+
+    /// Change the averaging and the window length in pixels.
+    ///
+    /// The window length is at least 1, and at most `N` for the boxcar.
+    pub fn configure(&mut self, averaging: Averaging, length: usize) {
+        let length = match averaging {
+            Averaging::Boxcar => length.clamp(1, N),
+            _ => length.max(1),
+        };
+        if averaging != self.averaging && averaging == Averaging::Exponential {
+            self.ema = None;
+        }
+        if averaging == Averaging::Boxcar
+            && (self.averaging != Averaging::Boxcar || length != self.length)
+        {
+            self.length = length;
+            self.resum();
+        }
+        self.averaging = averaging;
+        self.length = length;
+    }
+
+    /// The averaging and the window length.
+    pub fn config(&self) -> (Averaging, usize) {
+        (self.averaging, self.length)
+    }
+
+    /// Forget all pixels.
+    pub fn reset(&mut self) {
+        self.next = 0;
+        self.filled = 0;
+        self.sum = (0.0, 0.0);
+        self.ema = None;
+    }
+
+    /// Add a new pixel, and get the average.
+    pub fn update(&mut self, i: f32, q: f32) -> (f32, f32) {
+        if N == 0 {
+            return (i, q);
+        }
+        // pixel leaving the boxcar window, if the window is full
+        let leaving = if self.filled >= self.length {
+            Some(self.buffer[(self.next + N - self.length) % N])
+        } else {
+            None
+        };
+        self.buffer[self.next] = (i, q);
+        self.next = (self.next + 1) % N;
+        self.filled = (self.filled + 1).min(N);
+
+        match self.averaging {
+            Averaging::Off => (i, q),
+            Averaging::Boxcar => {
+                self.sum.0 += i as f64;
+                self.sum.1 += q as f64;
+                if let Some((i_old, q_old)) = leaving {
+                    self.sum.0 -= i_old as f64;
+                    self.sum.1 -= q_old as f64;
+                }
+                // a NaN or infinite pixel would stay in the running sum for good, so sum the
+                // window from scratch until it has left
+                if !(self.sum.0.is_finite() && self.sum.1.is_finite()) {
+                    self.resum();
+                }
+                let n = self.filled.min(self.length) as f64;
+                ((self.sum.0 / n) as f32, (self.sum.1 / n) as f32)
+            }
+            Averaging::Exponential => {
+                let alpha = 1.0 / self.length as f32;
+                let (ei, eq) = match self.ema {
+                    Some((ei, eq)) => (ei + alpha * (i - ei), eq + alpha * (q - eq)),
+                    None => (i, q),
+                };
+                // restart from the next pixel rather than stay NaN or infinite for good
+                self.ema = Some((ei, eq)).filter(|_| ei.is_finite() && eq.is_finite());
+                (ei, eq)
+            }
+        }
+    }
+
+    /// Sum the pixels in the boxcar window from scratch.
+    fn resum(&mut self) {
+        self.sum = (0.0, 0.0);
+        for k in 1..=self.filled.min(self.length) {
+            let (i, q) = self.buffer[(self.next + N - k) % N];
+            self.sum.0 += i as f64;
+            self.sum.1 += q as f64;
+        }
+    }
+}
+impl<const N: usize> Default for SlidingAverage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::abs;
+
+    #[test]
+    fn off_passes_through() {
+        let mut average = SlidingAverage::<4>::new();
+        assert_eq!(average.update(1.0, 2.0), (1.0, 2.0));
+        assert_eq!(average.update(3.0, 4.0), (3.0, 4.0));
+    }
+
+    #[test]
+    fn boxcar_over_window() {
+        let mut average = SlidingAverage::<8>::new();
+        average.configure(Averaging::Boxcar, 4);
+        let mut out = (0.0, 0.0);
+        for n in 0..100 {
+            out = average.update(n as f32, -(n as f32));
+        }
+        // mean of 96..=99
+        assert_eq!(out, (97.5, -97.5));
+    }
+
+    #[test]
+    fn window_length_changes_without_starting_over() {
+        let mut average = SlidingAverage::<8>::new();
+        for n in 0..20 {
+            average.update(n as f32, 0.0);
+        }
+        // the pixels before are used as soon as the boxcar is selected
+        average.configure(Averaging::Boxcar, 2);
+        assert_eq!(average.update(20.0, 0.0).0, 19.5);
+        average.configure(Averaging::Boxcar, 6);
+        assert_eq!(average.update(21.0, 0.0).0, 18.5);
+        // at most the ring buffer
+        average.configure(Averaging::Boxcar, 100);
+        assert_eq!(average.config(), (Averaging::Boxcar, 8));
+        assert_eq!(average.update(22.0, 0.0).0, 18.5);
+    }
+
+    #[test]
+    fn exponential_moving_average() {
+        let mut average = SlidingAverage::<1>::new();
+        average.configure(Averaging::Exponential, 10);
+        assert_eq!(average.update(1.0, 1.0), (1.0, 1.0));
+        // step response with a time constant of about 10 pixels
+        let mut out = (0.0, 0.0);
+        for _ in 0..10 {
+            out = average.update(0.0, 2.0);
+        }
+        assert!(abs(out.0 - 0.9_f32.powi(10)) < 1e-6);
+        assert!(abs(out.1 - (2.0 - 0.9_f32.powi(10))) < 1e-6);
+    }
+
+    #[test]
+    fn boxcar_does_not_drift() {
+        let mut average = SlidingAverage::<16>::new();
+        average.configure(Averaging::Boxcar, 16);
+        for n in 0..1_000_000 {
+            average.update(1e4 * (n % 7) as f32, 0.1);
+        }
+        for _ in 0..16 {
+            average.update(1.0, 0.1);
+        }
+        assert_eq!(average.update(1.0, 0.1), (1.0, 0.1));
+    }
+
+    #[test]
+    fn recovers_from_nan() {
+        let mut average = SlidingAverage::<8>::new();
+        average.configure(Averaging::Boxcar, 4);
+        for _ in 0..8 {
+            average.update(1.0, 2.0);
+        }
+        assert!(average.update(f32::NAN, 2.0).0.is_nan());
+        for _ in 0..3 {
+            let (i, q) = average.update(1.0, f32::INFINITY);
+            assert!(i.is_nan() && q.is_infinite());
+        }
+        // the NaN has left the window, the infinity not yet
+        assert_eq!(average.update(1.0, 2.0).0, 1.0);
+        for _ in 0..3 {
+            average.update(1.0, 2.0);
+        }
+        assert_eq!(average.update(1.0, 2.0), (1.0, 2.0));
+
+        average.configure(Averaging::Exponential, 4);
+        assert!(average.update(f32::NAN, 2.0).0.is_nan());
+        assert_eq!(average.update(1.0, 2.0), (1.0, 2.0));
+    }
+}
@@ -0,0 +1,95 @@
+//! Decoding of coefficients written by another processor, e.g. the APU, where every value is
+//! an `f32`, whole numbers included.
+
+use crate::validate::{finite, Rejection};
+
+/// Whole number of at least 1 in `value`, rounded down, `None` for less than 1 or NaN.
+///
+/// Values beyond the range of `u32` saturate.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::coefficients::count;
+/// assert_eq!(count(4.0), Some(4));
+/// assert_eq!(count(0.0), None);
+/// assert_eq!(count(4.0).unwrap_or(1), 4);
+/// ```
+pub fn count(value: f32) -> Option<u32> {
+    if value >= 1.0 {
+        Some(value as u32)
+    } else {
+        None
+    }
+}
+
+/// Numeric code in `value`, rounded down, `None` if negative or NaN.
+pub fn code(value: f32) -> Option<u32> {
+    if value >= 0.0 {
+        Some(value as u32)
+    } else {
+        None
+    }
+}
+
+/// Store `value` at `index` of `values`, or tell why it is rejected: an index outside of
+/// `values` is out of range, and a value that is NaN or infinite is not finite. A rejected value
+/// is not stored, the coefficient keeps its last value.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::coefficients::store;
+/// # use qafm_control::validate::Rejection;
+/// let mut values = [0.0; 4];
+/// assert_eq!(store(&mut values, 2, 1.5), Ok(()));
+/// assert_eq!(store(&mut values, 4, 1.5), Err(Rejection::OutOfRange));
+/// assert_eq!(values, [0.0, 0.0, 1.5, 0.0]);
+/// ```
+pub fn store(values: &mut [f32], index: usize, value: f32) -> Result<(), Rejection> {
+    let slot = values.get_mut(index).ok_or(Rejection::OutOfRange)?;
+    finite(&[value])?;
+    *slot = value;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        assert_eq!(count(1.0), Some(1));
+        assert_eq!(count(2.9), Some(2));
+        assert_eq!(count(0.99), None);
+        assert_eq!(count(-3.0), None);
+        assert_eq!(count(f32::NAN), None);
+        assert_eq!(count(1e20), Some(u32::MAX));
+        assert_eq!(count(f32::INFINITY), Some(u32::MAX));
+    }
+
+    #[test]
+    fn codes() {
+        assert_eq!(code(0.0), Some(0));
+        assert_eq!(code(2.0), Some(2));
+        assert_eq!(code(-0.5), None);
+        assert_eq!(code(f32::NAN), None);
+    }
+
+    #[test]
+    fn stores_valid_values_only() {
+        let mut values = [1.0; 3];
+        assert_eq!(store(&mut values, 0, -2.0), Ok(()));
+        assert_eq!(store(&mut values, 1, f32::NAN), Err(Rejection::NotFinite));
+        assert_eq!(
+            store(&mut values, 2, f32::INFINITY),
+            Err(Rejection::NotFinite)
+        );
+        assert_eq!(store(&mut values, 3, 4.0), Err(Rejection::OutOfRange));
+        assert_eq!(
+            store(&mut values, usize::MAX, f32::NAN),
+            Err(Rejection::OutOfRange)
+        );
+        assert_eq!(values, [-2.0, 1.0, 1.0]);
+    }
+}
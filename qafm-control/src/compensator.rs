@@ -0,0 +1,286 @@
+use crate::controller::{Controller, ControllerParams};
+use crate::filter::Biquad;
+use crate::limits::OutputLimits;
+use crate::math::abs;
+use crate::validate::{finite, Rejection};
+
+/// A first-order lead-lag compensator acting on the error.
+///
+/// The output is `u = u0 + y`, with the operating point `u0` set by [`Controller::reset`] and
+/// `y[n] = k * (e[n] - zero * e[n-1]) + pole * y[n-1]`.
+/// With `zero < pole` the compensator is a lag, with `zero > pole` a lead. A pole of 1.0 gives
+/// integral action. When the output saturates, `y` is limited as well to prevent windup.
+///
+/// Coefficients: `[k, zero, pole]`.
+pub struct LeadLag {
+    setpoint: f32,
+    limits: OutputLimits,
+
+    // coefficients
+    k: f32,
+    zero: f32,
+    pole: f32,
+
+    // compensator "memory"
+    offset: f32,
+    prev_error: f32,
+    y: f32,
+    output: f32,
+}
+impl LeadLag {
+    /// Create a new compensator with output range `min..=max` and all coefficients zero.
+    pub fn new(min: f32, max: f32) -> Self {
+        let limits = OutputLimits::new(min, max);
+        let output = limits.initial();
+        LeadLag {
+            setpoint: 0.0,
+            limits,
+            k: 0.0,
+            zero: 0.0,
+            pole: 0.0,
+            offset: output,
+            prev_error: 0.0,
+            y: 0.0,
+            output,
+        }
+    }
+}
+impl Controller for LeadLag {
+    fn update(&mut self, measurement: f32) -> f32 {
+        let error = self.setpoint - measurement;
+        let y = self.k * (error - self.zero * self.prev_error) + self.pole * self.y;
+
+        let (lim_min, lim_max) = self.limits.around(self.output);
+        let output = (self.offset + y).clamp(lim_min, lim_max);
+        // only keep the part of y that made it to the output
+        self.y = output - self.offset;
+
+        self.prev_error = error;
+        self.output = output;
+        output
+    }
+
+    fn reset(&mut self, output: f32) {
+        self.offset = output;
+        self.prev_error = 0.0;
+        self.y = 0.0;
+        self.output = output;
+    }
+
+    fn load_params(&mut self, params: &ControllerParams<'_>) {
+        self.setpoint = params.setpoint;
+        self.limits.set_slew_rate(params.slew);
+        if let [k, zero, pole, ..] = *params.coefficients {
+            self.k = k;
+            self.zero = zero;
+            self.pole = pole;
+        }
+    }
+
+    /// Coefficients must be finite, with the pole between -1.0 and 1.0.
+    fn check_coefficients(&self, coefficients: &[f32]) -> Result<(), Rejection> {
+        if let [k, zero, pole, ..] = *coefficients {
+            finite(&[k, zero, pole])?;
+            if abs(pole) > 1.0 {
+                return Err(Rejection::OutOfRange);
+            }
+        }
+        Ok(())
+    }
+
+    fn output(&self) -> f32 {
+        self.output
+    }
+
+    fn setpoint(&self) -> f32 {
+        self.setpoint
+    }
+
+    fn limits(&self) -> &OutputLimits {
+        &self.limits
+    }
+}
+
+/// A compensator made of `N` cascaded biquad sections acting on the error.
+///
+/// The output is `u = u0 + H(e)`, with the operating point `u0` set by [`Controller::reset`]
+/// and `H` the cascade of the biquads. There is no anti-windup other than the output limits, so
+/// compensators with integral action should be kept away from saturation.
+///
+/// Coefficients: `[b0, b1, b2, a1, a2]` of each section in turn, see [`Biquad`].
+pub struct IirCompensator<const N: usize> {
+    setpoint: f32,
+    limits: OutputLimits,
+    sections: [Biquad; N],
+    offset: f32,
+    output: f32,
+}
+impl<const N: usize> IirCompensator<N> {
+    /// Number of coefficients needed to load all sections.
+    pub const NR_COEFFICIENTS: usize = 5 * N;
+
+    /// Create a new compensator with output range `min..=max` and all coefficients zero.
+    pub fn new(min: f32, max: f32) -> Self {
+        let limits = OutputLimits::new(min, max);
+        let output = limits.initial();
+        IirCompensator {
+            setpoint: 0.0,
+            limits,
+            sections: [Biquad::default(); N],
+            offset: output,
+            output,
+        }
+    }
+}
+impl<const N: usize> Controller for IirCompensator<N> {
+    fn update(&mut self, measurement: f32) -> f32 {
+        let error = self.setpoint - measurement;
+        let y = self
+            .sections
+            .iter_mut()
+            .fold(error, |x, section| section.process(x));
+
+        let (lim_min, lim_max) = self.limits.around(self.output);
+        let output = (self.offset + y).clamp(lim_min, lim_max);
+
+        self.output = output;
+        output
+    }
+
+    fn reset(&mut self, output: f32) {
+        self.sections.iter_mut().for_each(Biquad::reset);
+        self.offset = output;
+        self.output = output;
+    }
+
+    fn load_params(&mut self, params: &ControllerParams<'_>) {
+        self.setpoint = params.setpoint;
+        self.limits.set_slew_rate(params.slew);
+        for (section, coefficients) in self
+            .sections
+            .iter_mut()
+            .zip(params.coefficients.chunks_exact(5))
+        {
+            section.set_coefficients([
+                coefficients[0],
+                coefficients[1],
+                coefficients[2],
+                coefficients[3],
+                coefficients[4],
+            ]);
+        }
+    }
+
+    /// Each section is checked in turn, see [`Biquad::check_coefficients`].
+    fn check_coefficients(&self, coefficients: &[f32]) -> Result<(), Rejection> {
+        coefficients
+            .chunks_exact(5)
+            .take(N)
+            .try_for_each(|c| Biquad::check_coefficients([c[0], c[1], c[2], c[3], c[4]]))
+    }
+
+    fn output(&self) -> f32 {
+        self.output
+    }
+
+    fn setpoint(&self) -> f32 {
+        self.setpoint
+    }
+
+    fn limits(&self) -> &OutputLimits {
+        &self.limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(setpoint: f32, coefficients: &[f32]) -> ControllerParams<'_> {
+        ControllerParams {
+            setpoint,
+            slew: 0.0,
+            coefficients,
+        }
+    }
+
+    #[test]
+    fn lead_lag_integrates_with_unit_pole() {
+        let mut ll = LeadLag::new(0.0, 1.0);
+        ll.reset(0.5);
+        ll.load_params(&params(0.3, &[0.1, 0.0, 1.0]));
+        assert!((ll.update(0.2) - 0.51).abs() < 1e-6);
+        assert!((ll.update(0.2) - 0.52).abs() < 1e-6);
+        // at the set point the output stays put
+        assert!((ll.update(0.3) - 0.52).abs() < 1e-6);
+    }
+
+    #[test]
+    fn lead_lag_does_not_wind_up() {
+        let mut ll = LeadLag::new(0.0, 1.0);
+        ll.load_params(&params(0.3, &[0.1, 0.0, 1.0]));
+        for _ in 0..1000 {
+            assert!(ll.update(-1.0) <= 1.0);
+        }
+        // leaves saturation as soon as the error changes sign
+        assert!(ll.update(0.4) < 1.0);
+    }
+
+    #[test]
+    fn iir_continues_from_reset() {
+        let mut iir = IirCompensator::<2>::new(0.0, 1.0);
+        let coefficients = [
+            0.5, 0.0, 0.0, 0.0, 0.0, // gain of 0.5
+            1.0, 0.0, 0.0, -1.0, 0.0, // integrator
+        ];
+        iir.load_params(&params(0.3, &coefficients));
+        iir.reset(0.4);
+        assert!((iir.update(0.3) - 0.4).abs() < 1e-6);
+        assert!((iir.update(0.1) - 0.5).abs() < 1e-6);
+        assert!((iir.update(0.1) - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn hold_keeps_output() {
+        let mut ll = LeadLag::new(0.0, 1.0);
+        ll.load_params(&params(0.3, &[0.1, 0.0, 1.0]));
+        ll.update(0.0);
+        let out = ll.output();
+        assert_eq!(ll.hold(0.0), out);
+        assert_eq!(ll.hold(0.0), out);
+        assert!((ll.update(0.3) - out).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_unstable_coefficients() {
+        let ll = LeadLag::new(0.0, 1.0);
+        assert_eq!(ll.check_coefficients(&[0.1, 0.5, -1.0]), Ok(()));
+        assert_eq!(
+            ll.check_coefficients(&[0.1, 0.5, 1.01]),
+            Err(Rejection::OutOfRange)
+        );
+        assert_eq!(
+            ll.check_coefficients(&[f32::NAN, 0.5, 0.9]),
+            Err(Rejection::NotFinite)
+        );
+        assert_eq!(ll.check_coefficients(&[]), Ok(()));
+
+        let iir = IirCompensator::<2>::new(0.0, 1.0);
+        let integrator = [0.1, 0.0, 0.0, -1.0, 0.0];
+        // complex poles just outside the unit circle in the second section
+        let resonator = [1.0, 0.0, 0.0, 0.0, 1.01];
+        assert_eq!(iir.check_coefficients(&integrator), Ok(()));
+        assert_eq!(
+            iir.check_coefficients(&[integrator, resonator].concat()),
+            Err(Rejection::OutOfRange)
+        );
+        assert_eq!(
+            iir.check_coefficients(&[integrator, [0.0, 0.0, 0.0, -2.0, 0.99]].concat()),
+            Err(Rejection::OutOfRange)
+        );
+        assert_eq!(
+            iir.check_coefficients(&[0.0, f32::INFINITY, 0.0, 0.0, 0.0]),
+            Err(Rejection::NotFinite)
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use crate::limits::OutputLimits;
+use crate::math::abs;
+use crate::validate::Rejection;
+
+/// Operating mode of a [`Controller`], see [`Controller::step`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Mode {
+    /// Normal feedback operation.
+    #[default]
+    Auto,
+    /// Freeze the output, the controller does not build up any integral action meanwhile.
+    Hold,
+    /// Move the output to the given value, no faster than the slew-rate limit.
+    Manual(f32),
+    /// Let the output follow the given value, e.g. the value applied by something else.
+    Track(f32),
+}
+
+/// Parameters loaded into a [`Controller`] before every iteration.
+pub struct ControllerParams<'a> {
+    /// feedback set point
+    pub setpoint: f32,
+    /// maximum output change per iteration, not positive for no limit
+    pub slew: f32,
+    /// coefficients specific to the control law
+    pub coefficients: &'a [f32],
+}
+
+/// A control law for the feedback loop.
+///
+/// Implementations keep their output within their output limits, see
+/// [`OutputLimits`](crate::limits::OutputLimits).
+///
+/// # Examples
+///
+/// ```no_run
+/// # use qafm_control::controller::{Controller, ControllerParams};
+/// # use qafm_control::pid::PidController;
+/// # fn make_a_new_measurement() -> f32 { 0.0 }
+/// # fn apply_new_output_value(_: f32) {}
+/// let mut pid_c = PidController::builder().limit_output(0.0, 1.0).build();
+/// let ctrl: &mut dyn Controller = &mut pid_c;
+///
+/// loop {
+///     let meas = make_a_new_measurement();
+///     let out = ctrl.update(meas);
+///     apply_new_output_value(out);
+///     ctrl.load_params(&ControllerParams {
+///         setpoint: 0.5,
+///         slew: 0.0,
+///         coefficients: &[5.0, 3.0, 0.0],
+///     });
+/// }
+/// ```
+pub trait Controller {
+    /// Provide a new measurement and generate a new output value.
+    fn update(&mut self, measurement: f32) -> f32;
+
+    /// Clear the controller memory, and continue from `output`.
+    ///
+    /// Use to switch bumplessly from another controller. The output continues exactly from
+    /// `output` as long as the error is zero.
+    fn reset(&mut self, output: f32);
+
+    /// Load new parameters, see the implementations for the meaning of the coefficients.
+    fn load_params(&mut self, params: &ControllerParams<'_>);
+
+    /// Check coefficients before they are loaded, see [`Controller::load_params`].
+    ///
+    /// Coefficients that are rejected, e.g. a negative gain or an unstable pole, should not be
+    /// loaded: load the parameters without coefficients instead, the controller then keeps its
+    /// last coefficients. Too few coefficients are not rejected, they are not loaded anyway.
+    fn check_coefficients(&self, coefficients: &[f32]) -> Result<(), Rejection>;
+
+    /// The last output value of the controller.
+    fn output(&self) -> f32;
+
+    /// The feedback set point, as last loaded.
+    fn setpoint(&self) -> f32;
+
+    /// The output limits of the controller.
+    fn limits(&self) -> &OutputLimits;
+
+    /// Hold the output at its last value, so that the controller continues from it.
+    ///
+    /// Call instead of [`Controller::update`] for as long as the output should be held.
+    fn hold(&mut self, _measurement: f32) -> f32 {
+        let output = self.output();
+        self.reset(output);
+        output
+    }
+
+    /// Set the output to `output`, within the output range but ignoring the slew-rate limit.
+    ///
+    /// Call instead of [`Controller::update`] for as long as the output should follow
+    /// `output`, the controller then continues bumplessly from the last value. A value that is
+    /// NaN or infinite holds the output instead.
+    ///
+    /// The output can step by the whole output range, so the actuator must be rate limited
+    /// downstream, see [`OutputPath`](crate::signal::OutputPath).
+    fn track(&mut self, measurement: f32, output: f32) -> f32 {
+        if !output.is_finite() {
+            return self.hold(measurement);
+        }
+        let (lim_min, lim_max) = self.limits().range();
+        let output = output.clamp(lim_min, lim_max);
+        self.reset(output);
+        output
+    }
+
+    /// Move the output towards `output`, within the output range and the slew-rate limit.
+    ///
+    /// Like [`Controller::track`], but safe to use with values far from the current output.
+    fn manual(&mut self, measurement: f32, output: f32) -> f32 {
+        let (lim_min, lim_max) = self.limits().around(self.output());
+        // NaN is passed on, and holds the output
+        self.track(measurement, output.clamp(lim_min, lim_max))
+    }
+
+    /// Whether `applied` is the last output, within a millionth of its magnitude or of 1.0,
+    /// whichever is larger.
+    fn is_applied(&self, applied: f32) -> bool {
+        let output = self.output();
+        abs(applied - output) <= 1e-6 * abs(output).max(1.0)
+    }
+
+    /// Like [`Controller::update`], but only once the last output has been applied to the
+    /// actuator, as reported by `applied`, see [`Controller::is_applied`]. Until then the
+    /// controller is left as it is, integral term included, and the output stays the same.
+    ///
+    /// Use when someone else applies the output, so that the controller does not wind up
+    /// while the actuator lags behind or does not follow at all. The gains then act per
+    /// applied step rather than per iteration.
+    fn update_applied(&mut self, measurement: f32, applied: f32) -> f32 {
+        if self.is_applied(applied) {
+            self.update(measurement)
+        } else {
+            self.output()
+        }
+    }
+
+    /// Generate a new output value in the given operating mode.
+    fn step(&mut self, measurement: f32, mode: Mode) -> f32 {
+        match mode {
+            Mode::Auto => self.update(measurement),
+            Mode::Hold => self.hold(measurement),
+            Mode::Manual(output) => self.manual(measurement, output),
+            Mode::Track(output) => self.track(measurement, output),
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! The Z feedback, from lockin data to Z bias, one iteration per lockin pixel.
+//!
+//! [`ZFeedback`] is the pipeline run by the firmware on every iteration, and by the simulator
+//! on the host, so that both run the same code. Where the parameters come from, e.g. the
+//! parameter map and the coefficient bank of the firmware, is left to the caller.
+
+use crate::analyzer::NetworkAnalyzer;
+use crate::autotune::{RelayAutotune, TuneState};
+use crate::controller::{Controller, Mode};
+use crate::mailbox::{Command, Response};
+use crate::ramp::RateLimiter;
+use crate::scan::ScanArea;
+use crate::signal::{ErrorPath, OutputPath};
+use crate::supervisor::Supervisor;
+
+/// The Z feedback around a [`Controller`], with `N` biquad sections in the error and output
+/// filters.
+///
+/// Each iteration, see [`ZFeedback::update`]:
+/// - the [`ErrorPath`] turns the lockin data into the error signal
+/// - the controller computes a new output in the requested [`Mode`], or follows the relay of
+///   the [`RelayAutotune`] while autotuning; a hold or retract of the [`Supervisor`] overrides
+///   both
+/// - the perturbation of the [`NetworkAnalyzer`] is added, while a sweep is running and no hold
+///   or retract is in effect
+/// - the [`OutputPath`] turns the sum into the Z bias, within its range and slew-rate limit
+///
+/// Between iterations, the set point is ramped towards its target with
+/// [`ZFeedback::ramp_setpoint`], and commands are carried out with [`ZFeedback::execute`].
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::controller::{Controller, ControllerParams, Mode};
+/// # use qafm_control::feedback::ZFeedback;
+/// # use qafm_control::pid::PidController;
+/// let mut pid = PidController::builder()
+///     .setpoint(0.5)
+///     .gain_i(0.1)
+///     .limit_output(0.0, 1.0)
+///     .build();
+/// let mut feedback = ZFeedback::<2>::new(1.0, pid.setpoint(), pid.limits().range());
+///
+/// let bias = feedback.update(&mut pid, (0.5, 0.0), Mode::Auto, None);
+/// assert_eq!(feedback.error(), 0.25);
+/// assert_eq!(bias, pid.output());
+///
+/// // move the set point at most 0.1 per iteration
+/// let setpoint = feedback.ramp_setpoint(0.8, 0.1);
+/// assert_eq!(setpoint, 0.6);
+/// pid.load_params(&ControllerParams {
+///     setpoint,
+///     slew: 0.0,
+///     coefficients: &[],
+/// });
+/// ```
+pub struct ZFeedback<const N: usize> {
+    error_path: ErrorPath<N>,
+    setpoint: RateLimiter,
+    tuner: RelayAutotune,
+    analyzer: NetworkAnalyzer,
+    supervisor: Supervisor,
+    output_path: OutputPath<N>,
+    error: f32,
+    output: f32,
+}
+impl<const N: usize> ZFeedback<N> {
+    /// Create a new feedback with lockin `scale`, starting from `setpoint`, and with Z bias
+    /// range `(low, high)`.
+    ///
+    /// The filters are bypassed, and there is no limit on the set point ramp and the Z bias
+    /// slew rate.
+    pub fn new(scale: f32, setpoint: f32, (low, high): (f32, f32)) -> Self {
+        let output_path = OutputPath::new(low, high);
+        ZFeedback {
+            error_path: ErrorPath::new(scale),
+            setpoint: RateLimiter::new(setpoint, 0.0),
+            tuner: RelayAutotune::new(0.0, 0.0, 0, 0),
+            analyzer: NetworkAnalyzer::new(),
+            supervisor: Supervisor::new(),
+            error: 0.0,
+            output: output_path.value(),
+            output_path,
+        }
+    }
+
+    /// From lockin data to error signal.
+    pub fn error_path_mut(&mut self) -> &mut ErrorPath<N> {
+        &mut self.error_path
+    }
+
+    /// From controller output to Z bias.
+    pub fn output_path_mut(&mut self) -> &mut OutputPath<N> {
+        &mut self.output_path
+    }
+
+    /// The network analyzer, started and stopped by the caller.
+    pub fn analyzer_mut(&mut self) -> &mut NetworkAnalyzer {
+        &mut self.analyzer
+    }
+
+    /// The relay autotuning, with its state and result.
+    pub fn tuner(&self) -> &RelayAutotune {
+        &self.tuner
+    }
+
+    /// The error signal of the last iteration.
+    pub fn error(&self) -> f32 {
+        self.error
+    }
+
+    /// The controller output of the last iteration, before the perturbation and the output
+    /// path.
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    /// The Z bias of the last iteration.
+    pub fn bias(&self) -> f32 {
+        self.output_path.value()
+    }
+
+    /// The set point, as ramped so far.
+    pub fn setpoint(&self) -> f32 {
+        self.setpoint.value()
+    }
+
+    /// Run one iteration on new lockin data `(i, q)`, and return the new Z bias.
+    ///
+    /// The controller runs in the `requested` mode, or follows the relay while `tune` has the
+    /// autotuning settings, see [`RelayAutotune::load_settings`]. The autotuning starts around
+    /// the current set point and output when `tune` is first given, and stops when it is
+    /// `None`, the controller then continues bumplessly.
+    ///
+    /// A hold or retract command takes precedence: the autotuning stops, and starts over once
+    /// released if `tune` is still given, and the network analyzer is paused meanwhile.
+    pub fn update(
+        &mut self,
+        controller: &mut dyn Controller,
+        (i, q): (f32, f32),
+        requested: Mode,
+        tune: Option<&[f32]>,
+    ) -> f32 {
+        self.error = self.error_path.process(i, q);
+        let overridden = self.supervisor.overrides();
+        let mode = match tune {
+            Some(settings) if !overridden => {
+                if self.tuner.state() == TuneState::Idle {
+                    self.tuner.load_settings(settings);
+                    self.tuner.start(self.setpoint.value(), controller.output());
+                }
+                // keep the control law ready to take over from the relay
+                Mode::Track(self.tuner.update(self.error))
+            }
+            _ => {
+                self.tuner.stop();
+                self.supervisor.mode(requested)
+            }
+        };
+        self.output = controller.step(self.error, mode);
+        let perturbation = if overridden {
+            0.0
+        } else {
+            self.analyzer.update(self.error, self.output)
+        };
+        self.output_path.process(self.output + perturbation)
+    }
+
+    /// Move the set point towards `target` by at most `max_step`, and return the set point to
+    /// load into the controller. A `max_step` that is not positive disables the ramp.
+    pub fn ramp_setpoint(&mut self, target: f32, max_step: f32) -> f32 {
+        self.setpoint.set_max_step(max_step);
+        self.setpoint.update(target)
+    }
+
+    /// Carry out a command on `controller`, see [`Supervisor::execute`].
+    pub fn execute(
+        &mut self,
+        command: Command,
+        controller: &mut dyn Controller,
+        area: impl FnOnce() -> ScanArea,
+        self_test: impl FnOnce() -> u32,
+    ) -> Response {
+        self.supervisor
+            .execute(command, controller, self.output, area, self_test)
+    }
+
+    /// Answer to the command still running once done, with the Z bias range `(low, high)` of
+    /// the controller, see [`Supervisor::poll`].
+    pub fn poll(&mut self, limits: (f32, f32)) -> Option<Response> {
+        let bias = self.bias();
+        self.supervisor.poll(limits, bias)
+    }
+
+    /// Position of the scanner `(x, y)` for this iteration, `None` unless a raster scan is
+    /// running.
+    pub fn scan(&mut self) -> Option<(f32, f32)> {
+        self.supervisor.scan()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::Sweep;
+    use crate::mailbox::{Completion, Request};
+    use crate::pid::PidController;
+
+    fn pid() -> PidController {
+        PidController::builder()
+            .setpoint(0.25)
+            .gain_i(0.01)
+            .limit_output(0.2, 0.8)
+            .build()
+    }
+
+    #[test]
+    fn autotune_from_current_output() {
+        let mut pid = pid();
+        pid.reset(0.5);
+        let mut feedback = ZFeedback::<1>::new(1.0, pid.setpoint(), pid.limits().range());
+        let settings = [0.1, 0.0, 2.0, 1000.0];
+        // amp^2 above the set point, the relay goes low around the current output
+        let bias = feedback.update(&mut pid, (0.6, 0.0), Mode::Auto, Some(&settings));
+        assert_eq!(feedback.tuner().state(), TuneState::Running);
+        assert_eq!(bias, 0.4);
+        assert_eq!(pid.output(), 0.4);
+        // and high once amp^2 is below the set point
+        let bias = feedback.update(&mut pid, (0.4, 0.0), Mode::Auto, Some(&settings));
+        assert_eq!(bias, 0.6);
+
+        // the controller continues from the relay once stopped
+        feedback.update(&mut pid, (0.5, 0.0), Mode::Hold, None);
+        assert_eq!(feedback.tuner().state(), TuneState::Idle);
+        assert_eq!(feedback.bias(), 0.6);
+    }
+
+    #[test]
+    fn retract_during_autotune() {
+        let mut pid = pid();
+        pid.reset(0.5);
+        let mut feedback = ZFeedback::<1>::new(1.0, pid.setpoint(), pid.limits().range());
+        let settings = [0.1, 0.0, 2.0, 1000.0];
+        feedback.update(&mut pid, (0.6, 0.0), Mode::Auto, Some(&settings));
+        assert_eq!(feedback.tuner().state(), TuneState::Running);
+
+        let retract = Command::decode((1 << 16) | Request::Retract.code(), 0.7);
+        let response = feedback.execute(retract, &mut pid, || unreachable!(), || 0);
+        assert_eq!(response.completion, Completion::Running);
+        // the relay no longer drives Z, and the retract completes
+        let bias = feedback.update(&mut pid, (0.4, 0.0), Mode::Auto, Some(&settings));
+        assert_eq!(feedback.tuner().state(), TuneState::Idle);
+        assert_eq!(bias, 0.7);
+        let response = feedback.poll(pid.limits().range()).unwrap();
+        assert_eq!(
+            (response.sequence, response.completion),
+            (1, Completion::Done)
+        );
+
+        // the autotuning starts over from the retracted Z bias once released
+        let release = Command::decode((2 << 16) | Request::Hold.code(), 0.0);
+        feedback.execute(release, &mut pid, || unreachable!(), || 0);
+        let bias = feedback.update(&mut pid, (0.6, 0.0), Mode::Auto, Some(&settings));
+        assert_eq!(feedback.tuner().state(), TuneState::Running);
+        assert_eq!(bias, 0.7 - 0.1);
+    }
+
+    #[test]
+    fn no_perturbation_while_held() {
+        let mut pid = pid();
+        let mut feedback = ZFeedback::<1>::new(1.0, 0.5, (0.2, 0.8));
+        feedback.analyzer_mut().start(Sweep {
+            f_start: 0.25,
+            f_stop: 0.25,
+            points: 1,
+            amplitude: 0.1,
+            settle_cycles: 1.0,
+            measure_cycles: 1.0,
+        });
+        feedback.update(&mut pid, (0.5, 0.0), Mode::Track(0.5), None);
+        let hold = Command::decode((1 << 16) | Request::Hold.code(), 1.0);
+        feedback.execute(hold, &mut pid, || unreachable!(), || 0);
+        for _ in 0..4 {
+            assert_eq!(feedback.update(&mut pid, (0.5, 0.0), Mode::Auto, None), 0.5);
+        }
+        assert!(feedback.analyzer_mut().is_running());
+    }
+
+    #[test]
+    fn perturbation_within_output_range() {
+        let mut pid = pid();
+        let mut feedback = ZFeedback::<1>::new(1.0, 0.5, (0.2, 0.8));
+        feedback.analyzer_mut().start(Sweep {
+            f_start: 0.25,
+            f_stop: 0.25,
+            points: 1,
+            amplitude: 1.0,
+            settle_cycles: 1.0,
+            measure_cycles: 1.0,
+        });
+        // the perturbation is added after the controller, and limited by the output path
+        feedback.update(&mut pid, (0.5, 0.0), Mode::Track(0.5), None);
+        let bias = feedback.update(&mut pid, (0.5, 0.0), Mode::Track(0.5), None);
+        assert_eq!(feedback.output(), 0.5);
+        assert_eq!(bias, 0.8);
+    }
+}
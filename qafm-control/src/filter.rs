@@ -0,0 +1,330 @@
+//! IIR filters made of biquad sections.
+//!
+//! Frequencies are normalized to the sample rate, i.e. in cycles per iteration, and must be
+//! between 0.0 and 0.5 (the Nyquist frequency).
+
+use crate::math::{abs, cos, sin, sqrt};
+use crate::validate::{finite, Rejection};
+use core::f32::consts::TAU;
+
+/// A second-order IIR filter section (biquad).
+///
+/// Implements the transfer function
+/// `H(z) = (b0 + b1 z^-1 + b2 z^-2) / (1 + a1 z^-1 + a2 z^-2)`
+/// in transposed direct form II.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Biquad {
+    // coefficients
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+
+    // filter state
+    s1: f32,
+    s2: f32,
+}
+impl Biquad {
+    /// Coefficients of a section that passes its input through unchanged.
+    pub const BYPASS: [f32; 5] = [1.0, 0.0, 0.0, 0.0, 0.0];
+
+    /// Create a new section from the coefficients `[b0, b1, b2, a1, a2]`.
+    pub fn new(coefficients: [f32; 5]) -> Self {
+        let mut biquad = Biquad::default();
+        biquad.set_coefficients(coefficients);
+        biquad
+    }
+
+    /// Check the coefficients `[b0, b1, b2, a1, a2]`: all finite, and the poles inside or on the
+    /// unit circle, so that the section is stable or at most an integrator.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use qafm_control::filter::Biquad;
+    /// # use qafm_control::validate::Rejection;
+    /// assert_eq!(Biquad::check_coefficients([1.0, 0.0, 0.0, -1.0, 0.0]), Ok(()));
+    /// assert_eq!(
+    ///     Biquad::check_coefficients([1.0, 0.0, 0.0, -1.1, 0.0]),
+    ///     Err(Rejection::OutOfRange)
+    /// );
+    /// ```
+    pub fn check_coefficients(coefficients: [f32; 5]) -> Result<(), Rejection> {
+        finite(&coefficients)?;
+        let [_, _, _, a1, a2] = coefficients;
+        // stability triangle of 1 + a1 z^-1 + a2 z^-2, edges included
+        if a2 <= 1.0 && abs(a1) <= 1.0 + a2 {
+            Ok(())
+        } else {
+            Err(Rejection::OutOfRange)
+        }
+    }
+
+    /// Change the coefficients `[b0, b1, b2, a1, a2]`, keeping the filter state.
+    pub fn set_coefficients(&mut self, coefficients: [f32; 5]) {
+        [self.b0, self.b1, self.b2, self.a1, self.a2] = coefficients;
+    }
+
+    /// Clear the filter state.
+    pub fn reset(&mut self) {
+        self.s1 = 0.0;
+        self.s2 = 0.0;
+    }
+
+    /// Filter one new sample.
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.s1;
+        self.s1 = self.b1 * x - self.a1 * y + self.s2;
+        self.s2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// Design of a biquad section.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Design {
+    /// Pass the input through unchanged.
+    Bypass,
+    /// Second-order low-pass with cutoff frequency `f` and quality factor `q`.
+    LowPass { f: f32, q: f32 },
+    /// Notch at frequency `f` with quality factor `q`.
+    Notch { f: f32, q: f32 },
+    /// Band-stop between frequencies `f_lo` and `f_hi`.
+    BandStop { f_lo: f32, f_hi: f32 },
+    /// Arbitrary coefficients `[b0, b1, b2, a1, a2]`, see [`Biquad`].
+    Custom([f32; 5]),
+}
+impl Design {
+    /// Number of parameters describing one section, see [`Design::from_params`].
+    pub const NR_PARAMS: usize = 6;
+
+    /// Parse a design from its parameters `[kind, p1, p2, p3, p4, p5]`:
+    /// - kind 0: bypass
+    /// - kind 1: low-pass, `p1 = f`, `p2 = q`
+    /// - kind 2: notch, `p1 = f`, `p2 = q`
+    /// - kind 3: band-stop, `p1 = f_lo`, `p2 = f_hi`
+    /// - kind 4: custom, `p1..=p5 = [b0, b1, b2, a1, a2]`
+    ///
+    /// Any other kind, or too few parameters, is treated as bypass.
+    pub fn from_params(params: &[f32]) -> Self {
+        match *params {
+            [kind, p1, p2, p3, p4, p5, ..] => match kind as u32 {
+                1 => Design::LowPass { f: p1, q: p2 },
+                2 => Design::Notch { f: p1, q: p2 },
+                3 => Design::BandStop { f_lo: p1, f_hi: p2 },
+                4 => Design::Custom([p1, p2, p3, p4, p5]),
+                _ => Design::Bypass,
+            },
+            _ => Design::Bypass,
+        }
+    }
+
+    /// Compute the coefficients `[b0, b1, b2, a1, a2]` of the section.
+    ///
+    /// Low-pass and notch follow the well-known "Audio EQ Cookbook" by R. Bristow-Johnson.
+    pub fn coefficients(&self) -> [f32; 5] {
+        match *self {
+            Design::Bypass => Biquad::BYPASS,
+            Design::LowPass { f, q } => {
+                let (cos_w, alpha) = cos_alpha(f, q);
+                let b1 = 1.0 - cos_w;
+                normalize([
+                    0.5 * b1,
+                    b1,
+                    0.5 * b1,
+                    1.0 + alpha,
+                    -2.0 * cos_w,
+                    1.0 - alpha,
+                ])
+            }
+            Design::Notch { f, q } => {
+                let (cos_w, alpha) = cos_alpha(f, q);
+                normalize([
+                    1.0,
+                    -2.0 * cos_w,
+                    1.0,
+                    1.0 + alpha,
+                    -2.0 * cos_w,
+                    1.0 - alpha,
+                ])
+            }
+            Design::BandStop { f_lo, f_hi } => {
+                // notch at the geometric center, with the band as -3 dB bandwidth
+                let f = sqrt(f_lo * f_hi);
+                Design::Notch {
+                    f,
+                    q: f / (f_hi - f_lo),
+                }
+                .coefficients()
+            }
+            Design::Custom(coefficients) => coefficients,
+        }
+    }
+}
+
+/// Cosine of the angular frequency, and the `alpha` parameter of the cookbook designs.
+fn cos_alpha(f: f32, q: f32) -> (f32, f32) {
+    let w = TAU * f;
+    (cos(w), sin(w) / (2.0 * q))
+}
+
+/// Normalize `[b0, b1, b2, a0, a1, a2]` so that `a0` is 1.0.
+fn normalize(c: [f32; 6]) -> [f32; 5] {
+    let a0 = c[3];
+    [c[0] / a0, c[1] / a0, c[2] / a0, c[4] / a0, c[5] / a0]
+}
+
+/// A cascade of `N` biquad sections.
+///
+/// The sections can be loaded either with raw coefficients or with designs, see
+/// [`Design::from_params`]. If the output ever becomes NaN or infinite, e.g. because of unstable
+/// coefficients, the filter state is cleared and the input is passed through for that sample.
+pub struct Cascade<const N: usize> {
+    sections: [Biquad; N],
+    designs: [[f32; Design::NR_PARAMS]; N],
+}
+impl<const N: usize> Cascade<N> {
+    /// Number of raw coefficients to load all sections, see [`Cascade::load_coefficients`].
+    pub const NR_COEFFICIENTS: usize = 5 * N;
+    /// Number of design parameters to load all sections, see [`Cascade::load_designs`].
+    pub const NR_DESIGN_PARAMS: usize = Design::NR_PARAMS * N;
+
+    /// Create a new cascade that passes its input through unchanged.
+    pub fn new() -> Self {
+        Cascade {
+            sections: [Biquad::new(Biquad::BYPASS); N],
+            designs: [[0.0; Design::NR_PARAMS]; N],
+        }
+    }
+
+    /// Load raw coefficients `[b0, b1, b2, a1, a2]` of each section in turn.
+    pub fn load_coefficients(&mut self, coefficients: &[f32]) {
+        for (section, c) in self.sections.iter_mut().zip(coefficients.chunks_exact(5)) {
+            section.set_coefficients([c[0], c[1], c[2], c[3], c[4]]);
+        }
+    }
+
+    /// Load design parameters of each section in turn, see [`Design::from_params`].
+    ///
+    /// Only sections whose parameters changed since the last call are redesigned, so this is
+    /// cheap to call on every iteration.
+    pub fn load_designs(&mut self, params: &[f32]) {
+        let chunks = params.chunks_exact(Design::NR_PARAMS);
+        for ((section, last), p) in self
+            .sections
+            .iter_mut()
+            .zip(self.designs.iter_mut())
+            .zip(chunks)
+        {
+            if last[..] != *p {
+                last.copy_from_slice(p);
+                section.set_coefficients(Design::from_params(p).coefficients());
+            }
+        }
+    }
+
+    /// Clear the state of all sections.
+    pub fn reset(&mut self) {
+        self.sections.iter_mut().for_each(Biquad::reset);
+    }
+
+    /// Filter one new sample.
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self
+            .sections
+            .iter_mut()
+            .fold(x, |x, section| section.process(x));
+        if y.is_finite() {
+            y
+        } else {
+            self.reset();
+            x
+        }
+    }
+}
+impl<const N: usize> Default for Cascade<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::abs;
+    use core::f32::consts::FRAC_1_SQRT_2;
+
+    /// Steady-state amplitude of the response to a sine at frequency `f`.
+    fn gain<const N: usize>(cascade: &mut Cascade<N>, f: f32) -> f32 {
+        cascade.reset();
+        let mut peak: f32 = 0.0;
+        for n in 0..20_000 {
+            let y = cascade.process(sin(TAU * f * n as f32));
+            if n >= 10_000 {
+                peak = peak.max(abs(y));
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn impulse_response() {
+        // y[n] = x[n] + 0.5 x[n-1] + 0.5 y[n-1]
+        let mut biquad = Biquad::new([1.0, 0.5, 0.0, -0.5, 0.0]);
+        assert_eq!(biquad.process(1.0), 1.0);
+        assert_eq!(biquad.process(0.0), 1.0);
+        assert_eq!(biquad.process(0.0), 0.5);
+        assert_eq!(biquad.process(0.0), 0.25);
+        biquad.reset();
+        assert_eq!(biquad.process(0.0), 0.0);
+    }
+
+    #[test]
+    fn lowpass_response() {
+        let mut cascade = Cascade::<1>::new();
+        cascade.load_designs(&[1.0, 0.01, FRAC_1_SQRT_2, 0.0, 0.0, 0.0]);
+        assert!(abs(gain(&mut cascade, 0.0005) - 1.0) < 0.01);
+        assert!(abs(gain(&mut cascade, 0.01) - FRAC_1_SQRT_2) < 0.01);
+        assert!(gain(&mut cascade, 0.1) < 0.02);
+    }
+
+    #[test]
+    fn notch_response() {
+        let mut cascade = Cascade::<1>::new();
+        cascade.load_designs(&[2.0, 0.05, 10.0, 0.0, 0.0, 0.0]);
+        assert!(gain(&mut cascade, 0.05) < 0.01);
+        assert!(abs(gain(&mut cascade, 0.02) - 1.0) < 0.05);
+        assert!(abs(gain(&mut cascade, 0.1) - 1.0) < 0.05);
+    }
+
+    #[test]
+    fn bandstop_edges() {
+        let mut cascade = Cascade::<1>::new();
+        cascade.load_designs(&[3.0, 0.04, 0.06, 0.0, 0.0, 0.0]);
+        assert!(gain(&mut cascade, sqrt(0.04 * 0.06)) < 0.01);
+        assert!(abs(gain(&mut cascade, 0.04) - FRAC_1_SQRT_2) < 0.05);
+        assert!(abs(gain(&mut cascade, 0.06) - FRAC_1_SQRT_2) < 0.05);
+    }
+
+    #[test]
+    fn cascade_bypass_and_custom() {
+        let mut cascade = Cascade::<2>::new();
+        assert_eq!(cascade.process(0.3), 0.3);
+        // second section as a gain of 2
+        cascade.load_designs(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(cascade.process(0.3), 0.6);
+    }
+
+    #[test]
+    fn unstable_cascade_recovers() {
+        let mut cascade = Cascade::<1>::new();
+        cascade.load_coefficients(&[1.0, 0.0, 0.0, -2.0, 0.0]);
+        let mut y = 0.0;
+        for _ in 0..1000 {
+            y = cascade.process(1.0);
+            assert!(y.is_finite());
+        }
+        assert!(y >= 1.0);
+    }
+}
@@ -0,0 +1,400 @@
+//! Lockin data frames with many frequencies, and the quantities of intermodulation spectra
+//! computed from them.
+//!
+//! A frame holds one word per frequency: I in the low 32 bits and Q in the high 32 bits, both
+//! as `f32`. The frequencies of the first input group come first, followed by those of the
+//! next input groups, see [`FrameLayout`].
+//!
+//! With a non-zero intermediate frequency, the phase of I and Q advances from one frame to the
+//! next, which is undone by a [`Derotator`].
+
+use core::f32::consts::PI;
+
+use crate::coefficients::count;
+use crate::math::{cos, sin, wrap_angle};
+use crate::signal::{amplitude, phase};
+
+/// Split a word of a lockin frame into I and Q.
+pub fn unpack(word: u64) -> (f32, f32) {
+    (
+        f32::from_bits(word as u32),
+        f32::from_bits((word >> 32) as u32),
+    )
+}
+
+/// Layout of a lockin frame: the number of frequencies in each of up to `G` input groups.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::frame::FrameLayout;
+/// let layout = FrameLayout::<4>::new(&[3, 2]);
+/// assert_eq!(layout.len(), 5);
+/// assert_eq!(layout.index(1, 0), Some(3));
+/// assert_eq!(layout.index(1, 2), None);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameLayout<const G: usize> {
+    freqs: [usize; G],
+    groups: usize,
+}
+impl<const G: usize> FrameLayout<G> {
+    /// Number of coefficients describing a layout, see [`FrameLayout::from_coefficients`].
+    pub const NR_COEFFICIENTS: usize = 1 + G;
+
+    /// Layout from the coefficients `[groups, freqs of group 0, freqs of group 1, ...]`, as
+    /// whole numbers, with at most `max_freqs` frequencies per group.
+    ///
+    /// Input groups beyond `G`, or beyond the coefficients given, are ignored.
+    pub fn from_coefficients(coefficients: &[f32], max_freqs: usize) -> Self {
+        let count = |value| count(value).unwrap_or(0) as usize;
+        let (&groups, freqs_per_group) = coefficients.split_first().unwrap_or((&0.0, &[]));
+        let groups = count(groups).min(G).min(freqs_per_group.len());
+        let mut freqs = [0; G];
+        for (n, &f) in freqs.iter_mut().zip(&freqs_per_group[..groups]) {
+            *n = count(f).min(max_freqs);
+        }
+        FrameLayout { freqs, groups }
+    }
+
+    /// Create a layout with the given number of frequencies per input group.
+    ///
+    /// Input groups beyond `G` are ignored.
+    pub fn new(freqs_per_group: &[usize]) -> Self {
+        let mut freqs = [0; G];
+        let groups = freqs_per_group.len().min(G);
+        freqs[..groups].copy_from_slice(&freqs_per_group[..groups]);
+        FrameLayout { freqs, groups }
+    }
+
+    /// Number of input groups.
+    pub fn groups(&self) -> usize {
+        self.groups
+    }
+
+    /// Number of frequencies of input `group`, 0 if there is no such group.
+    pub fn freqs(&self, group: usize) -> usize {
+        if group < self.groups {
+            self.freqs[group]
+        } else {
+            0
+        }
+    }
+
+    /// Total number of frequencies, i.e. words, in the frame.
+    pub fn len(&self) -> usize {
+        self.freqs[..self.groups].iter().sum()
+    }
+
+    /// Whether the frame is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Index in the frame of frequency `freq` of input `group`.
+    pub fn index(&self, group: usize, freq: usize) -> Option<usize> {
+        if freq < self.freqs(group) {
+            Some(self.freqs[..group].iter().sum::<usize>() + freq)
+        } else {
+            None
+        }
+    }
+}
+
+/// Kind of lockin producing the frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockinMode {
+    /// `Lockin`: the carrier is the first frequency of the first input group
+    #[default]
+    Lockin,
+    /// `SymmetricLockin`: the frequencies of the first input group are symmetric around the
+    /// carrier, which is the middle one
+    Symmetric,
+}
+impl LockinMode {
+    /// Lockin mode from its code: 0 `Lockin` or 1 `SymmetricLockin`.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(LockinMode::Lockin),
+            1 => Some(LockinMode::Symmetric),
+            _ => None,
+        }
+    }
+
+    /// Index in the frame of the carrier, for a frame with `layout`.
+    pub fn carrier<const G: usize>(&self, layout: &FrameLayout<G>) -> usize {
+        match self {
+            LockinMode::Lockin => 0,
+            LockinMode::Symmetric => layout.freqs(0) / 2,
+        }
+    }
+}
+
+/// Undo the phase advance of I and Q from one frame to the next, e.g. due to a non-zero
+/// intermediate frequency.
+///
+/// The phase advance per frame is given as a fraction of a cycle in units of `2^-32`, so that
+/// the phase is accumulated exactly, however long the measurement. The phase is that of the
+/// first frame after the last change of the phase advance, which is arbitrary.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::frame::Derotator;
+/// // a quarter cycle per frame, from phase 0 in the first frame
+/// let mut derotator = Derotator::new(1 << 30);
+/// derotator.advance();
+/// derotator.advance();
+/// let (i, q) = derotator.apply(0.0, 1.0);
+/// assert!((i - 1.0).abs() < 1e-6 && q.abs() < 1e-6);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Derotator {
+    step: u32,
+    phase: u32,
+    cos: f32,
+    sin: f32,
+}
+impl Derotator {
+    /// Create a new derotator with a phase advance of `step` per frame.
+    pub fn new(step: u32) -> Self {
+        Derotator {
+            step,
+            phase: 0u32.wrapping_sub(step),
+            cos: 1.0,
+            sin: 0.0,
+        }
+    }
+
+    /// Change the phase advance per frame, the phase starts over with the next frame.
+    pub fn set_step(&mut self, step: u32) {
+        if step != self.step {
+            *self = Self::new(step);
+        }
+    }
+
+    /// Move on to the next frame.
+    pub fn advance(&mut self) {
+        self.phase = self.phase.wrapping_add(self.step);
+        if self.step == 0 {
+            return;
+        }
+        // as signed, the angle is in -pi..pi
+        let angle = self.phase as i32 as f32 * (PI / (1u32 << 31) as f32);
+        self.cos = cos(angle);
+        self.sin = sin(angle);
+    }
+
+    /// Undo the phase advance of the current frame on I and Q of one tone.
+    pub fn apply(&self, i: f32, q: f32) -> (f32, f32) {
+        // multiply by exp(-j phase)
+        (i * self.cos + q * self.sin, q * self.cos - i * self.sin)
+    }
+}
+
+/// How the tones of a [`Spectrum`] are combined into one signal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Combination {
+    /// `sum w_k A_k`, with `A_k` the amplitude of tone `k`
+    #[default]
+    Amplitudes,
+    /// `sum (wi_k I_k + wq_k Q_k)`, e.g. a component of the tip-sample force
+    Quadratures,
+    /// `sum w_k phi_k` wrapped to `-pi..=pi`, with `phi_k` the phase of tone `k`, e.g. the
+    /// phase of one tone or the difference between two
+    Phases,
+}
+impl Combination {
+    /// Combination from its code: 0 amplitudes, 1 quadratures or 2 phases.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Combination::Amplitudes),
+            1 => Some(Combination::Quadratures),
+            2 => Some(Combination::Phases),
+            _ => None,
+        }
+    }
+}
+
+/// I and Q of up to `N` tones of a lockin frame, e.g. an intermodulation spectrum.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::frame::{Combination, Spectrum};
+/// let mut spectrum = Spectrum::<8>::new();
+/// spectrum.push(3.0, 4.0);
+/// spectrum.push(0.0, 1.0);
+/// assert_eq!(spectrum.amplitude(1), 1.0);
+/// let weights = [1.0, 0.0, 0.0, 2.0];
+/// assert_eq!(spectrum.combine(Combination::Quadratures, &weights), 5.0);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Spectrum<const N: usize> {
+    tones: [(f32, f32); N],
+    len: usize,
+}
+impl<const N: usize> Spectrum<N> {
+    /// Create a new, empty spectrum.
+    pub fn new() -> Self {
+        Spectrum {
+            tones: [(0.0, 0.0); N],
+            len: 0,
+        }
+    }
+
+    /// Remove all tones.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Add a tone, ignored if the spectrum is full.
+    pub fn push(&mut self, i: f32, q: f32) {
+        if self.len < N {
+            self.tones[self.len] = (i, q);
+            self.len += 1;
+        }
+    }
+
+    /// Number of tones.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no tones.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// I and Q of tone `k`, 0.0 beyond the last tone.
+    pub fn tone(&self, k: usize) -> (f32, f32) {
+        if k < self.len {
+            self.tones[k]
+        } else {
+            (0.0, 0.0)
+        }
+    }
+
+    /// Amplitude of tone `k`, see [`amplitude`].
+    pub fn amplitude(&self, k: usize) -> f32 {
+        let (i, q) = self.tone(k);
+        amplitude(i, q)
+    }
+
+    /// Phase of tone `k` in radians, see [`phase`].
+    pub fn phase(&self, k: usize) -> f32 {
+        let (i, q) = self.tone(k);
+        phase(i, q)
+    }
+
+    /// Combine the tones into one signal, with two weights per tone in `weights`.
+    ///
+    /// Only the first weight of each tone is used, except for [`Combination::Quadratures`].
+    /// Tones without weights, and weights without tones, are left out.
+    pub fn combine(&self, combination: Combination, weights: &[f32]) -> f32 {
+        let tones = self.tones[..self.len].iter().zip(weights.chunks_exact(2));
+        match combination {
+            Combination::Amplitudes => tones.map(|(&(i, q), w)| w[0] * amplitude(i, q)).sum(),
+            Combination::Quadratures => tones.map(|(&(i, q), w)| w[0] * i + w[1] * q).sum(),
+            Combination::Phases => wrap_angle(tones.map(|(&(i, q), w)| w[0] * phase(i, q)).sum()),
+        }
+    }
+}
+impl<const N: usize> Default for Spectrum<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::abs;
+    use core::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn unpack_word() {
+        let word = (2.5_f32.to_bits() as u64) << 32 | (-1.0_f32).to_bits() as u64;
+        assert_eq!(unpack(word), (-1.0, 2.5));
+    }
+
+    #[test]
+    fn layout_indices() {
+        let layout = FrameLayout::<2>::new(&[2, 3, 4]);
+        assert_eq!(layout.groups(), 2);
+        assert_eq!(layout.len(), 5);
+        assert_eq!(layout.index(0, 1), Some(1));
+        assert_eq!(layout.index(1, 2), Some(4));
+        assert_eq!(layout.index(2, 0), None);
+    }
+
+    #[test]
+    fn layout_from_coefficients() {
+        let layout = FrameLayout::<2>::from_coefficients(&[3.0, 2.0, 3.0], 100);
+        assert_eq!(layout, FrameLayout::new(&[2, 3]));
+        // frequencies limited, invalid counts are 0
+        let layout = FrameLayout::<2>::from_coefficients(&[2.0, 500.0, f32::NAN], 100);
+        assert_eq!(layout, FrameLayout::new(&[100, 0]));
+        assert!(FrameLayout::<2>::from_coefficients(&[-1.0, 2.0, 3.0], 100).is_empty());
+        assert_eq!(FrameLayout::<2>::from_coefficients(&[], 100).groups(), 0);
+    }
+
+    #[test]
+    fn carrier_of_symmetric_lockin() {
+        let layout = FrameLayout::<2>::new(&[5, 2]);
+        assert_eq!(LockinMode::Lockin.carrier(&layout), 0);
+        assert_eq!(LockinMode::Symmetric.carrier(&layout), 2);
+    }
+
+    #[test]
+    fn derotation_undoes_phase_advance() {
+        // an intermediate frequency of about 0.3 cycles per frame
+        let step = (0.3 * 4_294_967_296.0) as u32;
+        let cycles = step as f64 / 4_294_967_296.0;
+        let mut derotator = Derotator::new(step);
+        for n in 0..100_000 {
+            derotator.advance();
+            let angle = core::f64::consts::TAU * (cycles * n as f64).fract();
+            let (i, q) = (angle.cos() as f32, angle.sin() as f32);
+            let (i, q) = derotator.apply(i, q);
+            assert!(abs(i - 1.0) < 1e-4 && abs(q) < 1e-4, "frame {}", n);
+        }
+    }
+
+    #[test]
+    fn zero_step_leaves_data_untouched() {
+        let mut derotator = Derotator::new(0);
+        derotator.advance();
+        assert_eq!(derotator.apply(0.5, -0.25), (0.5, -0.25));
+        // half a cycle per frame, starting over from the next frame
+        derotator.set_step(1 << 31);
+        derotator.advance();
+        let (i, q) = derotator.apply(0.5, -0.25);
+        assert!(abs(i - 0.5) < 1e-6 && abs(q + 0.25) < 1e-6);
+        derotator.advance();
+        let (i, q) = derotator.apply(0.5, -0.25);
+        assert!(abs(i + 0.5) < 1e-6 && abs(q - 0.25) < 1e-6);
+    }
+
+    #[test]
+    fn spectrum_quantities() {
+        let mut spectrum = Spectrum::<2>::new();
+        spectrum.push(0.0, -2.0);
+        spectrum.push(1.0, 0.0);
+        spectrum.push(5.0, 5.0);
+        assert_eq!(spectrum.len(), 2);
+        assert!(abs(spectrum.amplitude(0) - 2.0) < 1e-6);
+        assert!(abs(spectrum.phase(0) + FRAC_PI_2) < 1e-5);
+        let weights = [0.5, 1.0, 2.0, 1.0];
+        assert!(abs(spectrum.combine(Combination::Amplitudes, &weights) - 3.0) < 1e-5);
+        assert_eq!(spectrum.combine(Combination::Quadratures, &weights), 0.0);
+        assert!(abs(spectrum.combine(Combination::Amplitudes, &weights[..2]) - 1.0) < 1e-5);
+        // phase difference, wrapped
+        let weights = [-1.0, 0.0, 1.0, 0.0];
+        assert!(abs(spectrum.combine(Combination::Phases, &weights) - FRAC_PI_2) < 1e-5);
+        let mut spectrum = Spectrum::<2>::new();
+        spectrum.push(-1.0, -0.1);
+        spectrum.push(-1.0, 0.1);
+        assert!(abs(spectrum.combine(Combination::Phases, &weights) + 0.2) < 1e-3);
+    }
+}
@@ -0,0 +1,31 @@
+//! Control algorithms for the QAFM feedback engine.
+//!
+//! This crate is `no_std` and free of any hardware access, so that it can run on the RPU as well
+//! as be unit tested on the host:
+//! ```text
+//! cargo test -p qafm-control --target x86_64-unknown-linux-gnu
+//! ```
+#![no_std]
+
+pub mod analyzer;
+pub mod autotune;
+pub mod average;
+pub mod coefficients;
+pub mod compensator;
+pub mod controller;
+pub mod feedback;
+pub mod filter;
+pub mod frame;
+pub mod limits;
+pub mod mailbox;
+pub mod math;
+pub mod pid;
+pub mod pll;
+pub mod ramp;
+pub mod scan;
+pub mod schedule;
+pub mod seqlock;
+pub mod signal;
+pub mod supervisor;
+pub mod timing;
+pub mod validate;
@@ -0,0 +1,78 @@
+/// Limits on the output of a controller: a fixed range and a maximum change per iteration.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputLimits {
+    min: f32,
+    max: f32,
+    slew: f32,
+}
+impl OutputLimits {
+    /// Limit the output to the range `min..=max`, without a slew-rate limit.
+    pub fn new(min: f32, max: f32) -> Self {
+        OutputLimits {
+            min,
+            max,
+            slew: 0.0,
+        }
+    }
+
+    /// No limits at all.
+    pub fn unlimited() -> Self {
+        Self::new(f32::NEG_INFINITY, f32::INFINITY)
+    }
+
+    /// Lower and upper limit of the output range.
+    pub fn range(&self) -> (f32, f32) {
+        (self.min, self.max)
+    }
+
+    /// Maximum change of the output per iteration, not positive without a slew-rate limit.
+    pub fn slew_rate(&self) -> f32 {
+        self.slew
+    }
+
+    /// Change the maximum change of the output per iteration.
+    ///
+    /// A `max_step` that is not positive disables the slew-rate limit.
+    pub fn set_slew_rate(&mut self, max_step: f32) {
+        self.slew = max_step;
+    }
+
+    /// Limits for the next output, narrowed around the `last` output by the slew-rate limit.
+    pub fn around(&self, last: f32) -> (f32, f32) {
+        if self.slew > 0.0 {
+            let min = f32::max(self.min, last - self.slew);
+            let max = f32::min(self.max, last + self.slew);
+            // output range takes precedence if the last output is outside of it
+            (min.min(self.max), max.max(self.min))
+        } else {
+            (self.min, self.max)
+        }
+    }
+
+    /// A starting value for the output: 0.0 if within range, otherwise the closest limit.
+    pub fn initial(&self) -> f32 {
+        f32::max(0.0, self.min).min(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slew_narrows_range() {
+        let mut limits = OutputLimits::new(0.0, 1.0);
+        assert_eq!(limits.around(0.5), (0.0, 1.0));
+        limits.set_slew_rate(0.125);
+        assert_eq!(limits.around(0.5), (0.375, 0.625));
+        assert_eq!(limits.around(0.95), (0.825, 1.0));
+    }
+
+    #[test]
+    fn range_takes_precedence() {
+        let mut limits = OutputLimits::new(0.5, 1.0);
+        limits.set_slew_rate(0.125);
+        assert_eq!(limits.around(0.0), (0.5, 0.5));
+        assert_eq!(limits.around(2.0), (1.0, 1.0));
+    }
+}
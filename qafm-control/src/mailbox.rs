@@ -0,0 +1,275 @@
+//! One-shot commands from another processor, e.g. the APU, each with a sequence number and
+//! answered with a [`Response`].
+//!
+//! A command is two 32-bit words, as in a slot of the parameter map:
+//! - request code in bits 0-15 and sequence number in bits 16-31, changed by the sender for
+//!   every command so that sending the same command twice is noticed
+//! - the argument, as f32
+//!
+//! The response has the same layout, with the [`Completion`] code instead of the request code
+//! and the result instead of the argument. Commands that take a while are first answered as
+//! running, and answered again once done.
+//!
+//! # Examples
+//!
+//! ```
+//! # use qafm_control::mailbox::{Completion, Mailbox, Request};
+//! let mut mailbox = Mailbox::new(0);
+//! assert_eq!(mailbox.receive(0, 0.0), None);
+//!
+//! // the sender writes self-test with sequence number 1
+//! let command = mailbox.receive((1 << 16) | 6, 0.0).unwrap();
+//! assert_eq!(command.request(), Some(Request::SelfTest));
+//! let response = command.respond(Completion::Done, 0.0);
+//! assert_eq!(response.encode(), (1 << 16, 0.0));
+//! // and it is only received once
+//! assert_eq!(mailbox.receive((1 << 16) | 6, 0.0), None);
+//! ```
+
+/// Bits of the sequence number in the first word of a command or response.
+const SEQUENCE_SHIFT: u32 = 16;
+/// Bits of the request or completion code in the first word of a command or response.
+const CODE_MASK: u32 = (1 << SEQUENCE_SHIFT) - 1;
+
+/// A command the APU can send to the RPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// clear the memory of the control law, and continue from the argument, or from the
+    /// current Z bias if NaN
+    ResetIntegrator,
+    /// hold the Z bias if the argument is not zero, release all commands on the Z bias if zero
+    Hold,
+    /// move the Z bias to the argument at the slew-rate limit, and keep it there until released
+    Retract,
+    /// start a raster scan of the X and Y scanner, over the area in the coefficient bank
+    StartScan,
+    /// stop the raster scan
+    StopScan,
+    /// check the RPU, the result is a bit mask of the checks failed
+    SelfTest,
+}
+impl Request {
+    /// Request from its code: 1 reset integrator, 2 hold, 3 retract, 4 start scan, 5 stop scan
+    /// or 6 self-test.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(Request::ResetIntegrator),
+            2 => Some(Request::Hold),
+            3 => Some(Request::Retract),
+            4 => Some(Request::StartScan),
+            5 => Some(Request::StopScan),
+            6 => Some(Request::SelfTest),
+            _ => None,
+        }
+    }
+
+    /// Numeric code of the request, see [`Request::from_code`].
+    pub fn code(self) -> u32 {
+        match self {
+            Request::ResetIntegrator => 1,
+            Request::Hold => 2,
+            Request::Retract => 3,
+            Request::StartScan => 4,
+            Request::StopScan => 5,
+            Request::SelfTest => 6,
+        }
+    }
+}
+
+/// Outcome of a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Completion {
+    /// done, with its result
+    Done,
+    /// accepted and in progress, answered again once done
+    Running,
+    /// request code not known to the firmware
+    Unknown,
+    /// not carried out, e.g. an invalid argument
+    Rejected,
+    /// carried out, but not successfully
+    Failed,
+}
+impl Completion {
+    /// Completion from its code, see [`Completion::code`].
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Completion::Done),
+            1 => Some(Completion::Running),
+            2 => Some(Completion::Unknown),
+            3 => Some(Completion::Rejected),
+            4 => Some(Completion::Failed),
+            _ => None,
+        }
+    }
+
+    /// Numeric code for reporting: 0 done, 1 running, 2 unknown, 3 rejected, 4 failed.
+    pub fn code(self) -> u32 {
+        match self {
+            Completion::Done => 0,
+            Completion::Running => 1,
+            Completion::Unknown => 2,
+            Completion::Rejected => 3,
+            Completion::Failed => 4,
+        }
+    }
+}
+
+/// A command received by the [`Mailbox`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Command {
+    sequence: u32,
+    code: u32,
+    argument: f32,
+}
+impl Command {
+    /// The command in the two words written by the sender.
+    pub fn decode(word: u32, argument: f32) -> Self {
+        Command {
+            sequence: word >> SEQUENCE_SHIFT,
+            code: word & CODE_MASK,
+            argument,
+        }
+    }
+
+    /// The two words of the command, as written by the sender.
+    pub fn encode(&self) -> (u32, f32) {
+        ((self.sequence << SEQUENCE_SHIFT) | self.code, self.argument)
+    }
+
+    /// Sequence number of the command, 16 bits.
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// The request, if known.
+    pub fn request(&self) -> Option<Request> {
+        Request::from_code(self.code)
+    }
+
+    /// The argument of the command.
+    pub fn argument(&self) -> f32 {
+        self.argument
+    }
+
+    /// Response to the command, with its sequence number.
+    pub fn respond(&self, completion: Completion, result: f32) -> Response {
+        Response {
+            sequence: self.sequence,
+            completion,
+            result,
+        }
+    }
+}
+
+/// Response to a [`Command`], with the same sequence number.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Response {
+    /// sequence number of the command answered
+    pub sequence: u32,
+    /// outcome of the command
+    pub completion: Completion,
+    /// result of the command, see [`Request`]
+    pub result: f32,
+}
+impl Response {
+    /// The response in the two words written by the receiver, if the completion code is known.
+    pub fn decode(word: u32, result: f32) -> Option<Self> {
+        Some(Response {
+            sequence: word >> SEQUENCE_SHIFT,
+            completion: Completion::from_code(word & CODE_MASK)?,
+            result,
+        })
+    }
+
+    /// The two words of the response.
+    pub fn encode(&self) -> (u32, f32) {
+        (
+            (self.sequence << SEQUENCE_SHIFT) | self.completion.code(),
+            self.result,
+        )
+    }
+}
+
+/// Receiving side of the mailbox: notices new commands by their sequence number.
+pub struct Mailbox {
+    last: u32,
+}
+impl Mailbox {
+    /// Create a new mailbox, ignoring the command in `word` left over from before.
+    pub fn new(word: u32) -> Self {
+        Mailbox {
+            last: word >> SEQUENCE_SHIFT,
+        }
+    }
+
+    /// The command in the two words written by the sender, if its sequence number is new.
+    pub fn receive(&mut self, word: u32, argument: f32) -> Option<Command> {
+        let command = Command::decode(word, argument);
+        if command.sequence == self.last {
+            return None;
+        }
+        self.last = command.sequence;
+        Some(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_round_trip() {
+        let command = Command::decode(0xbeef_0003, 0.25);
+        assert_eq!(command.sequence(), 0xbeef);
+        assert_eq!(command.request(), Some(Request::Retract));
+        assert_eq!(command.argument(), 0.25);
+        assert_eq!(command.encode(), (0xbeef_0003, 0.25));
+        for code in 1..=6 {
+            assert_eq!(Request::from_code(code).unwrap().code(), code);
+        }
+        assert_eq!(Command::decode(0x0001_0000, 0.0).request(), None);
+        assert_eq!(Command::decode(0x0001_0007, 0.0).request(), None);
+    }
+
+    #[test]
+    fn completion_codes() {
+        let codes = [
+            (Completion::Done, 0),
+            (Completion::Running, 1),
+            (Completion::Unknown, 2),
+            (Completion::Rejected, 3),
+            (Completion::Failed, 4),
+        ];
+        for (completion, code) in codes {
+            assert_eq!(completion.code(), code);
+            assert_eq!(Completion::from_code(code), Some(completion));
+        }
+        assert_eq!(Completion::from_code(5), None);
+    }
+
+    #[test]
+    fn answer_carries_sequence() {
+        let command = Command::decode(0x1234_0004, 0.0);
+        let (word, result) = command.respond(Completion::Running, 3.0).encode();
+        assert_eq!((word, result), (0x1234_0001, 3.0));
+        let response = Response::decode(word, result).unwrap();
+        assert_eq!(response.sequence, command.sequence());
+        assert_eq!(response.completion, Completion::Running);
+        assert_eq!(Response::decode(0x1234_0009, 0.0), None);
+    }
+
+    #[test]
+    fn new_sequence_numbers_only() {
+        // left over from a previous run
+        let mut mailbox = Mailbox::new(0x0007_0006);
+        assert_eq!(mailbox.receive(0x0007_0006, 0.0), None);
+        // the same request again, with the next sequence number
+        let command = mailbox.receive(0x0008_0006, 0.0).unwrap();
+        assert_eq!(command.sequence(), 8);
+        assert_eq!(mailbox.receive(0x0008_0006, 1.0), None);
+        // sequence numbers wrap around
+        let mut mailbox = Mailbox::new(0xffff_0001);
+        assert!(mailbox.receive(0x0000_0001, 0.0).is_some());
+    }
+}
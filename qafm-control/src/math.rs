@@ -0,0 +1,236 @@
+//! Elementary functions for `no_std`, where `core` doesn't provide them.
+
+use core::f32::consts::{FRAC_PI_2, LN_2, PI, SQRT_2, TAU};
+
+/// Absolute value.
+pub fn abs(x: f32) -> f32 {
+    f32::from_bits(x.to_bits() & 0x7fff_ffff)
+}
+
+/// Round to the nearest integer, halfway cases away from zero.
+///
+/// Only valid for `|x| < 2^31`.
+pub fn round(x: f32) -> f32 {
+    if x >= 0.0 {
+        (x + 0.5) as i32 as f32
+    } else {
+        (x - 0.5) as i32 as f32
+    }
+}
+
+/// Sine, with an absolute error below 1e-6 for `|x| <= 2 pi`.
+///
+/// For larger `|x|` the error grows with the rounding error of the range reduction.
+pub fn sin(x: f32) -> f32 {
+    // reduce to -pi..=pi, then to -pi/2..=pi/2 using sin(pi - x) = sin(x)
+    let mut x = x - TAU * round(x / TAU);
+    if x > FRAC_PI_2 {
+        x = PI - x;
+    } else if x < -FRAC_PI_2 {
+        x = -PI - x;
+    }
+    // Taylor series up to x^11, truncation error below 6e-8 at pi/2
+    let x2 = x * x;
+    let mut p = -1.0 / 39_916_800.0;
+    p = p * x2 + 1.0 / 362_880.0;
+    p = p * x2 - 1.0 / 5_040.0;
+    p = p * x2 + 1.0 / 120.0;
+    p = p * x2 - 1.0 / 6.0;
+    p = p * x2 + 1.0;
+    p * x
+}
+
+/// Cosine, with an absolute error below 1e-6 for `|x| <= 2 pi`, see [`sin`].
+pub fn cos(x: f32) -> f32 {
+    sin(x + FRAC_PI_2)
+}
+
+/// Square root, with a relative error below 1e-6. Negative inputs return NaN.
+///
+/// Takes a fixed number of operations: three divisions.
+pub fn sqrt(x: f32) -> f32 {
+    if x < 0.0 || x.is_nan() {
+        return f32::NAN;
+    }
+    if x == 0.0 || x.is_infinite() {
+        return x;
+    }
+    // initial guess by halving the exponent, then Newton-Raphson
+    let mut y = f32::from_bits((x.to_bits() >> 1) + 0x1fc0_0000);
+    y = 0.5 * (y + x / y);
+    y = 0.5 * (y + x / y);
+    y = 0.5 * (y + x / y);
+    y
+}
+
+/// Four-quadrant arctangent of `y / x` in `-pi..=pi`, with an absolute error below 2e-6.
+///
+/// Takes a fixed number of operations: one division and 7 multiplications. `atan2(0, 0)` is 0.
+pub fn atan2(y: f32, x: f32) -> f32 {
+    let (ax, ay) = (abs(x), abs(y));
+    let (num, den) = if ay > ax { (ax, ay) } else { (ay, ax) };
+    if den == 0.0 {
+        return 0.0;
+    }
+    // atan(t) for t in 0..=1, minimax polynomial
+    let t = num / den;
+    let t2 = t * t;
+    let mut p = -0.011_721_2;
+    p = p * t2 + 0.052_653_32;
+    p = p * t2 - 0.116_432_87;
+    p = p * t2 + 0.193_543_46;
+    p = p * t2 - 0.332_623_47;
+    p = p * t2 + 0.999_977_26;
+    let mut r = p * t;
+    if ay > ax {
+        r = FRAC_PI_2 - r;
+    }
+    if x < 0.0 {
+        r = PI - r;
+    }
+    if y < 0.0 {
+        -r
+    } else {
+        r
+    }
+}
+
+/// Wrap an angle to `-pi..=pi`.
+pub fn wrap_angle(x: f32) -> f32 {
+    x - TAU * round(x / TAU)
+}
+
+/// Natural logarithm, with a relative error below 1e-6 away from 1.0.
+///
+/// Zero returns negative infinity, negative inputs and NaN return NaN. Subnormal inputs are not
+/// supported.
+pub fn ln(x: f32) -> f32 {
+    if x < 0.0 || x.is_nan() {
+        return f32::NAN;
+    }
+    if x == 0.0 {
+        return f32::NEG_INFINITY;
+    }
+    if x.is_infinite() {
+        return x;
+    }
+    // x = m * 2^e with m in sqrt(0.5)..sqrt(2)
+    let bits = x.to_bits();
+    let mut e = ((bits >> 23) & 0xff) as i32 - 127;
+    let mut m = f32::from_bits((bits & 0x007f_ffff) | 0x3f80_0000);
+    if m > SQRT_2 {
+        m *= 0.5;
+        e += 1;
+    }
+    // ln(m) = 2 atanh(s), series up to s^9, truncation error below 4e-10
+    let s = (m - 1.0) / (m + 1.0);
+    let s2 = s * s;
+    let mut p = 1.0 / 9.0;
+    p = p * s2 + 1.0 / 7.0;
+    p = p * s2 + 1.0 / 5.0;
+    p = p * s2 + 1.0 / 3.0;
+    p = p * s2 + 1.0;
+    e as f32 * LN_2 + 2.0 * s * p
+}
+
+/// Exponential function, with a relative error below 1e-6.
+///
+/// Results too small for a normal `f32` are flushed to zero.
+pub fn exp(x: f32) -> f32 {
+    if x.is_nan() {
+        return x;
+    }
+    if x > 88.8 {
+        return f32::INFINITY;
+    }
+    if x < -87.3 {
+        return 0.0;
+    }
+    // exp(x) = 2^k * exp(r) with |r| <= ln(2) / 2, ln(2) split in two for an exact k * ln(2)
+    const LN_2_HI: f32 = 0.693_145_75;
+    const LN_2_LO: f32 = 1.428_606_8e-6;
+    let k = round(x / LN_2);
+    let r = (x - k * LN_2_HI) - k * LN_2_LO;
+    // Taylor series up to r^7, truncation error below 1e-8
+    let mut p = 1.0 / 5_040.0;
+    p = p * r + 1.0 / 720.0;
+    p = p * r + 1.0 / 120.0;
+    p = p * r + 1.0 / 24.0;
+    p = p * r + 1.0 / 6.0;
+    p = p * r + 0.5;
+    p = p * r + 1.0;
+    p = p * r + 1.0;
+    // 2^k, split in two factors since 2^128 is not finite
+    let k = k as i32;
+    let half = k / 2;
+    let scale = |e: i32| f32::from_bits(((e + 127) as u32) << 23);
+    p * scale(half) * scale(k - half)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sin_cos_accuracy() {
+        for n in -10_000..=10_000 {
+            let x = n as f32 * 0.01;
+            let tol = if abs(x) <= TAU { 1e-6 } else { 2e-5 };
+            assert!(abs(sin(x) - (x as f64).sin() as f32) < tol, "sin({})", x);
+            assert!(abs(cos(x) - (x as f64).cos() as f32) < tol, "cos({})", x);
+        }
+    }
+
+    #[test]
+    fn sqrt_accuracy() {
+        for n in 0..10_000 {
+            let x = 1e-6 * 1.0017_f32.powi(n);
+            let rel = (sqrt(x) as f64 - (x as f64).sqrt()) / (x as f64).sqrt();
+            assert!(rel.abs() < 1e-6, "sqrt({})", x);
+        }
+        assert_eq!(sqrt(0.0), 0.0);
+        assert!(sqrt(-1.0).is_nan());
+    }
+
+    #[test]
+    fn round_half_away() {
+        assert_eq!(round(2.5), 3.0);
+        assert_eq!(round(-2.5), -3.0);
+        assert_eq!(round(-0.4), 0.0);
+    }
+
+    #[test]
+    fn ln_exp_accuracy() {
+        for n in -5_000..5_000 {
+            let x = 1.0017_f32.powi(n * 10);
+            let rel = (ln(x) as f64 - (x as f64).ln()) / (x as f64).ln().abs().max(1.0);
+            assert!(rel.abs() < 1e-6, "ln({})", x);
+        }
+        for n in -8_000..8_000 {
+            let x = n as f32 * 0.01;
+            let rel = (exp(x) as f64 - (x as f64).exp()) / (x as f64).exp();
+            assert!(rel.abs() < 1e-6, "exp({})", x);
+        }
+        assert_eq!(ln(0.0), f32::NEG_INFINITY);
+        assert!(ln(-1.0).is_nan());
+        assert_eq!(exp(100.0), f32::INFINITY);
+        assert_eq!(exp(-100.0), 0.0);
+    }
+
+    #[test]
+    fn atan2_accuracy() {
+        for n in 0..3600 {
+            let angle = (n as f32).to_radians() - PI;
+            for r in [1e-3, 1.0, 1e3] {
+                let (y, x) = (
+                    r * (angle as f64).sin() as f32,
+                    r * (angle as f64).cos() as f32,
+                );
+                let err = wrap_angle(atan2(y, x) - (y as f64).atan2(x as f64) as f32);
+                assert!(abs(err) < 2e-6, "atan2({}, {})", y, x);
+            }
+        }
+        assert_eq!(atan2(0.0, 0.0), 0.0);
+        assert_eq!(atan2(0.0, -1.0), PI);
+    }
+}
@@ -0,0 +1,1036 @@
+use crate::coefficients::code;
+use crate::controller::{Controller, ControllerParams};
+use crate::limits::OutputLimits;
+use crate::validate::{at_least, finite, Rejection};
+
+/// Strategy used to prevent integral windup while the controller output is saturated.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AntiWindup {
+    /// Only clamp the integrator to its limits.
+    #[default]
+    Clamp,
+    /// Feed the difference between saturated and unsaturated output back into the integrator,
+    /// scaled by the tracking gain `kt`.
+    ///
+    /// A `kt` of 1.0 discharges the integrator in a single iteration, smaller values track the
+    /// saturation limit more slowly.
+    BackCalculation { kt: f32 },
+    /// Freeze the integrator while the output is saturated and the error would drive it further
+    /// into saturation.
+    ConditionalIntegration,
+}
+impl AntiWindup {
+    /// Strategy from its code: 0 clamp, 1 back-calculation with tracking gain `kt`, or 2
+    /// conditional integration.
+    pub fn from_code(code: u32, kt: f32) -> Option<Self> {
+        match code {
+            0 => Some(AntiWindup::Clamp),
+            1 => Some(AntiWindup::BackCalculation { kt }),
+            2 => Some(AntiWindup::ConditionalIntegration),
+            _ => None,
+        }
+    }
+}
+
+/// Signal the derivative term acts on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DerivativeMode {
+    /// Derivative of the measurement: no derivative kick on set point changes.
+    #[default]
+    OnMeasurement,
+    /// Derivative of the error: also reacts to set point changes, weighted by the derivative
+    /// set point weight (see [`PidBuilder::setpoint_weights`]).
+    OnError,
+}
+
+/// Default time constant of the derivative filter, in iterations.
+pub const DEFAULT_TAU: f32 = 3.5;
+
+#[derive(Default)]
+pub struct PidBuilder {
+    setpoint: f32,
+
+    // controller gains
+    kp: f32,
+    ki: f32,
+    kd: f32,
+
+    // output limits
+    lim_out: Option<(f32, f32)>,
+
+    // integrator limits
+    lim_int: Option<(f32, f32)>,
+
+    // maximum output change per iteration
+    slew: f32,
+
+    // anti-windup strategy
+    anti_windup: AntiWindup,
+
+    // derivative filter and mode
+    tau: Option<f32>,
+    derivative_mode: DerivativeMode,
+
+    // set point weights
+    weights: Option<(f32, f32)>,
+
+    // additive feedforward term
+    feedforward: f32,
+
+    // error deadband and integrator leak
+    deadband: f32,
+    leak: f32,
+}
+impl PidBuilder {
+    fn new() -> Self {
+        PidBuilder {
+            ..Default::default()
+        }
+    }
+    /// The controller set point
+    pub fn setpoint(mut self, sp: f32) -> Self {
+        self.setpoint = sp;
+        self
+    }
+    /// The proportional gain
+    pub fn gain_p(mut self, kp: f32) -> Self {
+        self.kp = kp;
+        self
+    }
+    /// The integral gain
+    pub fn gain_i(mut self, ki: f32) -> Self {
+        self.ki = ki;
+        self
+    }
+    /// The derivative gain
+    pub fn gain_d(mut self, kd: f32) -> Self {
+        self.kd = kd;
+        self
+    }
+    /// Limit controller output to prevent damage to DUT
+    pub fn limit_output(mut self, min: f32, max: f32) -> Self {
+        self.lim_out = Some((min, max));
+        self
+    }
+    /// Limit integrator value to prevent windup
+    pub fn limit_integrator(mut self, min: f32, max: f32) -> Self {
+        self.lim_int = Some((min, max));
+        self
+    }
+    /// Limit the change of the controller output to `max_step` per iteration.
+    ///
+    /// Within one iteration this narrows the output limits around the previous output, so the
+    /// anti-windup strategy also acts while the output is slew-rate limited. A `max_step` that
+    /// is not positive disables the limit, which is the default.
+    pub fn limit_slew_rate(mut self, max_step: f32) -> Self {
+        self.slew = max_step;
+        self
+    }
+    /// Strategy to prevent integral windup when the output saturates, see [`AntiWindup`].
+    ///
+    /// The integrator is always clamped to its limits as well.
+    pub fn anti_windup(mut self, anti_windup: AntiWindup) -> Self {
+        self.anti_windup = anti_windup;
+        self
+    }
+    /// Time constant of the low-pass filter on the derivative term, in iterations.
+    ///
+    /// A value of 0.5 disables the filter, smaller values are raised to 0.5. Defaults to 3.5.
+    pub fn derivative_filter(mut self, tau: f32) -> Self {
+        self.tau = Some(tau);
+        self
+    }
+    /// Signal the derivative term acts on, see [`DerivativeMode`]
+    pub fn derivative_mode(mut self, mode: DerivativeMode) -> Self {
+        self.derivative_mode = mode;
+        self
+    }
+    /// Set point weights `b` of the proportional and `c` of the derivative term.
+    ///
+    /// The proportional term acts on `b * setpoint - measurement` and, with
+    /// [`DerivativeMode::OnError`], the derivative term on `c * setpoint - measurement`. The
+    /// integral term always acts on the full error, so the set point is still reached. Weights
+    /// below 1.0 soften the reaction to set point changes without changing the rejection of
+    /// disturbances. Both default to 1.0.
+    pub fn setpoint_weights(mut self, b: f32, c: f32) -> Self {
+        self.weights = Some((b, c));
+        self
+    }
+    /// Term added to the controller output, see [`PidController::set_feedforward`].
+    pub fn feedforward(mut self, value: f32) -> Self {
+        self.feedforward = value;
+        self
+    }
+    /// Ignore errors smaller than `width` in the proportional and integral terms.
+    ///
+    /// Errors outside of the deadband are reduced by `width`, so the terms are continuous at
+    /// the edges of the deadband. Use to keep noise below the lockin noise floor away from the
+    /// output. A `width` that is not positive disables the deadband, which is the default.
+    pub fn deadband(mut self, width: f32) -> Self {
+        self.deadband = width;
+        self
+    }
+    /// Let the integrator decay toward zero by `factor` of its value every iteration.
+    ///
+    /// Use to stop the output from creeping with integrated noise, at the cost of a steady-state
+    /// error that grows with the factor. The factor is limited to `0.0..=1.0`, and defaults to
+    /// 0.0 for no leak.
+    pub fn integrator_leak(mut self, factor: f32) -> Self {
+        self.leak = factor;
+        self
+    }
+    /// Finalize the builder and return a ready-to-use PI controller.
+    ///
+    /// See [`PidController`] for examples.
+    pub fn build(self) -> PidController {
+        let mut limits = match self.lim_out {
+            Some((min, max)) => OutputLimits::new(min, max),
+            None => OutputLimits::unlimited(),
+        };
+        limits.set_slew_rate(self.slew);
+        let (lim_min_int, lim_max_int) = self.lim_int.unwrap_or(limits.range());
+        let (weight_p, weight_d) = self.weights.unwrap_or((1.0, 1.0));
+        PidController {
+            setpoint: self.setpoint,
+            kp: self.kp,
+            ki: self.ki,
+            kd: self.kd,
+            limits,
+            lim_min_int,
+            lim_max_int,
+            anti_windup: self.anti_windup,
+            tau: sanitize_tau(self.tau.unwrap_or(DEFAULT_TAU)),
+            derivative_mode: self.derivative_mode,
+            weight_p,
+            weight_d,
+            feedforward: self.feedforward,
+            deadband: self.deadband,
+            leak: sanitize_leak(self.leak),
+            integrator: 0.0,
+            differentiator: 0.0,
+            prev_measurement: 0.0,
+            prev_setpoint: self.setpoint,
+            output: limits.initial(),
+        }
+    }
+}
+
+/// A proportional-integral-derivative (PID) controller.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use qafm_control::pid::PidController;
+/// # fn make_a_new_measurement() -> f32 { 0.0 }
+/// # fn apply_new_output_value(_: f32) {}
+/// let mut pid_c = PidController::builder()
+///     .setpoint(0.0)
+///     .gain_p(5.0)
+///     .gain_i(3.0)
+///     .limit_output(-1.0, 1.0)
+///     .build();
+///
+/// loop {
+///     let meas = make_a_new_measurement();
+///     let out = pid_c.update(meas);
+///     apply_new_output_value(out);
+/// }
+/// ```
+pub struct PidController {
+    /// feedback set point
+    pub setpoint: f32,
+
+    /// proportional gain
+    ///
+    /// Prefer [`PidController::set_gains`] to change gains while the controller is running.
+    pub kp: f32,
+    /// integral gain
+    pub ki: f32,
+    /// derivative gain
+    pub kd: f32,
+
+    // output limits, including slew rate
+    limits: OutputLimits,
+
+    // integrator limits
+    lim_min_int: f32,
+    lim_max_int: f32,
+
+    // anti-windup strategy
+    anti_windup: AntiWindup,
+
+    // derivative filter and mode
+    tau: f32,
+    derivative_mode: DerivativeMode,
+
+    // set point weights
+    weight_p: f32,
+    weight_d: f32,
+
+    // additive feedforward term
+    feedforward: f32,
+
+    // error deadband and integrator leak
+    deadband: f32,
+    leak: f32,
+
+    // controller "memory"
+    integrator: f32,
+    differentiator: f32,
+    prev_measurement: f32,
+    prev_setpoint: f32,
+    output: f32,
+}
+impl PidController {
+    /// Number of options, see [`PidController::load_options`].
+    pub const NR_OPTIONS: usize = 4;
+
+    /// Start building a new PID controller.
+    ///
+    /// Call [`PidBuilder::build`] to get a ready-to-use controller.
+    /// See [`PidController`] for examples.
+    pub fn builder() -> PidBuilder {
+        PidBuilder::new()
+    }
+
+    /// Provide a new measurement and generate a new output value.
+    ///
+    /// See [`PidController`] for examples.
+    pub fn update(&mut self, measurement: f32) -> f32 {
+        let error = apply_deadband(self.setpoint - measurement, self.deadband);
+        let proportional = self.kp * self.proportional_error(self.setpoint, measurement);
+
+        // Note: derivative on measurement has a minus sign, since error = setpoint - measurement
+        let delta = match self.derivative_mode {
+            DerivativeMode::OnMeasurement => -(measurement - self.prev_measurement),
+            DerivativeMode::OnError => {
+                (self.weight_d * self.setpoint - measurement)
+                    - (self.weight_d * self.prev_setpoint - self.prev_measurement)
+            }
+        };
+        // band-limited differentiator, bilinear transform with unit sample time
+        self.differentiator = (2.0 * self.kd * delta
+            + (2.0 * self.tau - 1.0) * self.differentiator)
+            / (2.0 * self.tau + 1.0);
+
+        // output limits for this iteration, narrowed by the slew-rate limit
+        let (lim_min, lim_max) = self.limits.around(self.output);
+
+        self.integrator -= self.leak * self.integrator;
+        let increment = self.ki * error;
+        match self.anti_windup {
+            AntiWindup::Clamp => self.integrator += increment,
+            AntiWindup::BackCalculation { kt } => {
+                self.integrator += increment;
+                let unsaturated =
+                    proportional + self.integrator + self.differentiator + self.feedforward;
+                let saturated = unsaturated.clamp(lim_min, lim_max);
+                self.integrator += kt * (saturated - unsaturated);
+            }
+            AntiWindup::ConditionalIntegration => {
+                let unsaturated = proportional
+                    + self.integrator
+                    + increment
+                    + self.differentiator
+                    + self.feedforward;
+                let winding_up = (unsaturated > lim_max && increment > 0.0)
+                    || (unsaturated < lim_min && increment < 0.0);
+                if !winding_up {
+                    self.integrator += increment;
+                }
+            }
+        }
+        // clamp integrator to prevent integral windup
+        self.integrator = self.integrator.clamp(self.lim_min_int, self.lim_max_int);
+
+        let mut output = proportional + self.integrator + self.differentiator + self.feedforward;
+        // clamp output to prevent damage to DUT
+        output = output.clamp(lim_min, lim_max);
+
+        self.prev_measurement = measurement;
+        self.prev_setpoint = self.setpoint;
+        self.output = output;
+
+        // return controller output
+        output
+    }
+
+    /// Change the term added to the controller output.
+    ///
+    /// Use to apply a known disturbance or reference directly, e.g. the expected topography,
+    /// leaving only the residual to the feedback. The output follows a change of the
+    /// feedforward term immediately, within the output limits.
+    pub fn set_feedforward(&mut self, value: f32) {
+        self.feedforward = value;
+    }
+
+    /// Change the slew-rate limit of the output, see [`PidBuilder::limit_slew_rate`].
+    pub fn set_slew_rate_limit(&mut self, max_step: f32) {
+        self.limits.set_slew_rate(max_step);
+    }
+
+    /// The last output value of the controller.
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    /// Change the controller gains without a step in the output.
+    ///
+    /// The integrator accumulates the integral term already multiplied by `ki`, and the
+    /// differentiator the derivative term already multiplied by `kd`, so changing those two
+    /// gains is bumpless by construction. A change in `kp` is compensated by shifting the
+    /// integrator, such that the output stays the same for the last seen error.
+    pub fn set_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        if kp != self.kp {
+            let error = self.proportional_error(self.prev_setpoint, self.prev_measurement);
+            self.integrator += (self.kp - kp) * error;
+            self.integrator = self.integrator.clamp(self.lim_min_int, self.lim_max_int);
+        }
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
+    /// Change the gains, given in physical units, without a step in the output.
+    ///
+    /// The gains are those of the ideal PID form `kp * (e + 1/ti * ∫e dt + td * de/dt)`, with
+    /// the integral time `ti` and the derivative time `td` in seconds. They are converted to
+    /// per-iteration gains for the sample period `ts`, also in seconds, see
+    /// [`PidController::set_gains`]. A `ti` that is not positive disables the integral term.
+    /// Until the sample period is known, i.e. while `ts` is not positive, only the proportional
+    /// term acts.
+    pub fn set_gains_physical(&mut self, kp: f32, ti: f32, td: f32, ts: f32) {
+        let (kp, ki, kd) = discretize_gains(kp, ti, td, ts);
+        self.set_gains(kp, ki, kd);
+    }
+
+    /// Change the set point weights without a step in the output.
+    ///
+    /// See [`PidBuilder::setpoint_weights`]. Like for [`PidController::set_gains`], a change in
+    /// the proportional weight `b` is compensated by shifting the integrator.
+    pub fn set_setpoint_weights(&mut self, b: f32, c: f32) {
+        if b != self.weight_p {
+            self.shift_proportional(|pid| pid.weight_p = b);
+        }
+        self.weight_d = c;
+    }
+
+    /// Change the error deadband without a step in the output, see [`PidBuilder::deadband`].
+    pub fn set_deadband(&mut self, width: f32) {
+        if width != self.deadband {
+            self.shift_proportional(|pid| pid.deadband = width);
+        }
+    }
+
+    /// Change the anti-windup strategy, see [`PidBuilder::anti_windup`].
+    ///
+    /// Takes effect from the next iteration, the integrator is left as it is.
+    pub fn set_anti_windup(&mut self, anti_windup: AntiWindup) {
+        self.anti_windup = anti_windup;
+    }
+
+    /// Change the integrator leak, see [`PidBuilder::integrator_leak`].
+    pub fn set_integrator_leak(&mut self, factor: f32) {
+        self.leak = sanitize_leak(factor);
+    }
+
+    /// Change the options from the coefficients `[deadband, leak, anti-windup code, kt]`, see
+    /// [`AntiWindup::from_code`]. An invalid code clamps the integrator.
+    ///
+    /// Panics if there are fewer than [`PidController::NR_OPTIONS`].
+    pub fn load_options(&mut self, options: &[f32]) {
+        let anti_windup =
+            code(options[2]).and_then(|code| AntiWindup::from_code(code, options[3]));
+        self.set_deadband(options[0]);
+        self.set_integrator_leak(options[1]);
+        self.set_anti_windup(anti_windup.unwrap_or_default());
+    }
+
+    /// Error the proportional term acts on: weighted, and with the deadband applied.
+    fn proportional_error(&self, setpoint: f32, measurement: f32) -> f32 {
+        apply_deadband(self.weight_p * setpoint - measurement, self.deadband)
+    }
+
+    /// Apply a change to the proportional term, and compensate it by shifting the integrator
+    /// such that the output stays the same for the last seen error.
+    fn shift_proportional(&mut self, change: impl FnOnce(&mut Self)) {
+        let before = self.proportional_error(self.prev_setpoint, self.prev_measurement);
+        change(self);
+        let after = self.proportional_error(self.prev_setpoint, self.prev_measurement);
+        self.integrator += self.kp * (before - after);
+        self.integrator = self.integrator.clamp(self.lim_min_int, self.lim_max_int);
+    }
+
+    /// Change the time constant of the derivative filter, see [`PidBuilder::derivative_filter`].
+    pub fn set_derivative_filter(&mut self, tau: f32) {
+        self.tau = sanitize_tau(tau);
+    }
+
+    /// Change the signal the derivative term acts on, see [`DerivativeMode`].
+    pub fn set_derivative_mode(&mut self, mode: DerivativeMode) {
+        self.derivative_mode = mode;
+    }
+
+    /// Hold the output at its last value and re-seed the integrator from it.
+    ///
+    /// Call instead of [`PidController::update`] for as long as the output should be held, e.g.
+    /// while the set point and gains are changed. The integrator is re-seeded on every call so
+    /// that the output continues from the held value once `update` is called again.
+    pub fn hold(&mut self, measurement: f32) -> f32 {
+        let error = self.proportional_error(self.setpoint, measurement);
+        self.differentiator = 0.0;
+        self.integrator = self.output - self.kp * error - self.feedforward;
+        self.integrator = self.integrator.clamp(self.lim_min_int, self.lim_max_int);
+        self.prev_measurement = measurement;
+        self.prev_setpoint = self.setpoint;
+        self.output
+    }
+
+    /// Set the output to `output`, within the output range but ignoring the slew-rate limit.
+    ///
+    /// Call instead of [`PidController::update`] for as long as the output should follow
+    /// `output`, e.g. while the APU drives the actuator itself. The integrator is re-seeded on
+    /// every call as for [`PidController::hold`], so the controller continues bumplessly from
+    /// the last value. A value that is NaN or infinite holds the output instead.
+    pub fn track(&mut self, measurement: f32, output: f32) -> f32 {
+        if output.is_finite() {
+            let (lim_min, lim_max) = self.limits.range();
+            self.output = output.clamp(lim_min, lim_max);
+        }
+        self.hold(measurement)
+    }
+}
+
+/// Coefficients: `[kp, ki, kd]`, loaded with [`PidController::set_gains`].
+impl Controller for PidController {
+    fn update(&mut self, measurement: f32) -> f32 {
+        PidController::update(self, measurement)
+    }
+
+    fn reset(&mut self, output: f32) {
+        self.differentiator = 0.0;
+        self.integrator = (output - self.feedforward).clamp(self.lim_min_int, self.lim_max_int);
+        self.prev_setpoint = self.setpoint;
+        self.output = output;
+    }
+
+    fn load_params(&mut self, params: &ControllerParams<'_>) {
+        self.setpoint = params.setpoint;
+        self.set_slew_rate_limit(params.slew);
+        if let [kp, ki, kd, ..] = *params.coefficients {
+            self.set_gains(kp, ki, kd);
+        }
+    }
+
+    /// Gains `[kp, ki, kd]` must be finite and not negative.
+    fn check_coefficients(&self, coefficients: &[f32]) -> Result<(), Rejection> {
+        if let [kp, ki, kd, ..] = *coefficients {
+            finite(&[kp, ki, kd])?;
+            at_least(kp, 0.0)?;
+            at_least(ki, 0.0)?;
+            at_least(kd, 0.0)?;
+        }
+        Ok(())
+    }
+
+    fn output(&self) -> f32 {
+        self.output
+    }
+
+    fn setpoint(&self) -> f32 {
+        self.setpoint
+    }
+
+    fn limits(&self) -> &OutputLimits {
+        &self.limits
+    }
+
+    fn hold(&mut self, measurement: f32) -> f32 {
+        PidController::hold(self, measurement)
+    }
+
+    fn track(&mut self, measurement: f32, output: f32) -> f32 {
+        PidController::track(self, measurement, output)
+    }
+}
+
+/// Convert gains of the ideal PID form in physical units to per-iteration gains.
+///
+/// See [`PidController::set_gains_physical`].
+pub fn discretize_gains(kp: f32, ti: f32, td: f32, ts: f32) -> (f32, f32, f32) {
+    if ts > 0.0 {
+        let ki = if ti > 0.0 { kp * ts / ti } else { 0.0 };
+        let kd = kp * f32::max(td, 0.0) / ts;
+        (kp, ki, kd)
+    } else {
+        (kp, 0.0, 0.0)
+    }
+}
+
+/// Leak factor is a fraction of the integrator, also catches NaN.
+fn sanitize_leak(factor: f32) -> f32 {
+    if factor > 0.0 {
+        factor.min(1.0)
+    } else {
+        0.0
+    }
+}
+
+/// Shrink `error` by `width` towards zero, zero within the deadband.
+fn apply_deadband(error: f32, width: f32) -> f32 {
+    if width > 0.0 {
+        if error > width {
+            error - width
+        } else if error < -width {
+            error + width
+        } else {
+            0.0
+        }
+    } else {
+        error
+    }
+}
+
+/// Smallest meaningful filter time constant is 0.5 (no filtering), also catches NaN.
+fn sanitize_tau(tau: f32) -> f32 {
+    f32::max(tau, 0.5)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::Mode;
+
+    /// Controller with Z-like output limits and no derivative action.
+    fn pi(anti_windup: AntiWindup) -> PidController {
+        PidController::builder()
+            .setpoint(0.5)
+            .gain_p(1.0)
+            .gain_i(0.05)
+            .limit_output(0.0, 1.0)
+            .anti_windup(anti_windup)
+            .build()
+    }
+
+    /// Drive the controller into saturation with a large error, then flip the sign of the error
+    /// and count how many iterations the output stays above the set point.
+    fn iterations_to_recover(mut pid_c: PidController) -> usize {
+        for _ in 0..1000 {
+            pid_c.update(0.0);
+        }
+        (0..1000).take_while(|_| pid_c.update(0.6) >= 0.5).count()
+    }
+
+    #[test]
+    fn clamp_winds_up_to_limit() {
+        let mut pid_c = pi(AntiWindup::Clamp);
+        for _ in 0..1000 {
+            pid_c.update(0.0);
+        }
+        assert_eq!(pid_c.integrator, 1.0);
+        assert!(iterations_to_recover(pi(AntiWindup::Clamp)) > 50);
+    }
+
+    #[test]
+    fn back_calculation_tracks_limit() {
+        let mut pid_c = pi(AntiWindup::BackCalculation { kt: 1.0 });
+        for _ in 0..1000 {
+            pid_c.update(0.0);
+        }
+        // unsaturated output sits right at the limit
+        assert!((pid_c.integrator - 0.5).abs() < 1e-6);
+        assert_eq!(
+            iterations_to_recover(pi(AntiWindup::BackCalculation { kt: 1.0 })),
+            0
+        );
+    }
+
+    #[test]
+    fn conditional_integration_freezes_integrator() {
+        let mut pid_c = pi(AntiWindup::ConditionalIntegration);
+        for _ in 0..1000 {
+            pid_c.update(0.0);
+        }
+        assert!(pid_c.integrator <= 0.5);
+        assert_eq!(
+            iterations_to_recover(pi(AntiWindup::ConditionalIntegration)),
+            0
+        );
+    }
+
+    #[test]
+    fn anti_windup_from_code() {
+        assert_eq!(AntiWindup::from_code(0, 0.5), Some(AntiWindup::Clamp));
+        assert_eq!(
+            AntiWindup::from_code(1, 0.5),
+            Some(AntiWindup::BackCalculation { kt: 0.5 })
+        );
+        assert_eq!(
+            AntiWindup::from_code(2, 0.5),
+            Some(AntiWindup::ConditionalIntegration)
+        );
+        assert_eq!(AntiWindup::from_code(3, 0.5), None);
+    }
+
+    #[test]
+    fn options_from_coefficients() {
+        let mut pid_c = pi(AntiWindup::Clamp);
+        pid_c.load_options(&[0.01, 1e-3, 1.0, 0.25]);
+        assert_eq!((pid_c.deadband, pid_c.leak), (0.01, 1e-3));
+        assert_eq!(pid_c.anti_windup, AntiWindup::BackCalculation { kt: 0.25 });
+        for invalid in [3.0, -1.0, f32::NAN] {
+            pid_c.load_options(&[0.0, 0.0, invalid, 0.25]);
+            assert_eq!(pid_c.anti_windup, AntiWindup::Clamp);
+        }
+    }
+
+    #[test]
+    fn anti_windup_changes_at_runtime() {
+        let mut pid_c = pi(AntiWindup::Clamp);
+        for _ in 0..1000 {
+            pid_c.update(0.0);
+        }
+        assert_eq!(pid_c.integrator, 1.0);
+        pid_c.set_anti_windup(AntiWindup::BackCalculation { kt: 1.0 });
+        pid_c.update(0.0);
+        assert!((pid_c.integrator - 0.5).abs() < 0.1);
+    }
+
+    /// Closed loop against a slow first-order plant whose gain collapses for a while, as when
+    /// the tip is far away from the surface. Return the overshoot after the plant recovers.
+    fn overshoot(anti_windup: AntiWindup) -> f32 {
+        let mut pid_c = pi(anti_windup);
+        let mut y = 0.0;
+        let mut max_y: f32 = 0.0;
+        for n in 0..4000 {
+            let gain = if n < 1000 { 0.2 } else { 1.0 };
+            let u = pid_c.update(y);
+            y += 0.05 * (gain * u - y);
+            if n >= 1000 {
+                max_y = max_y.max(y);
+            }
+        }
+        assert!((y - 0.5).abs() < 1e-3, "did not settle: {}", y);
+        max_y - 0.5
+    }
+
+    #[test]
+    fn anti_windup_reduces_overshoot() {
+        let clamp = overshoot(AntiWindup::Clamp);
+        let back_calculation = overshoot(AntiWindup::BackCalculation { kt: 0.5 });
+        let conditional = overshoot(AntiWindup::ConditionalIntegration);
+        assert!(
+            back_calculation < 0.6 * clamp,
+            "{} vs {}",
+            back_calculation,
+            clamp
+        );
+        assert!(conditional < 0.6 * clamp, "{} vs {}", conditional, clamp);
+    }
+
+    fn pid() -> PidController {
+        PidController::builder()
+            .setpoint(0.5)
+            .gain_p(1.0)
+            .gain_i(0.001)
+            .gain_d(0.1)
+            .limit_output(0.0, 1.0)
+            .build()
+    }
+
+    #[test]
+    fn set_gains_is_bumpless() {
+        let mut pid_c = pid();
+        for _ in 0..200 {
+            pid_c.update(0.4);
+        }
+        let before = pid_c.update(0.4);
+        pid_c.set_gains(0.5, 0.001, 0.1);
+        let after = pid_c.update(0.4);
+        // only the integral increment of a single iteration separates the two outputs
+        assert!((after - before - 0.001 * 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn direct_gain_change_steps() {
+        let mut pid_c = pid();
+        for _ in 0..200 {
+            pid_c.update(0.4);
+        }
+        let before = pid_c.update(0.4);
+        pid_c.kp = 3.0;
+        let after = pid_c.update(0.4);
+        assert!(after - before > 0.1);
+    }
+
+    #[test]
+    fn hold_and_reseed() {
+        let mut pid_c = pid();
+        for _ in 0..200 {
+            pid_c.update(0.4);
+        }
+        let before = pid_c.output();
+        // change set point and gains while holding
+        pid_c.setpoint = 0.3;
+        assert_eq!(pid_c.hold(0.4), before);
+        pid_c.set_gains(2.0, 0.1, 0.0);
+        assert_eq!(pid_c.hold(0.4), before);
+        // first iteration after the handover moves by one integral increment only
+        let after = pid_c.update(0.4);
+        assert!((after - before + 0.1 * 0.1).abs() < 1e-6);
+    }
+
+    fn pd(mode: DerivativeMode, tau: f32) -> PidController {
+        PidController::builder()
+            .setpoint(0.5)
+            .gain_d(1.0)
+            .derivative_filter(tau)
+            .derivative_mode(mode)
+            .build()
+    }
+
+    #[test]
+    fn derivative_without_filter() {
+        let mut pid_c = pd(DerivativeMode::OnMeasurement, 0.5);
+        pid_c.update(0.1);
+        assert!((pid_c.update(0.3) + 0.2).abs() < 1e-6);
+        assert_eq!(pid_c.update(0.3), 0.0);
+    }
+
+    #[test]
+    fn derivative_filter_decays_monotonically() {
+        let mut pid_c = pd(DerivativeMode::OnMeasurement, 3.5);
+        pid_c.update(0.0);
+        let mut prev = pid_c.update(0.1);
+        assert!(prev < 0.0);
+        for _ in 0..20 {
+            let out = pid_c.update(0.1);
+            assert!(out <= 0.0 && out > prev);
+            prev = out;
+        }
+    }
+
+    #[test]
+    fn derivative_mode_setpoint_kick() {
+        let mut on_meas = pd(DerivativeMode::OnMeasurement, 0.5);
+        let mut on_err = pd(DerivativeMode::OnError, 0.5);
+        on_meas.update(0.2);
+        on_err.update(0.2);
+        on_meas.setpoint = 0.7;
+        on_err.setpoint = 0.7;
+        assert_eq!(on_meas.update(0.2), 0.0);
+        assert!((on_err.update(0.2) - 0.2).abs() < 1e-6);
+    }
+
+    fn weighted_pi(b: f32) -> PidController {
+        PidController::builder()
+            .setpoint(0.2)
+            .gain_p(1.0)
+            .gain_i(0.01)
+            .setpoint_weights(b, 0.0)
+            .limit_output(-1.0, 1.0)
+            .build()
+    }
+
+    #[test]
+    fn setpoint_weight_softens_kick() {
+        let mut full = weighted_pi(1.0);
+        let mut soft = weighted_pi(0.0);
+        let before_full = full.update(0.2);
+        let before_soft = soft.update(0.2);
+        full.setpoint = 0.5;
+        soft.setpoint = 0.5;
+        let step_full = full.update(0.2) - before_full;
+        let step_soft = soft.update(0.2) - before_soft;
+        assert!((step_full - 0.3 - 0.01 * 0.3).abs() < 1e-6);
+        assert!((step_soft - 0.01 * 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn setpoint_weight_keeps_disturbance_rejection() {
+        let mut full = weighted_pi(1.0);
+        let mut soft = weighted_pi(0.0);
+        let before_full = full.update(0.2);
+        let before_soft = soft.update(0.2);
+        // same reaction to a change in measurement
+        let step_full = full.update(0.1) - before_full;
+        let step_soft = soft.update(0.1) - before_soft;
+        assert!((step_full - step_soft).abs() < 1e-6);
+    }
+
+    #[test]
+    fn setpoint_weight_change_is_bumpless() {
+        let mut pid_c = weighted_pi(1.0);
+        pid_c.setpoint = 0.5;
+        pid_c.update(0.2);
+        let before = pid_c.update(0.2);
+        pid_c.set_setpoint_weights(0.5, 0.0);
+        let after = pid_c.update(0.2);
+        assert!((after - before - 0.01 * 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn slew_rate_limit() {
+        let mut pid_c = PidController::builder()
+            .setpoint(0.8)
+            .gain_p(1.0)
+            .gain_i(0.1)
+            .limit_output(0.0, 1.0)
+            .limit_slew_rate(0.01)
+            .anti_windup(AntiWindup::BackCalculation { kt: 1.0 })
+            .build();
+        let mut prev = pid_c.output();
+        for _ in 0..50 {
+            let out = pid_c.update(0.0);
+            assert!((out - prev - 0.01).abs() < 1e-6);
+            prev = out;
+        }
+        // integrator tracks the slewing output instead of winding up to the output limit
+        assert!(pid_c.integrator < prev);
+        assert!(pid_c.update(0.8) < prev);
+    }
+
+    #[test]
+    fn physical_gains() {
+        let (kp, ki, kd) = discretize_gains(2.0, 1e-3, 1e-5, 1e-4);
+        assert_eq!(kp, 2.0);
+        assert!((ki - 0.2).abs() < 1e-6);
+        assert!((kd - 0.2).abs() < 1e-6);
+        // twice the sample rate, same physical behavior
+        let (_, ki, kd) = discretize_gains(2.0, 1e-3, 1e-5, 0.5e-4);
+        assert!((ki - 0.1).abs() < 1e-6);
+        assert!((kd - 0.4).abs() < 1e-6);
+        // no integral action, unknown sample period
+        assert_eq!(discretize_gains(2.0, 0.0, 1e-5, 1e-4).1, 0.0);
+        assert_eq!(discretize_gains(2.0, 1e-3, 1e-5, 0.0), (2.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn manual_then_auto_is_bumpless() {
+        let mut pid_c = PidController::builder()
+            .setpoint(0.5)
+            .gain_p(0.5)
+            .gain_i(0.05)
+            .limit_output(0.0, 1.0)
+            .limit_slew_rate(0.1)
+            .build();
+        let ctrl: &mut dyn Controller = &mut pid_c;
+        // manual output is reached at the slew rate
+        assert!((ctrl.step(0.3, Mode::Manual(0.8)) - 0.1).abs() < 1e-6);
+        for _ in 0..10 {
+            ctrl.step(0.3, Mode::Manual(0.8));
+        }
+        assert_eq!(ctrl.output(), 0.8);
+        // invalid manual value holds the output
+        assert_eq!(ctrl.step(0.3, Mode::Manual(f32::NAN)), 0.8);
+        // first step in auto continues from the manual value
+        let out = ctrl.step(0.3, Mode::Auto);
+        assert!((out - 0.8 - 0.05 * 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn track_ignores_slew_rate() {
+        let mut pid_c = PidController::builder()
+            .gain_p(1.0)
+            .gain_i(0.1)
+            .limit_output(0.0, 1.0)
+            .limit_slew_rate(0.01)
+            .build();
+        assert_eq!(pid_c.track(0.0, 0.7), 0.7);
+        assert_eq!(pid_c.track(0.0, 1.5), 1.0);
+        // no integral action builds up while tracking
+        for _ in 0..100 {
+            pid_c.step(0.0, Mode::Track(0.4));
+        }
+        assert!((pid_c.update(0.0) - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn update_waits_for_applied_output() {
+        let mut pid_c = pi(AntiWindup::Clamp);
+        let first = pid_c.update_applied(0.4, 0.0);
+        assert!(first > 0.0);
+        // the output is not applied: no integral action builds up
+        for _ in 0..1000 {
+            assert_eq!(pid_c.update_applied(0.4, 0.0), first);
+        }
+        assert!(!pid_c.is_applied(0.0));
+        assert!(pid_c.is_applied(first));
+        let second = pid_c.update_applied(0.4, first);
+        assert!((second - first - 0.05 * 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn feedforward_adds_to_output() {
+        let mut pid_c = PidController::builder()
+            .setpoint(0.5)
+            .gain_p(0.2)
+            .gain_i(0.01)
+            .limit_output(0.0, 1.0)
+            .build();
+        pid_c.reset(0.4);
+        let before = pid_c.update(0.5);
+        pid_c.set_feedforward(0.1);
+        assert!((pid_c.update(0.5) - before - 0.1).abs() < 1e-6);
+        // bumpless hold and reset with feedforward
+        assert_eq!(pid_c.hold(0.3), pid_c.output());
+        assert!((pid_c.update(0.3) - 0.5 - 0.01 * 0.2).abs() < 1e-6);
+        pid_c.reset(0.7);
+        assert!((pid_c.update(0.5) - 0.7).abs() < 1e-6);
+    }
+
+    #[test]
+    fn deadband_ignores_small_errors() {
+        let mut pid_c = PidController::builder()
+            .setpoint(0.5)
+            .gain_p(1.0)
+            .gain_i(0.1)
+            .limit_output(0.0, 1.0)
+            .deadband(0.01)
+            .build();
+        pid_c.reset(0.3);
+        for _ in 0..100 {
+            assert_eq!(pid_c.update(0.505), 0.3);
+        }
+        // reduced by the width outside of the deadband
+        assert!((pid_c.update(0.52) - (0.3 - 1.1 * 0.01)).abs() < 1e-6);
+        // runtime change is bumpless
+        let out = pid_c.output();
+        pid_c.set_deadband(0.0);
+        pid_c.set_integrator_leak(0.0);
+        assert!((pid_c.update(0.52) - (out - 0.1 * 0.02)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn integrator_leaks_to_zero() {
+        let mut pid_c = PidController::builder()
+            .setpoint(0.5)
+            .gain_i(0.1)
+            .limit_output(0.0, 1.0)
+            .integrator_leak(0.01)
+            .build();
+        pid_c.reset(0.8);
+        for _ in 0..100 {
+            pid_c.update(0.5);
+        }
+        assert!((pid_c.output() - 0.8 * 0.99f32.powi(100)).abs() < 1e-4);
+        // out of range factor is limited
+        pid_c.set_integrator_leak(2.0);
+        assert_eq!(pid_c.update(0.5), 0.0);
+    }
+
+    #[test]
+    fn rejects_negative_gains() {
+        let pid_c = PidController::builder().build();
+        assert_eq!(pid_c.check_coefficients(&[1.0, 0.0, 0.5]), Ok(()));
+        assert_eq!(
+            pid_c.check_coefficients(&[1.0, -0.1, 0.5]),
+            Err(Rejection::OutOfRange)
+        );
+        assert_eq!(
+            pid_c.check_coefficients(&[1.0, 0.1, f32::NAN]),
+            Err(Rejection::NotFinite)
+        );
+        assert_eq!(pid_c.check_coefficients(&[-1.0]), Ok(()));
+    }
+}
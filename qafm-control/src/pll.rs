@@ -0,0 +1,225 @@
+//! Phase-locked loop keeping the drive of a resonator on resonance.
+
+use crate::controller::Controller;
+use crate::math::wrap_angle;
+use crate::pid::{AntiWindup, PidController};
+
+/// Phase-locked loop: a PI controller on the phase of the resonator response, adjusting the
+/// drive frequency.
+///
+/// The output is the frequency shift from the center frequency, limited to `-range..=range`.
+/// With positive gains a phase above the set point raises the frequency, as for a resonator
+/// whose phase lag grows with frequency, like a cantilever with the phase measured as
+/// `atan2(Q, I)`. Use negative gains for the opposite convention.
+///
+/// The phase error is wrapped to `-pi..=pi`, so the loop locks from any phase.
+///
+/// When someone else applies the drive frequency, e.g. with a round trip through another
+/// processor, use [`PhaseLockedLoop::update_applied`] instead of [`PhaseLockedLoop::update`]:
+/// the loop then waits for each frequency shift to be applied before the next step, so that it
+/// does not wind up while the drive frequency lags behind or is not applied at all.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use qafm_control::pll::PhaseLockedLoop;
+/// # use qafm_control::signal::phase;
+/// # fn measure_i_q() -> (f32, f32) { (0.0, 0.0) }
+/// # fn set_drive_frequency(_: f32) {}
+/// let mut pll = PhaseLockedLoop::new(300e3, 1e3);
+/// pll.set_setpoint(-core::f32::consts::FRAC_PI_2);
+/// pll.set_gains(20.0, 4.0);
+///
+/// loop {
+///     let (i, q) = measure_i_q();
+///     set_drive_frequency(pll.update(phase(i, q)));
+///     // pll.shift() is the frequency shift of the resonance
+/// }
+/// ```
+pub struct PhaseLockedLoop {
+    center: f32,
+    setpoint: f32,
+    // frequency shift from the phase error, with the error negated as measurement
+    pid: PidController,
+}
+impl PhaseLockedLoop {
+    /// Number of settings, see [`PhaseLockedLoop::from_settings`].
+    pub const NR_SETTINGS: usize = 5;
+
+    /// Create a new phase-locked loop from the settings `[center, setpoint, kp, ki, range]`,
+    /// see [`PhaseLockedLoop::new`] and [`PhaseLockedLoop::load_settings`].
+    ///
+    /// Panics if there are fewer than [`PhaseLockedLoop::NR_SETTINGS`].
+    pub fn from_settings(settings: &[f32]) -> Self {
+        let mut pll = Self::new(settings[0], settings[4]);
+        pll.load_settings(settings);
+        pll
+    }
+
+    /// Change center frequency, phase set point and gains from the settings, see
+    /// [`PhaseLockedLoop::from_settings`]. The range is only set when created.
+    pub fn load_settings(&mut self, settings: &[f32]) {
+        self.set_center(settings[0]);
+        self.set_setpoint(settings[1]);
+        self.set_gains(settings[2], settings[3]);
+    }
+
+    /// Create a new phase-locked loop at `center` frequency, with a frequency shift of at most
+    /// `range`.
+    ///
+    /// The gains are zero until set with [`PhaseLockedLoop::set_gains`].
+    pub fn new(center: f32, range: f32) -> Self {
+        let range = range.max(0.0);
+        PhaseLockedLoop {
+            center,
+            setpoint: 0.0,
+            pid: PidController::builder()
+                .limit_output(-range, range)
+                .anti_windup(AntiWindup::BackCalculation { kt: 0.5 })
+                .build(),
+        }
+    }
+
+    /// Change the phase set point, in radians.
+    pub fn set_setpoint(&mut self, phase: f32) {
+        self.setpoint = phase;
+    }
+
+    /// Change the center frequency, the frequency shift is kept.
+    pub fn set_center(&mut self, center: f32) {
+        self.center = center;
+    }
+
+    /// Change the proportional and integral gain, in frequency per radian, without a step in
+    /// the frequency. The integral gain is per iteration.
+    pub fn set_gains(&mut self, kp: f32, ki: f32) {
+        self.pid.set_gains(kp, ki, 0.0);
+    }
+
+    /// Provide the phase of the resonator response, in radians, and get the new drive
+    /// frequency.
+    pub fn update(&mut self, phase: f32) -> f32 {
+        self.pid.update(wrap_angle(self.setpoint - phase));
+        self.frequency()
+    }
+
+    /// Like [`PhaseLockedLoop::update`], but only if the current frequency shift has been
+    /// applied to the drive, as reported by `applied_shift`. Otherwise the loop holds, integral
+    /// term included, and the drive frequency stays the same.
+    ///
+    /// The gains then act per applied step rather than per iteration.
+    pub fn update_applied(&mut self, phase: f32, applied_shift: f32) -> f32 {
+        self.pid
+            .update_applied(wrap_angle(self.setpoint - phase), applied_shift);
+        self.frequency()
+    }
+
+    /// Whether `applied_shift` is the current frequency shift, see [`Controller::is_applied`].
+    pub fn is_applied(&self, applied_shift: f32) -> bool {
+        self.pid.is_applied(applied_shift)
+    }
+
+    /// Go back to the center frequency.
+    pub fn reset(&mut self) {
+        Controller::reset(&mut self.pid, 0.0);
+    }
+
+    /// The current drive frequency.
+    pub fn frequency(&self) -> f32 {
+        self.center + self.shift()
+    }
+
+    /// The current frequency shift from the center frequency.
+    pub fn shift(&self) -> f32 {
+        self.pid.output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::{abs, atan2};
+    use core::f32::consts::{FRAC_PI_2, PI};
+
+    /// Phase of a resonator at `f0` with quality factor `q`, driven at `f`.
+    fn resonator_phase(f: f32, f0: f32, q: f32) -> f32 {
+        -atan2(1.0, q * (f0 / f - f / f0))
+    }
+
+    #[test]
+    fn locks_on_resonance() {
+        let (f0, q) = (300_050.0, 200.0);
+        let mut pll = PhaseLockedLoop::new(300e3, 1e3);
+        pll.set_setpoint(-FRAC_PI_2);
+        pll.set_gains(20.0, 4.0);
+        let mut f = pll.frequency();
+        for _ in 0..2000 {
+            f = pll.update(resonator_phase(f, f0, q));
+        }
+        assert!(abs(f - f0) < 0.1);
+        assert!(abs(pll.shift() - 50.0) < 0.1);
+    }
+
+    #[test]
+    fn shift_is_limited_to_range() {
+        let mut pll = PhaseLockedLoop::new(300e3, 10.0);
+        pll.set_setpoint(-FRAC_PI_2);
+        pll.set_gains(20.0, 4.0);
+        for _ in 0..1000 {
+            pll.update(resonator_phase(pll.frequency(), 300_050.0, 200.0));
+        }
+        assert_eq!(pll.shift(), 10.0);
+        pll.reset();
+        assert_eq!(pll.frequency(), 300e3);
+    }
+
+    #[test]
+    fn holds_until_shift_is_applied() {
+        let (f0, q) = (300_050.0, 200.0);
+        let mut pll = PhaseLockedLoop::new(300e3, 1e3);
+        pll.set_setpoint(-FRAC_PI_2);
+        pll.set_gains(20.0, 4.0);
+
+        // nothing applies the drive frequency: one step, then no windup
+        let f = pll.frequency();
+        let first = pll.update_applied(resonator_phase(f, f0, q), 0.0);
+        assert!(first > f);
+        for _ in 0..1000 {
+            assert_eq!(pll.update_applied(resonator_phase(f, f0, q), 0.0), first);
+        }
+
+        // the drive follows every 10 iterations
+        let (mut f, mut applied) = (first, pll.shift());
+        for i in 0..20_000 {
+            pll.update_applied(resonator_phase(f, f0, q), applied);
+            if i % 10 == 0 {
+                (f, applied) = (pll.frequency(), pll.shift());
+            }
+        }
+        assert!(abs(f - f0) < 0.1);
+        assert!(pll.is_applied(applied));
+    }
+
+    #[test]
+    fn from_settings() {
+        let mut pll = PhaseLockedLoop::from_settings(&[300e3, -FRAC_PI_2, 20.0, 4.0, 10.0]);
+        assert_eq!((pll.frequency(), pll.setpoint), (300e3, -FRAC_PI_2));
+        for _ in 0..1000 {
+            pll.update(resonator_phase(pll.frequency(), 300_050.0, 200.0));
+        }
+        assert_eq!(pll.shift(), 10.0);
+        // the range stays, the shift too
+        pll.load_settings(&[301e3, 0.0, 0.0, 0.0, 1e3]);
+        assert_eq!(pll.frequency(), 301_010.0);
+    }
+
+    #[test]
+    fn phase_error_is_wrapped() {
+        let mut pll = PhaseLockedLoop::new(0.0, 1e3);
+        pll.set_setpoint(PI - 0.1);
+        pll.set_gains(1.0, 0.0);
+        // 0.2 rad above the set point, across the wrap-around
+        let f = pll.update(-PI + 0.1);
+        assert!(abs(f - 0.2) < 1e-5);
+    }
+}
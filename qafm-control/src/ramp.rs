@@ -0,0 +1,70 @@
+/// Limit the rate of change of a signal to a maximum step per iteration.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::ramp::RateLimiter;
+/// let mut sp = RateLimiter::new(0.0, 0.25);
+/// assert_eq!(sp.update(1.0), 0.25);
+/// assert_eq!(sp.update(1.0), 0.5);
+/// assert_eq!(sp.update(0.0), 0.25);
+/// ```
+pub struct RateLimiter {
+    max_step: f32,
+    value: f32,
+}
+impl RateLimiter {
+    /// Create a new rate limiter starting from `value`.
+    ///
+    /// A `max_step` that is not positive disables the limit.
+    pub fn new(value: f32, max_step: f32) -> Self {
+        RateLimiter { max_step, value }
+    }
+
+    /// Change the maximum step per iteration.
+    pub fn set_max_step(&mut self, max_step: f32) {
+        self.max_step = max_step;
+    }
+
+    /// The current value of the limited signal.
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /// Jump to `value` without limiting the rate.
+    pub fn reset(&mut self, value: f32) {
+        self.value = value;
+    }
+
+    /// Move towards `target` by at most the maximum step, and return the new value.
+    pub fn update(&mut self, target: f32) -> f32 {
+        if self.max_step > 0.0 {
+            let step = (target - self.value).clamp(-self.max_step, self.max_step);
+            self.value += step;
+        } else {
+            self.value = target;
+        }
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reaches_target() {
+        let mut ramp = RateLimiter::new(0.0, 0.1);
+        let n = (0..100).take_while(|_| ramp.update(0.55) < 0.55).count();
+        assert_eq!(n, 5);
+        assert_eq!(ramp.update(0.55), 0.55);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut ramp = RateLimiter::new(0.0, 0.0);
+        assert_eq!(ramp.update(0.7), 0.7);
+        ramp.set_max_step(f32::NAN);
+        assert_eq!(ramp.update(-0.7), -0.7);
+    }
+}
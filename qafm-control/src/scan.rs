@@ -0,0 +1,279 @@
+//! Raster scan of the X and Y scanner.
+
+use crate::coefficients::count;
+
+/// Area of a raster scan of the [`Raster`], in normalized scanner bias.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanArea {
+    /// X bias of the first pixel of each line
+    pub x0: f32,
+    /// Y bias of the first line
+    pub y0: f32,
+    /// X bias from the first to the last pixel of a line
+    pub width: f32,
+    /// Y bias from the first to the last line
+    pub height: f32,
+    /// number of pixels per line
+    pub pixels: u32,
+    /// number of lines
+    pub lines: u32,
+    /// number of iterations at each pixel
+    pub dwell: u32,
+}
+impl ScanArea {
+    /// Number of coefficients describing an area, see [`ScanArea::from_coefficients`].
+    pub const NR_COEFFICIENTS: usize = 7;
+
+    /// Area from the coefficients `[x0, y0, width, height, pixels, lines, dwell]`, with the
+    /// counts as whole numbers and 0 if less than 1.
+    ///
+    /// Panics if there are fewer than [`ScanArea::NR_COEFFICIENTS`].
+    pub fn from_coefficients(coefficients: &[f32]) -> Self {
+        let count = |value| count(value).unwrap_or(0);
+        ScanArea {
+            x0: coefficients[0],
+            y0: coefficients[1],
+            width: coefficients[2],
+            height: coefficients[3],
+            pixels: count(coefficients[4]),
+            lines: count(coefficients[5]),
+            dwell: count(coefficients[6]),
+        }
+    }
+
+    /// Whether the area can be scanned, i.e. has at least one pixel and stays within the
+    /// normalized bias range of 0.0 to 1.0.
+    pub fn is_valid(&self) -> bool {
+        let within = |start: f32, span: f32| {
+            (0.0..=1.0).contains(&start) && (0.0..=1.0).contains(&(start + span))
+        };
+        within(self.x0, self.width)
+            && within(self.y0, self.height)
+            && self.pixels > 0
+            && self.lines > 0
+            && self.dwell > 0
+    }
+}
+
+/// Raster scan moving the scanner over a [`ScanArea`], one pixel at a time.
+///
+/// The lines are scanned back and forth, so the scanner never jumps: even lines from `x0` to
+/// `x0 + width`, odd lines the other way round, each line `height / (lines - 1)` further in Y.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::scan::{Raster, ScanArea};
+/// let mut raster = Raster::new();
+/// raster.start(ScanArea {
+///     x0: 0.0,
+///     y0: 0.5,
+///     width: 1.0,
+///     height: 0.5,
+///     pixels: 3,
+///     lines: 2,
+///     dwell: 1,
+/// });
+/// let mut path = Vec::new();
+/// while let Some(position) = raster.update() {
+///     path.push(position);
+/// }
+/// assert_eq!(
+///     path,
+///     [(0.0, 0.5), (0.5, 0.5), (1.0, 0.5), (1.0, 1.0), (0.5, 1.0), (0.0, 1.0)]
+/// );
+/// ```
+pub struct Raster {
+    area: ScanArea,
+    running: bool,
+
+    // current pixel, and iterations spent at it
+    pixel: u32,
+    line: u32,
+    dwelled: u32,
+}
+impl Raster {
+    /// Create a new, idle raster scan.
+    pub fn new() -> Self {
+        Raster {
+            area: ScanArea {
+                x0: 0.0,
+                y0: 0.0,
+                width: 0.0,
+                height: 0.0,
+                pixels: 0,
+                lines: 0,
+                dwell: 0,
+            },
+            running: false,
+            pixel: 0,
+            line: 0,
+            dwelled: 0,
+        }
+    }
+
+    /// Start a new scan from the first pixel. An invalid area leaves the scan idle, see
+    /// [`ScanArea::is_valid`].
+    pub fn start(&mut self, area: ScanArea) {
+        self.area = area;
+        self.running = area.is_valid();
+        self.pixel = 0;
+        self.line = 0;
+        self.dwelled = 0;
+    }
+
+    /// Stop the scan where it is.
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    /// Whether a scan is in progress.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Number of lines scanned completely.
+    pub fn lines_done(&self) -> u32 {
+        self.line
+    }
+
+    /// Position of the scanner `(x, y)` for this iteration, or `None` once the scan is done.
+    pub fn update(&mut self) -> Option<(f32, f32)> {
+        if !self.running {
+            return None;
+        }
+        let area = &self.area;
+        let column = if self.line & 1 == 0 {
+            self.pixel
+        } else {
+            area.pixels - 1 - self.pixel
+        };
+        let x = area.x0 + area.width * fraction(column, area.pixels);
+        let y = area.y0 + area.height * fraction(self.line, area.lines);
+
+        // next iteration
+        self.dwelled += 1;
+        if self.dwelled >= area.dwell {
+            self.dwelled = 0;
+            self.pixel += 1;
+            if self.pixel >= area.pixels {
+                self.pixel = 0;
+                self.line += 1;
+                self.running = self.line < area.lines;
+            }
+        }
+        Some((x, y))
+    }
+}
+impl Default for Raster {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Position of step `index` out of `count` steps from 0.0 to 1.0.
+fn fraction(index: u32, count: u32) -> f32 {
+    if count > 1 {
+        index as f32 / (count - 1) as f32
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::abs;
+
+    fn area() -> ScanArea {
+        ScanArea {
+            x0: 0.2,
+            y0: 0.1,
+            width: 0.4,
+            height: 0.8,
+            pixels: 5,
+            lines: 3,
+            dwell: 2,
+        }
+    }
+
+    fn close(a: (f32, f32), b: (f32, f32)) -> bool {
+        abs(a.0 - b.0) < 1e-6 && abs(a.1 - b.1) < 1e-6
+    }
+
+    #[test]
+    fn scans_back_and_forth() {
+        let mut raster = Raster::new();
+        raster.start(area());
+        let mut path = [(0.0, 0.0); 30];
+        for position in path.iter_mut() {
+            *position = raster.update().unwrap();
+        }
+        assert!(!raster.is_running());
+        assert_eq!(raster.update(), None);
+        assert_eq!(raster.lines_done(), 3);
+
+        // each pixel for two iterations
+        assert_eq!(path[0], path[1]);
+        assert!(close(path[0], (0.2, 0.1)));
+        assert!(close(path[9], (0.6, 0.1)));
+        // next line starts where the last one ended
+        assert!(close(path[10], (0.6, 0.5)));
+        assert!(close(path[19], (0.2, 0.5)));
+        assert!(close(path[29], (0.6, 0.9)));
+        // no jumps larger than a pixel
+        for pair in path.windows(2) {
+            assert!(abs(pair[1].0 - pair[0].0) <= 0.1 + 1e-6);
+            assert!(abs(pair[1].1 - pair[0].1) <= 0.4 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn invalid_area_stays_idle() {
+        let mut raster = Raster::new();
+        assert_eq!(raster.update(), None);
+        for invalid in [
+            ScanArea {
+                pixels: 0,
+                ..area()
+            },
+            ScanArea { dwell: 0, ..area() },
+            ScanArea {
+                width: 0.9,
+                ..area()
+            },
+            ScanArea { y0: -0.1, ..area() },
+            ScanArea {
+                x0: f32::NAN,
+                ..area()
+            },
+        ] {
+            raster.start(invalid);
+            assert!(!raster.is_running());
+            assert_eq!(raster.update(), None);
+        }
+    }
+
+    #[test]
+    fn area_from_coefficients() {
+        let coefficients = [0.2, 0.1, 0.4, 0.8, 5.0, 3.0, 2.0];
+        assert_eq!(ScanArea::from_coefficients(&coefficients), area());
+        let area = ScanArea::from_coefficients(&[0.2, 0.1, 0.4, 0.8, 5.7, f32::NAN, -2.0]);
+        assert_eq!((area.pixels, area.lines, area.dwell), (5, 0, 0));
+        assert!(!area.is_valid());
+    }
+
+    #[test]
+    fn stop_and_restart() {
+        let mut raster = Raster::new();
+        raster.start(area());
+        for _ in 0..12 {
+            raster.update();
+        }
+        raster.stop();
+        assert_eq!(raster.update(), None);
+        assert_eq!(raster.lines_done(), 1);
+        raster.start(area());
+        assert!(close(raster.update().unwrap(), (0.2, 0.1)));
+    }
+}
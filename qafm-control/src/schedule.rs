@@ -0,0 +1,145 @@
+use crate::coefficients::count;
+
+/// A gain schedule: PID gains `[kp, ki, kd]` as a function of an operating point.
+///
+/// The table has up to `N` entries `[x, kp, ki, kd]` with increasing `x`, e.g. the set point or
+/// the Z bias. Between entries the gains are interpolated linearly, outside of the table the
+/// gains of the closest entry are used.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::schedule::GainSchedule;
+/// let mut schedule = GainSchedule::<4>::new();
+/// schedule.load(&[0.0, 1.0, 0.5, 0.0, 1.0, 3.0, 1.5, 0.0]);
+/// assert_eq!(schedule.lookup(0.5), Some([2.0, 1.0, 0.0]));
+/// assert_eq!(schedule.lookup(2.0), Some([3.0, 1.5, 0.0]));
+/// ```
+pub struct GainSchedule<const N: usize> {
+    entries: [[f32; 4]; N],
+    len: usize,
+}
+impl<const N: usize> GainSchedule<N> {
+    /// Number of values describing one entry of the table.
+    pub const NR_PARAMS: usize = 4;
+
+    /// Create a new, empty schedule.
+    pub fn new() -> Self {
+        GainSchedule {
+            entries: [[0.0; 4]; N],
+            len: 0,
+        }
+    }
+
+    /// Load the table from entries `[x, kp, ki, kd]` in turn.
+    ///
+    /// Loading stops after `N` entries, or at the first entry whose `x` is not larger than the
+    /// previous one, or is NaN. An empty table disables the schedule.
+    pub fn load(&mut self, table: &[f32]) {
+        self.len = 0;
+        for entry in table.chunks_exact(Self::NR_PARAMS).take(N) {
+            let x = entry[0];
+            let increasing = match self.len {
+                0 => !x.is_nan(),
+                len => x > self.entries[len - 1][0],
+            };
+            if !increasing {
+                break;
+            }
+            self.entries[self.len].copy_from_slice(entry);
+            self.len += 1;
+        }
+    }
+
+    /// Load the table from the number of entries followed by the entries, see
+    /// [`GainSchedule::load`]. An invalid number of entries disables the schedule.
+    pub fn load_counted(&mut self, coefficients: &[f32]) {
+        let (&len, table) = coefficients.split_first().unwrap_or((&0.0, &[]));
+        let len = (count(len).unwrap_or(0) as usize).min(N);
+        let len = (len * Self::NR_PARAMS).min(table.len());
+        self.load(&table[..len]);
+    }
+
+    /// Number of entries in the table.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gains `[kp, ki, kd]` at operating point `x`, `None` if the table is empty.
+    pub fn lookup(&self, x: f32) -> Option<[f32; 3]> {
+        let entries = &self.entries[..self.len];
+        let first = entries.first()?;
+        let last = entries[entries.len() - 1];
+        let gains = |e: &[f32; 4]| [e[1], e[2], e[3]];
+        if x.is_nan() || x <= first[0] {
+            return Some(gains(first));
+        }
+        if x >= last[0] {
+            return Some(gains(&last));
+        }
+        // x is strictly inside the table, so there is an upper entry that is not the first
+        let upper = entries
+            .iter()
+            .position(|e| e[0] >= x)
+            .unwrap_or(entries.len() - 1);
+        let (lo, hi) = (&entries[upper - 1], &entries[upper]);
+        let t = (x - lo[0]) / (hi[0] - lo[0]);
+        Some([1, 2, 3].map(|i| lo[i] + t * (hi[i] - lo[i])))
+    }
+}
+impl<const N: usize> Default for GainSchedule<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_and_clamps() {
+        let mut schedule = GainSchedule::<3>::new();
+        schedule.load(&[0.2, 1.0, 0.0, 0.0, 0.4, 2.0, 0.0, 0.0, 0.8, 6.0, 0.0, 0.0]);
+        assert_eq!(schedule.len(), 3);
+        assert_eq!(schedule.lookup(0.0).unwrap()[0], 1.0);
+        assert_eq!(schedule.lookup(0.4).unwrap()[0], 2.0);
+        assert!((schedule.lookup(0.6).unwrap()[0] - 4.0).abs() < 1e-6);
+        assert_eq!(schedule.lookup(1.0).unwrap()[0], 6.0);
+        assert_eq!(schedule.lookup(f32::NAN).unwrap()[0], 1.0);
+    }
+
+    #[test]
+    fn load_stops_at_unsorted_entry() {
+        let mut schedule = GainSchedule::<4>::new();
+        assert_eq!(schedule.lookup(0.5), None);
+        schedule.load(&[0.2, 1.0, 0.0, 0.0, 0.1, 2.0, 0.0, 0.0, 0.8, 6.0, 0.0, 0.0]);
+        assert_eq!(schedule.len(), 1);
+        assert_eq!(schedule.lookup(0.9), Some([1.0, 0.0, 0.0]));
+        schedule.load(&[]);
+        assert!(schedule.is_empty());
+    }
+
+    #[test]
+    fn load_counted_entries() {
+        let mut schedule = GainSchedule::<2>::new();
+        let table = [0.2, 1.0, 0.0, 0.0, 0.4, 2.0, 0.0, 0.0, 0.8, 6.0, 0.0, 0.0];
+        let mut coefficients = [0.0; 13];
+        coefficients[1..].copy_from_slice(&table);
+        for (len, expected) in [(1.0, 1), (3.0, 2), (0.0, 0), (f32::NAN, 0), (-1.0, 0)] {
+            coefficients[0] = len;
+            schedule.load_counted(&coefficients);
+            assert_eq!(schedule.len(), expected);
+        }
+        // fewer entries than counted
+        schedule.load_counted(&coefficients[..5]);
+        assert!(schedule.is_empty());
+        schedule.load_counted(&[]);
+        assert!(schedule.is_empty());
+    }
+}
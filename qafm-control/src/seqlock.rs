@@ -0,0 +1,160 @@
+//! Sequence lock over memory shared with another processor, e.g. the parameter map between the
+//! RPU and the APU.
+//!
+//! The writer makes a generation counter odd before writing a group of values, and even again
+//! after. The reader copies the group only while the generation is even, and keeps the copy only
+//! if the generation is the same before and after. Neither side ever waits for the other.
+
+use core::sync::atomic::{fence, Ordering};
+
+/// Width of a generation field packed into a larger word, see [`generation_field`].
+pub const GENERATION_BITS: u32 = 8;
+
+/// Generation in the [`GENERATION_BITS`] of `word` starting at bit `shift`.
+///
+/// The field wraps around from 255 to 0, which is still even, so a reader that compares the
+/// generation before and after copying sees the change as with any other write.
+pub const fn generation_field(word: u32, shift: u32) -> u32 {
+    (word >> shift) & ((1 << GENERATION_BITS) - 1)
+}
+
+/// Reader side: copy a group of values with `copy` if `generation` is even and the same before
+/// and after, otherwise return `None` and let the caller keep its previous copy.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::seqlock::read;
+/// use core::cell::Cell;
+///
+/// let generation = Cell::new(2);
+/// assert_eq!(read(|| generation.get(), || 42), Some(42));
+/// generation.set(3);
+/// assert_eq!(read(|| generation.get(), || 42), None);
+/// ```
+pub fn read<T>(generation: impl Fn() -> u32, copy: impl FnOnce() -> T) -> Option<T> {
+    let before = generation();
+    if before & 1 != 0 {
+        return None;
+    }
+    fence(Ordering::Acquire);
+    let value = copy();
+    fence(Ordering::Acquire);
+    if generation() != before {
+        return None;
+    }
+    Some(value)
+}
+
+/// Writer side: the sequence is odd while a group of values is being written, so the reader can
+/// check the sequence before and after the group, see [`read`].
+pub struct Sequence(u32);
+impl Sequence {
+    /// Start from sequence 0.
+    pub fn new() -> Self {
+        Sequence(0)
+    }
+
+    /// The last published sequence.
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+
+    /// Write a group of values with `write`, announcing the sequence with `publish` before and
+    /// after.
+    pub fn write(&mut self, publish: impl Fn(u32), write: impl FnOnce()) {
+        self.0 = self.0.wrapping_add(1);
+        publish(self.0);
+        fence(Ordering::Release);
+        write();
+        fence(Ordering::Release);
+        self.0 = self.0.wrapping_add(1);
+        publish(self.0);
+    }
+}
+impl Default for Sequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    #[test]
+    fn odd_generation_is_not_read() {
+        let generation = Cell::new(7);
+        let copied = Cell::new(false);
+        assert_eq!(read(|| generation.get(), || copied.set(true)), None);
+        assert!(!copied.get());
+        generation.set(8);
+        assert_eq!(read(|| generation.get(), || 1), Some(1));
+    }
+
+    #[test]
+    fn change_during_copy_is_discarded() {
+        let generation = Cell::new(4);
+        // the writer starts and finishes a group while the reader copies
+        let copy = || {
+            generation.set(generation.get() + 2);
+            1
+        };
+        assert_eq!(read(|| generation.get(), copy), None);
+        // the writer is still writing when the reader is done
+        let copy = || {
+            generation.set(generation.get() + 1);
+            1
+        };
+        assert_eq!(read(|| generation.get(), copy), None);
+    }
+
+    #[test]
+    fn generation_field_wraps_around() {
+        const SHIFT: u32 = 24;
+        // other bits of the word are not part of the generation
+        let flags = 0x00ab_cdef;
+        let word = Cell::new(flags | (254 << SHIFT));
+        let generation = || generation_field(word.get(), SHIFT);
+        assert_eq!(generation(), 254);
+
+        // the writer bumps the generation as the APU does, 8 bits wide
+        let bump = || {
+            let next = (generation() + 1) & 0xff;
+            word.set((word.get() & !(0xff << SHIFT)) | (next << SHIFT));
+        };
+        bump();
+        assert_eq!(generation(), 255);
+        assert_eq!(read(generation, || 1), None);
+        bump();
+        assert_eq!(generation(), 0);
+        assert_eq!(word.get() & !(0xff << SHIFT), flags);
+        assert_eq!(read(generation, || 1), Some(1));
+
+        // a group started at 254 and finished at 0 is detected during the copy
+        for _ in 0..254 {
+            bump();
+        }
+        assert_eq!(
+            read(generation, || {
+                bump();
+                bump();
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn sequence_is_odd_while_writing() {
+        let mut sequence = Sequence::new();
+        let published = Cell::new(0);
+        let during = Cell::new(0);
+        for _ in 0..3 {
+            sequence.write(|seq| published.set(seq), || during.set(published.get()));
+            assert_eq!(during.get() & 1, 1);
+            assert_eq!(published.get() & 1, 0);
+        }
+        assert_eq!(sequence.value(), 6);
+    }
+}
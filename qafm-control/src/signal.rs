@@ -0,0 +1,247 @@
+//! Signal paths around the control law: from lockin data to error signal, and from controller
+//! output to actuator.
+
+use crate::filter::Cascade;
+use crate::math::{atan2, sqrt, wrap_angle};
+use crate::ramp::RateLimiter;
+
+/// Squared amplitude `I^2 + Q^2` of a lockin measurement.
+pub fn amplitude_squared(i: f32, q: f32) -> f32 {
+    (i * i) + (q * q)
+}
+
+/// Amplitude `sqrt(I^2 + Q^2)` of a lockin measurement, see [`sqrt`].
+pub fn amplitude(i: f32, q: f32) -> f32 {
+    sqrt(amplitude_squared(i, q))
+}
+
+/// Phase `atan2(Q, I)` of a lockin measurement in radians, see [`atan2`].
+pub fn phase(i: f32, q: f32) -> f32 {
+    atan2(q, i)
+}
+
+/// Quantity of the lockin measurement used as error signal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorSignal {
+    /// squared amplitude, the cheapest to compute
+    #[default]
+    AmplitudeSquared,
+    /// amplitude, linear in the oscillation amplitude
+    Amplitude,
+    /// phase in radians, in `-pi..=pi` relative to the phase reference
+    Phase,
+    /// frequency shift of a phase-locked loop, see [`ErrorPath::set_frequency_shift`]
+    FrequencyShift,
+    /// combination of the tones of an intermodulation spectrum, see
+    /// [`ErrorPath::set_intermodulation`]
+    Intermodulation,
+}
+
+/// Error signal from lockin data, filtered by `N` biquad sections.
+///
+/// The error signal is computed from the scaled lockin data as selected by [`ErrorSignal`],
+/// by default the squared amplitude. The phase is relative to a phase reference and wrapped to
+/// `-pi..=pi`, so that the set point can be kept away from the wrap-around. The frequency
+/// shift is not computed from the lockin data, but provided by a
+/// [`PhaseLockedLoop`](crate::pll::PhaseLockedLoop) before every iteration, and likewise the
+/// combination of a [`Spectrum`](crate::frame::Spectrum) of many tones.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::signal::{ErrorPath, ErrorSignal};
+/// let mut error_path = ErrorPath::<2>::new(0.5);
+/// assert_eq!(error_path.process(2.0, 0.0), 1.0);
+/// error_path.set_signal(ErrorSignal::Phase);
+/// assert_eq!(error_path.process(0.0, -1.0), -core::f32::consts::FRAC_PI_2);
+/// ```
+pub struct ErrorPath<const N: usize> {
+    scale: f32,
+    signal: ErrorSignal,
+    phase_reference: f32,
+    frequency_shift: f32,
+    intermodulation: f32,
+    filter: Cascade<N>,
+}
+impl<const N: usize> ErrorPath<N> {
+    /// Create a new error path with lockin `scale` and the filter bypassed.
+    pub fn new(scale: f32) -> Self {
+        ErrorPath {
+            scale,
+            signal: ErrorSignal::default(),
+            phase_reference: 0.0,
+            frequency_shift: 0.0,
+            intermodulation: 0.0,
+            filter: Cascade::new(),
+        }
+    }
+
+    /// Change the scaling factor of the lockin data.
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+    }
+
+    /// Change the quantity used as error signal.
+    ///
+    /// The filter is reset on a change, since its state holds values of the previous quantity.
+    pub fn set_signal(&mut self, signal: ErrorSignal) {
+        if signal != self.signal {
+            self.signal = signal;
+            self.filter.reset();
+        }
+    }
+
+    /// The quantity used as error signal.
+    pub fn signal(&self) -> ErrorSignal {
+        self.signal
+    }
+
+    /// Change the phase reference in radians, subtracted from the phase of the lockin data.
+    pub fn set_phase_reference(&mut self, reference: f32) {
+        self.phase_reference = reference;
+    }
+
+    /// The filter on the error signal.
+    pub fn filter_mut(&mut self) -> &mut Cascade<N> {
+        &mut self.filter
+    }
+
+    /// Provide the frequency shift for the next call to [`ErrorPath::process`].
+    pub fn set_frequency_shift(&mut self, shift: f32) {
+        self.frequency_shift = shift;
+    }
+
+    /// Provide the combination of an intermodulation spectrum for the next call to
+    /// [`ErrorPath::process`].
+    pub fn set_intermodulation(&mut self, value: f32) {
+        self.intermodulation = value;
+    }
+
+    /// Compute the error signal from new lockin data.
+    pub fn process(&mut self, i: f32, q: f32) -> f32 {
+        let (i, q) = (i * self.scale, q * self.scale);
+        let error = match self.signal {
+            ErrorSignal::AmplitudeSquared => amplitude_squared(i, q),
+            ErrorSignal::Amplitude => amplitude(i, q),
+            ErrorSignal::Phase => wrap_angle(phase(i, q) - self.phase_reference),
+            ErrorSignal::FrequencyShift => self.frequency_shift,
+            ErrorSignal::Intermodulation => self.intermodulation,
+        };
+        self.filter.process(error)
+    }
+}
+
+/// Actuator signal from controller output: filtered by `N` biquad sections, clamped, then
+/// rate limited.
+///
+/// This is the last stage before the actuator, so the range and the slew-rate limit hold
+/// whatever drives the output: the control law in any [`Mode`](crate::controller::Mode), a
+/// perturbation added to it, or the filter ringing. A NaN output keeps the last value.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::signal::OutputPath;
+/// let mut output_path = OutputPath::<2>::new(0.0, 1.0);
+/// assert_eq!(output_path.process(1.5), 1.0);
+/// output_path.set_slew_rate(0.25);
+/// assert_eq!(output_path.process(0.0), 0.75);
+/// ```
+pub struct OutputPath<const N: usize> {
+    min: f32,
+    max: f32,
+    filter: Cascade<N>,
+    slew: RateLimiter,
+}
+impl<const N: usize> OutputPath<N> {
+    /// Create a new output path with output range `min..=max`, the filter bypassed and no
+    /// slew-rate limit.
+    ///
+    /// The output starts from 0.0 if within range, otherwise from the closest limit.
+    pub fn new(min: f32, max: f32) -> Self {
+        OutputPath {
+            min,
+            max,
+            filter: Cascade::new(),
+            slew: RateLimiter::new(f32::max(0.0, min).min(max), 0.0),
+        }
+    }
+
+    /// Change the maximum change of the output per iteration.
+    ///
+    /// A `max_step` that is not positive disables the slew-rate limit.
+    pub fn set_slew_rate(&mut self, max_step: f32) {
+        self.slew.set_max_step(max_step);
+    }
+
+    /// Continue from `value` on the next iteration, without limiting the rate.
+    pub fn reset(&mut self, value: f32) {
+        self.slew.reset(value.clamp(self.min, self.max));
+    }
+
+    /// The last actuator value.
+    pub fn value(&self) -> f32 {
+        self.slew.value()
+    }
+
+    /// The filter on the controller output.
+    pub fn filter_mut(&mut self) -> &mut Cascade<N> {
+        &mut self.filter
+    }
+
+    /// Compute the actuator value from a new controller output.
+    pub fn process(&mut self, output: f32) -> f32 {
+        let target = self.filter.process(output).clamp(self.min, self.max);
+        if !target.is_nan() {
+            self.slew.update(target);
+        }
+        self.slew.value().clamp(self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::PI;
+
+    #[test]
+    fn selectable_error_signal() {
+        let mut error_path = ErrorPath::<1>::new(2.0);
+        assert_eq!(error_path.process(1.5, 2.0), 25.0);
+        error_path.set_signal(ErrorSignal::Amplitude);
+        assert!((error_path.process(1.5, 2.0) - 5.0).abs() < 1e-5);
+        error_path.set_signal(ErrorSignal::Phase);
+        assert!((error_path.process(1.0, 1.0) - PI / 4.0).abs() < 1e-5);
+        error_path.set_signal(ErrorSignal::FrequencyShift);
+        error_path.set_frequency_shift(-5.0);
+        assert_eq!(error_path.process(1.0, 1.0), -5.0);
+    }
+
+    #[test]
+    fn output_is_clamped_then_rate_limited() {
+        let mut output_path = OutputPath::<1>::new(0.2, 0.8);
+        assert_eq!(output_path.value(), 0.2);
+        assert_eq!(output_path.process(1.0), 0.8);
+        output_path.set_slew_rate(0.25);
+        assert_eq!(output_path.process(-1.0), 0.55);
+        assert_eq!(output_path.process(-1.0), 0.3);
+        assert_eq!(output_path.process(-1.0), 0.2);
+        // a NaN output holds the actuator
+        assert_eq!(output_path.process(f32::NAN), 0.2);
+        assert_eq!(output_path.process(0.3), 0.3);
+        output_path.reset(1.0);
+        assert_eq!(output_path.value(), 0.8);
+    }
+
+    #[test]
+    fn phase_is_wrapped_around_reference() {
+        let mut error_path = ErrorPath::<1>::new(1.0);
+        error_path.set_signal(ErrorSignal::Phase);
+        error_path.set_phase_reference(PI);
+        // just below pi and just above -pi are both close to the reference
+        let below = error_path.process(-1.0, 0.01);
+        let above = error_path.process(-1.0, -0.01);
+        assert!((below + 0.01).abs() < 1e-4);
+        assert!((above - 0.01).abs() < 1e-4);
+    }
+}
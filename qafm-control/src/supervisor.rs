@@ -0,0 +1,317 @@
+//! Commands of the [`Mailbox`](crate::mailbox::Mailbox) carried out on a [`Controller`] and a
+//! raster scan.
+//!
+//! # Examples
+//!
+//! ```
+//! # use qafm_control::controller::{Controller, Mode};
+//! # use qafm_control::mailbox::{Command, Completion};
+//! # use qafm_control::pid::PidController;
+//! # use qafm_control::scan::ScanArea;
+//! # use qafm_control::supervisor::Supervisor;
+//! let mut pid = PidController::builder().limit_output(0.0, 1.0).build();
+//! let mut supervisor = Supervisor::new();
+//!
+//! // retract the Z bias to 0.0
+//! let command = Command::decode((1 << 16) | 3, 0.0);
+//! let area = || ScanArea::from_coefficients(&[0.0; 7]);
+//! let response = supervisor.execute(command, &mut pid, 0.5, area, || 0);
+//! assert_eq!(response.completion, Completion::Running);
+//! assert_eq!(supervisor.mode(Mode::Auto), Mode::Manual(0.0));
+//!
+//! // answered again once the Z bias is there
+//! assert_eq!(supervisor.poll(pid.limits().range(), 0.25), None);
+//! let response = supervisor.poll(pid.limits().range(), 0.0).unwrap();
+//! assert_eq!(response.completion, Completion::Done);
+//! ```
+
+use crate::controller::{Controller, Mode};
+use crate::mailbox::{Command, Completion, Request, Response};
+use crate::math::abs;
+use crate::scan::{Raster, ScanArea};
+
+/// Distance from the target at which a retract is done, in normalized bias.
+///
+/// The output may approach the target only asymptotically, e.g. through a filter.
+pub const RETRACT_TOLERANCE: f32 = 1e-5;
+
+/// State of the commands: overrides of the operating mode, the raster scan, and the last
+/// command still running, if any.
+pub struct Supervisor {
+    held: bool,
+    retract: Option<f32>,
+    raster: Raster,
+    running: Option<Command>,
+}
+impl Supervisor {
+    /// Create a new supervisor, without any overrides.
+    pub fn new() -> Self {
+        Supervisor {
+            held: false,
+            retract: None,
+            raster: Raster::new(),
+            running: None,
+        }
+    }
+
+    /// Whether a hold or retract overrides the operating mode, see [`Supervisor::mode`].
+    pub fn overrides(&self) -> bool {
+        self.held || self.retract.is_some()
+    }
+
+    /// Operating mode of the controller: `requested` unless held or retracting.
+    pub fn mode(&self, requested: Mode) -> Mode {
+        match self.retract {
+            Some(output) => Mode::Manual(output),
+            None if self.held => Mode::Hold,
+            None => requested,
+        }
+    }
+
+    /// Carry out `command` on `controller`, whose last output was `output`, and answer it. A
+    /// new command replaces the one still running.
+    ///
+    /// `area` is the area of a raster scan to start, and `self_test` the bit mask of the
+    /// checks failed, only called for these commands.
+    pub fn execute(
+        &mut self,
+        command: Command,
+        controller: &mut dyn Controller,
+        output: f32,
+        area: impl FnOnce() -> ScanArea,
+        self_test: impl FnOnce() -> u32,
+    ) -> Response {
+        self.running = None;
+        let (completion, result) = match command.request() {
+            Some(Request::ResetIntegrator) => {
+                let (low, high) = controller.limits().range();
+                let argument = command.argument();
+                let output = if argument.is_nan() {
+                    output
+                } else {
+                    argument.clamp(low, high)
+                };
+                controller.reset(output);
+                (Completion::Done, output)
+            }
+            Some(Request::Hold) => {
+                self.held = command.argument() != 0.0;
+                if !self.held {
+                    self.retract = None;
+                }
+                (Completion::Done, output)
+            }
+            Some(Request::Retract) => {
+                let target = command.argument();
+                if (0.0..=1.0).contains(&target) {
+                    self.retract = Some(target);
+                    self.running = Some(command);
+                    (Completion::Running, output)
+                } else {
+                    (Completion::Rejected, output)
+                }
+            }
+            Some(Request::StartScan) => {
+                self.raster.start(area());
+                if self.raster.is_running() {
+                    self.running = Some(command);
+                    (Completion::Running, 0.0)
+                } else {
+                    (Completion::Rejected, 0.0)
+                }
+            }
+            Some(Request::StopScan) => {
+                self.raster.stop();
+                (Completion::Done, self.raster.lines_done() as f32)
+            }
+            Some(Request::SelfTest) => {
+                let failed = self_test();
+                let completion = if failed == 0 {
+                    Completion::Done
+                } else {
+                    Completion::Failed
+                };
+                (completion, failed as f32)
+            }
+            None => (Completion::Unknown, f32::NAN),
+        };
+        command.respond(completion, result)
+    }
+
+    /// Answer to the command still running, once done: a retract once `bias` is within
+    /// [`RETRACT_TOLERANCE`] of the target within `(low, high)` limits, a raster scan once
+    /// all lines are scanned.
+    pub fn poll(&mut self, (low, high): (f32, f32), bias: f32) -> Option<Response> {
+        let command = self.running?;
+        let result = match command.request() {
+            Some(Request::Retract) => self
+                .retract
+                .filter(|target| abs(target.clamp(low, high) - bias) <= RETRACT_TOLERANCE)
+                .map(|_| bias),
+            Some(Request::StartScan) => {
+                (!self.raster.is_running()).then(|| self.raster.lines_done() as f32)
+            }
+            _ => None,
+        }?;
+        self.running = None;
+        Some(command.respond(Completion::Done, result))
+    }
+
+    /// Position of the scanner `(x, y)` for this iteration, `None` unless a raster scan is
+    /// running.
+    pub fn scan(&mut self) -> Option<(f32, f32)> {
+        self.raster.update()
+    }
+}
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pid::PidController;
+
+    fn pid() -> PidController {
+        PidController::builder().limit_output(0.2, 0.8).build()
+    }
+
+    fn command(sequence: u32, request: Request, argument: f32) -> Command {
+        Command::decode((sequence << 16) | request.code(), argument)
+    }
+
+    fn area(pixels: f32) -> ScanArea {
+        ScanArea::from_coefficients(&[0.0, 0.0, 1.0, 1.0, pixels, 1.0, 1.0])
+    }
+
+    fn execute(
+        supervisor: &mut Supervisor,
+        pid: &mut PidController,
+        command: Command,
+    ) -> Response {
+        supervisor.execute(command, pid, 0.5, || area(2.0), || 0)
+    }
+
+    #[test]
+    fn reset_within_limits() {
+        let (mut supervisor, mut pid) = (Supervisor::new(), pid());
+        let response = execute(
+            &mut supervisor,
+            &mut pid,
+            command(1, Request::ResetIntegrator, 0.9),
+        );
+        assert_eq!(
+            (response.completion, response.result),
+            (Completion::Done, 0.8)
+        );
+        assert_eq!(pid.output(), 0.8);
+        // NaN continues from the last output
+        let reset = command(2, Request::ResetIntegrator, f32::NAN);
+        assert_eq!(execute(&mut supervisor, &mut pid, reset).result, 0.5);
+        assert_eq!(pid.output(), 0.5);
+    }
+
+    #[test]
+    fn hold_and_release() {
+        let (mut supervisor, mut pid) = (Supervisor::new(), pid());
+        execute(&mut supervisor, &mut pid, command(1, Request::Hold, 1.0));
+        assert_eq!(supervisor.mode(Mode::Track(0.3)), Mode::Hold);
+        // a retract takes precedence, and is released with the hold
+        execute(&mut supervisor, &mut pid, command(2, Request::Retract, 0.1));
+        assert_eq!(supervisor.mode(Mode::Auto), Mode::Manual(0.1));
+        execute(&mut supervisor, &mut pid, command(3, Request::Hold, 0.0));
+        assert_eq!(supervisor.mode(Mode::Track(0.3)), Mode::Track(0.3));
+    }
+
+    #[test]
+    fn retract_done_near_limited_target() {
+        let (mut supervisor, mut pid) = (Supervisor::new(), pid());
+        let response = execute(&mut supervisor, &mut pid, command(4, Request::Retract, 1.5));
+        assert_eq!(response.completion, Completion::Rejected);
+        assert_eq!(supervisor.mode(Mode::Auto), Mode::Auto);
+
+        let response = execute(&mut supervisor, &mut pid, command(5, Request::Retract, 0.0));
+        assert_eq!(
+            (response.sequence, response.completion),
+            (5, Completion::Running)
+        );
+        let limits = pid.limits().range();
+        assert_eq!(supervisor.poll(limits, 0.3), None);
+        // the target is below the Z limits, and the output approaches the limit from above
+        let bias = 0.2 + 0.5 * RETRACT_TOLERANCE;
+        let response = supervisor.poll(limits, bias).unwrap();
+        assert_eq!(
+            (response.sequence, response.completion),
+            (5, Completion::Done)
+        );
+        assert_eq!(response.result, bias);
+        // answered once, and still retracted
+        assert_eq!(supervisor.poll(limits, bias), None);
+        assert_eq!(supervisor.mode(Mode::Auto), Mode::Manual(0.0));
+    }
+
+    #[test]
+    fn scan_until_done_or_replaced() {
+        let (mut supervisor, mut pid) = (Supervisor::new(), pid());
+        assert_eq!(supervisor.scan(), None);
+        let start = command(6, Request::StartScan, 0.0);
+        let response = supervisor.execute(start, &mut pid, 0.5, || area(0.0), || 0);
+        assert_eq!(response.completion, Completion::Rejected);
+
+        execute(&mut supervisor, &mut pid, start);
+        assert_eq!(supervisor.scan(), Some((0.0, 0.0)));
+        assert_eq!(supervisor.poll((0.2, 0.8), 0.5), None);
+        assert_eq!(supervisor.scan(), Some((1.0, 0.0)));
+        let response = supervisor.poll((0.2, 0.8), 0.5).unwrap();
+        assert_eq!(
+            (response.completion, response.result),
+            (Completion::Done, 1.0)
+        );
+
+        // a new command replaces the scan still running, which is not answered anymore
+        execute(&mut supervisor, &mut pid, start);
+        supervisor.scan();
+        let response = execute(
+            &mut supervisor,
+            &mut pid,
+            command(7, Request::SelfTest, 0.0),
+        );
+        assert_eq!(response.completion, Completion::Done);
+        supervisor.scan();
+        assert_eq!(supervisor.poll((0.2, 0.8), 0.5), None);
+    }
+
+    #[test]
+    fn stop_scan_and_other_answers() {
+        let (mut supervisor, mut pid) = (Supervisor::new(), pid());
+        execute(
+            &mut supervisor,
+            &mut pid,
+            command(1, Request::StartScan, 0.0),
+        );
+        supervisor.scan();
+        let response = execute(
+            &mut supervisor,
+            &mut pid,
+            command(2, Request::StopScan, 0.0),
+        );
+        assert_eq!(
+            (response.completion, response.result),
+            (Completion::Done, 0.0)
+        );
+        assert_eq!(supervisor.scan(), None);
+
+        let test = command(3, Request::SelfTest, 0.0);
+        let response = supervisor.execute(test, &mut pid, 0.5, || area(2.0), || 0b101);
+        assert_eq!(
+            (response.completion, response.result),
+            (Completion::Failed, 5.0)
+        );
+        let unknown = Command::decode((4 << 16) | 99, 0.0);
+        let response = execute(&mut supervisor, &mut pid, unknown);
+        assert_eq!(response.completion, Completion::Unknown);
+        assert!(response.result.is_nan());
+    }
+}
@@ -0,0 +1,78 @@
+/// Estimate the period between iterations from a free-running cycle counter.
+///
+/// The counter is allowed to wrap around. The estimate is averaged over many iterations, so
+/// that jitter and the occasional missed iteration have little effect.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_control::timing::PeriodEstimator;
+/// let mut period = PeriodEstimator::new(500e6);
+/// assert_eq!(period.seconds(), 0.0);
+/// period.update(u32::MAX - 9_999);
+/// period.update(20_000);
+/// assert!((period.seconds() - 60e-6).abs() < 1e-9);
+/// ```
+pub struct PeriodEstimator {
+    clock_hz: f32,
+    last: Option<u32>,
+    cycles: f32,
+}
+impl PeriodEstimator {
+    /// Weight of a new period measurement in the running average.
+    const ALPHA: f32 = 1.0 / 64.0;
+
+    /// Create a new estimator for a cycle counter running at `clock_hz`.
+    pub fn new(clock_hz: f32) -> Self {
+        PeriodEstimator {
+            clock_hz,
+            last: None,
+            cycles: 0.0,
+        }
+    }
+
+    /// Provide the value of the cycle counter at the start of a new iteration.
+    pub fn update(&mut self, counter: u32) {
+        if let Some(last) = self.last {
+            let delta = counter.wrapping_sub(last) as f32;
+            if self.cycles > 0.0 {
+                self.cycles += Self::ALPHA * (delta - self.cycles);
+            } else {
+                self.cycles = delta;
+            }
+        }
+        self.last = Some(counter);
+    }
+
+    /// The estimated period in seconds, or 0.0 until two iterations have been seen.
+    pub fn seconds(&self) -> f32 {
+        self.cycles / self.clock_hz
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_jitter() {
+        let mut period = PeriodEstimator::new(1e6);
+        let mut counter = 0u32;
+        for n in 0..10_000 {
+            period.update(counter);
+            counter = counter.wrapping_add(if n % 2 == 0 { 90 } else { 110 });
+        }
+        assert!((period.seconds() - 100e-6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn follows_rate_change() {
+        let mut period = PeriodEstimator::new(1e6);
+        let mut counter = 0u32;
+        for n in 0..2_000 {
+            period.update(counter);
+            counter = counter.wrapping_add(if n < 1_000 { 100 } else { 50 });
+        }
+        assert!((period.seconds() - 50e-6).abs() < 1e-7);
+    }
+}
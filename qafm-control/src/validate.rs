@@ -0,0 +1,128 @@
+//! Rules for values written by another processor, e.g. parameters and coefficients from the
+//! APU, which must be checked before they reach an actuator.
+//!
+//! Each rule returns why a value is rejected, so that the rules of a parameter can be chained
+//! with `?`:
+//!
+//! ```
+//! # use qafm_control::validate::{finite, increasing, normalized, Rejection};
+//! fn z_limits(low: f32, high: f32) -> Result<(), Rejection> {
+//!     finite(&[low, high])?;
+//!     normalized(low)?;
+//!     normalized(high)?;
+//!     increasing(low, high)
+//! }
+//! assert_eq!(z_limits(0.1, 0.9), Ok(()));
+//! assert_eq!(z_limits(0.9, 0.1), Err(Rejection::NotIncreasing));
+//! assert_eq!(z_limits(f32::NAN, 0.9), Err(Rejection::NotFinite));
+//! ```
+
+/// Why a value was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// NaN or infinity
+    NotFinite,
+    /// outside the allowed range, e.g. a negative gain
+    OutOfRange,
+    /// low limit not below the high limit
+    NotIncreasing,
+}
+impl Rejection {
+    /// Numeric code for reporting: 1 not finite, 2 out of range, 3 low limit not below the high
+    /// limit.
+    pub fn code(self) -> u32 {
+        match self {
+            Rejection::NotFinite => 1,
+            Rejection::OutOfRange => 2,
+            Rejection::NotIncreasing => 3,
+        }
+    }
+}
+
+/// All values are neither NaN nor infinite.
+pub fn finite(values: &[f32]) -> Result<(), Rejection> {
+    if values.iter().all(|value| value.is_finite()) {
+        Ok(())
+    } else {
+        Err(Rejection::NotFinite)
+    }
+}
+
+/// The value is `min` or more.
+pub fn at_least(value: f32, min: f32) -> Result<(), Rejection> {
+    if value >= min {
+        Ok(())
+    } else {
+        Err(Rejection::OutOfRange)
+    }
+}
+
+/// The value is more than zero.
+pub fn positive(value: f32) -> Result<(), Rejection> {
+    if value > 0.0 {
+        Ok(())
+    } else {
+        Err(Rejection::OutOfRange)
+    }
+}
+
+/// The value is a normalized DC bias, between 0 and 1.
+pub fn normalized(value: f32) -> Result<(), Rejection> {
+    if (0.0..=1.0).contains(&value) {
+        Ok(())
+    } else {
+        Err(Rejection::OutOfRange)
+    }
+}
+
+/// The low limit is below the high limit.
+pub fn increasing(low: f32, high: f32) -> Result<(), Rejection> {
+    if low < high {
+        Ok(())
+    } else {
+        Err(Rejection::NotIncreasing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_finite() {
+        assert_eq!(finite(&[0.0, -1e30, 1e30]), Ok(()));
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert_eq!(finite(&[0.0, value]), Err(Rejection::NotFinite));
+        }
+        assert_eq!(Rejection::NotFinite.code(), 1);
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(at_least(0.0, 0.0), Ok(()));
+        assert_eq!(at_least(-1e-9, 0.0), Err(Rejection::OutOfRange));
+        assert_eq!(positive(1e-9), Ok(()));
+        assert_eq!(positive(0.0), Err(Rejection::OutOfRange));
+        assert_eq!(normalized(0.0), Ok(()));
+        assert_eq!(normalized(1.0), Ok(()));
+        assert_eq!(normalized(1.01), Err(Rejection::OutOfRange));
+        assert_eq!(normalized(-0.01), Err(Rejection::OutOfRange));
+        assert_eq!(Rejection::OutOfRange.code(), 2);
+    }
+
+    #[test]
+    fn nan_is_never_in_range() {
+        assert!(at_least(f32::NAN, 0.0).is_err());
+        assert!(positive(f32::NAN).is_err());
+        assert!(normalized(f32::NAN).is_err());
+        assert!(increasing(f32::NAN, 1.0).is_err());
+    }
+
+    #[test]
+    fn not_increasing() {
+        assert_eq!(increasing(0.2, 0.8), Ok(()));
+        assert_eq!(increasing(0.8, 0.2), Err(Rejection::NotIncreasing));
+        assert_eq!(increasing(0.5, 0.5), Err(Rejection::NotIncreasing));
+        assert_eq!(Rejection::NotIncreasing.code(), 3);
+    }
+}
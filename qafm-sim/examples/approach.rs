@@ -0,0 +1,39 @@
+//! Approach the surface from far away and scan over a step, writing the time series as CSV.
+//!
+//! ```text
+//! cargo run -p qafm-sim --target x86_64-unknown-linux-gnu --release --example approach -- \
+//!     [KP] [KI] [KD] > approach.csv
+//! ```
+
+use std::io;
+
+use qafm_control::pid::{AntiWindup, PidController};
+use qafm_sim::{write_csv, PlantConfig, Simulation};
+
+fn main() -> io::Result<()> {
+    let gains: Vec<f32> = std::env::args()
+        .skip(1)
+        .map(|arg| arg.parse().expect("gains must be numbers"))
+        .collect();
+    let gain = |n: usize, default: f32| gains.get(n).copied().unwrap_or(default);
+
+    // 70 % of the free amplitude
+    let pid_c = PidController::builder()
+        .setpoint(0.49)
+        .gain_p(gain(0, 0.002))
+        .gain_i(gain(1, 0.002))
+        .gain_d(gain(2, 0.0))
+        .limit_output(0.0, 1.0)
+        .anti_windup(AntiWindup::BackCalculation { kt: 0.5 })
+        .build();
+    let mut sim = Simulation::new(PlantConfig::default(), 4, 16, Box::new(pid_c));
+    sim.controller.reset(1.0);
+    sim.plant.set_height(45e-9);
+
+    let mut samples = sim.run(3000);
+    // 2 nm step in the topography
+    sim.plant.set_height(47e-9);
+    samples.extend(sim.run(2000));
+
+    write_csv(&samples, io::stdout().lock())
+}
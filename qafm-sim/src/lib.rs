@@ -0,0 +1,28 @@
+//! Closed-loop simulation of the QAFM feedback on the host.
+//!
+//! A dynamic AFM is simulated in the time domain: a driven cantilever with tip-sample
+//! interaction on a Z piezo, measured by a lockin with a sliding window of `nsw` pixels. The
+//! Z feedback runs the same code as the firmware once per lockin pixel, the
+//! [`ZFeedback`](qafm_control::feedback::ZFeedback) of `qafm-control`, so gains and filters can
+//! be tried out here before programming them through `lockin_feedback.py`.
+//!
+//! Left out is what `src/user.rs` does around it: the hardware access, and reading the
+//! parameter map and the coefficient bank. Parameters are set on the [`Simulation`] and its
+//! controller directly, e.g. the feedforward of a `PidController`, and are not validated as in
+//! the firmware. The gain schedule, the gains in physical units, the intermodulation error
+//! signal, the de-rotation of the intermediate frequency and the X and Y scanner are not
+//! simulated.
+//!
+//! Since the default target is the RPU, the host target must be given explicitly:
+//! ```text
+//! cargo test -p qafm-sim --target x86_64-unknown-linux-gnu
+//! cargo run -p qafm-sim --target x86_64-unknown-linux-gnu --release --example approach
+//! ```
+
+mod lockin;
+mod plant;
+mod sim;
+
+pub use lockin::Lockin;
+pub use plant::{Plant, PlantConfig};
+pub use sim::{write_csv, Sample, Simulation, FILTER_SECTIONS};
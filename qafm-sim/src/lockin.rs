@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+
+use crate::plant::Plant;
+
+/// Lockin amplifier demodulating the cantilever deflection at the drive frequency.
+///
+/// Each pixel spans an integer number of oscillation periods, as with a perfectly tuned lockin.
+/// Like the Presto lockin used by the firmware with `nsum`, the output is the sliding sum of
+/// the last `nsw` pixels; the firmware divides by `nsw` through its scaling factor.
+///
+/// The phase convention is that of the deflection `A cos(w t + phi)` giving `I = A cos(phi)`
+/// and `Q = A sin(phi)`.
+pub struct Lockin {
+    periods_per_pixel: u32,
+    nsw: usize,
+    window: VecDeque<(f64, f64)>,
+    noise: Noise,
+}
+impl Lockin {
+    /// Create a new lockin, summing `nsw` pixels of `periods_per_pixel` oscillation periods.
+    ///
+    /// Gaussian noise with standard deviation `noise_rms` in meters is added to the I and Q
+    /// of each pixel.
+    pub fn new(periods_per_pixel: u32, nsw: usize, noise_rms: f64) -> Self {
+        Lockin {
+            periods_per_pixel,
+            nsw: nsw.max(1),
+            window: VecDeque::with_capacity(nsw.max(1)),
+            noise: Noise::new(noise_rms),
+        }
+    }
+
+    /// Number of pixels in the sliding sum.
+    pub fn nsw(&self) -> usize {
+        self.nsw
+    }
+
+    /// Duration of one pixel in seconds, for a plant with resonance frequency `f0`.
+    pub fn pixel_time(&self, f0: f64) -> f64 {
+        self.periods_per_pixel as f64 / f0
+    }
+
+    /// Run the plant for one pixel with the Z piezo at `bias`, then return the sliding sums of
+    /// I and Q.
+    pub fn pixel(&mut self, plant: &mut Plant, bias: f32) -> (f32, f32) {
+        let steps = self.periods_per_pixel * plant.config().steps_per_period;
+        let (mut sum_i, mut sum_q) = (0.0, 0.0);
+        for _ in 0..steps {
+            plant.step(bias);
+            let phase = plant.drive_phase(plant.time());
+            sum_i += plant.deflection() * phase.cos();
+            sum_q -= plant.deflection() * phase.sin();
+        }
+        let scale = 2.0 / steps as f64;
+        let i = scale * sum_i + self.noise.sample();
+        let q = scale * sum_q + self.noise.sample();
+
+        if self.window.len() == self.nsw {
+            self.window.pop_front();
+        }
+        self.window.push_back((i, q));
+        let (i, q) = self
+            .window
+            .iter()
+            .fold((0.0, 0.0), |(si, sq), (i, q)| (si + i, sq + q));
+        (i as f32, q as f32)
+    }
+}
+
+/// Deterministic Gaussian noise, so simulations are reproducible.
+struct Noise {
+    rms: f64,
+    state: u64,
+}
+impl Noise {
+    fn new(rms: f64) -> Self {
+        Noise {
+            rms,
+            state: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Uniform in `(0, 1]`, xorshift64.
+    fn uniform(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        ((self.state >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Box-Muller transform, one of the two samples is thrown away for simplicity.
+    fn sample(&mut self) -> f64 {
+        if self.rms == 0.0 {
+            return 0.0;
+        }
+        let (u1, u2) = (self.uniform(), self.uniform());
+        self.rms * (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+    }
+}
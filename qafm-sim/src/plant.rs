@@ -0,0 +1,250 @@
+use std::f64::consts::TAU;
+
+/// Parameters of the simulated microscope.
+///
+/// Lengths are in meters, frequencies in Hz. Forces are given divided by the cantilever spring
+/// constant, i.e. as the static deflection they would cause.
+#[derive(Clone, Debug)]
+pub struct PlantConfig {
+    /// resonance frequency of the cantilever, also the initial drive frequency
+    pub f0: f64,
+    /// quality factor of the cantilever
+    pub q: f64,
+    /// oscillation amplitude far away from the surface
+    pub free_amplitude: f64,
+    /// integration steps per oscillation period
+    pub steps_per_period: u32,
+
+    /// stiffness of the tip-sample contact, relative to the cantilever
+    pub contact_stiffness: f64,
+    /// attractive van der Waals force `-hamaker / (gap + a0)^2`, scaled as the deflection
+    pub hamaker: f64,
+    /// intermolecular distance where the attractive force saturates
+    pub a0: f64,
+
+    /// Z piezo extension for a normalized Z bias of 1.0, the extension is 0.0 at a bias of 0.0
+    pub z_range: f64,
+    /// resonance frequency of the Z piezo
+    pub piezo_f0: f64,
+    /// quality factor of the Z piezo
+    pub piezo_q: f64,
+}
+impl Default for PlantConfig {
+    fn default() -> Self {
+        PlantConfig {
+            f0: 300e3,
+            q: 200.0,
+            free_amplitude: 10e-9,
+            steps_per_period: 32,
+            contact_stiffness: 10.0,
+            hamaker: 1e-30,
+            a0: 0.2e-9,
+            z_range: 100e-9,
+            piezo_f0: 20e3,
+            piezo_q: 5.0,
+        }
+    }
+}
+
+/// Cantilever oscillator with tip-sample interaction, on a Z piezo.
+///
+/// The cantilever base is at height `z` above the sample surface at height `h`, where `z`
+/// follows the Z bias through the second-order dynamics of the piezo. The tip is at
+/// `z + x - h` above the surface, with `x` the deflection of the cantilever. The cantilever is
+/// driven at its resonance frequency unless changed with [`Plant::set_drive_frequency`], with a
+/// force giving `free_amplitude` on resonance far from the surface, times the relative drive
+/// amplitude set with [`Plant::set_drive_amplitude`].
+pub struct Plant {
+    config: PlantConfig,
+    dt: f64,
+    t: f64,
+
+    // cantilever deflection and velocity
+    x: f64,
+    v: f64,
+
+    // piezo extension and velocity
+    z: f64,
+    z_v: f64,
+
+    height: f64,
+
+    // drive amplitude relative to the one giving the free amplitude
+    a_drive: f64,
+
+    // drive frequency, and drive phase at time t_drive
+    f_drive: f64,
+    phase_drive: f64,
+    t_drive: f64,
+}
+impl Plant {
+    /// Create a new plant at rest, with the piezo extended to `bias`.
+    pub fn new(config: PlantConfig, bias: f32) -> Self {
+        let dt = 1.0 / (config.f0 * config.steps_per_period as f64);
+        let z = config.z_range * bias as f64;
+        Plant {
+            a_drive: 1.0,
+            f_drive: config.f0,
+            config,
+            dt,
+            t: 0.0,
+            x: 0.0,
+            v: 0.0,
+            z,
+            z_v: 0.0,
+            height: 0.0,
+            phase_drive: 0.0,
+            t_drive: 0.0,
+        }
+    }
+
+    /// The parameters of the plant.
+    pub fn config(&self) -> &PlantConfig {
+        &self.config
+    }
+
+    /// Current time in seconds.
+    pub fn time(&self) -> f64 {
+        self.t
+    }
+
+    /// Change the height of the sample surface.
+    pub fn set_height(&mut self, height: f64) {
+        self.height = height;
+    }
+
+    /// Height of the sample surface.
+    pub fn height(&self) -> f64 {
+        self.height
+    }
+
+    /// Extension of the Z piezo.
+    pub fn z(&self) -> f64 {
+        self.z
+    }
+
+    /// Deflection of the cantilever.
+    pub fn deflection(&self) -> f64 {
+        self.x
+    }
+
+    /// Change the drive frequency, the drive phase stays continuous.
+    pub fn set_drive_frequency(&mut self, f: f64) {
+        self.phase_drive = self.drive_phase(self.t);
+        self.t_drive = self.t;
+        self.f_drive = f;
+    }
+
+    /// Change the drive amplitude, relative to the one giving the free amplitude.
+    pub fn set_drive_amplitude(&mut self, amplitude: f64) {
+        self.a_drive = amplitude;
+    }
+
+    /// Current drive amplitude, relative to the one giving the free amplitude.
+    pub fn drive_amplitude(&self) -> f64 {
+        self.a_drive
+    }
+
+    /// Current drive frequency.
+    pub fn drive_frequency(&self) -> f64 {
+        self.f_drive
+    }
+
+    /// Duration of one integration step in seconds.
+    pub fn dt(&self) -> f64 {
+        self.dt
+    }
+
+    /// Advance by one integration step, with the Z piezo driven by `bias`.
+    pub fn step(&mut self, bias: f32) {
+        let c = &self.config;
+
+        // piezo, semi-implicit Euler is plenty for its slow dynamics
+        let wp = TAU * c.piezo_f0;
+        let z_target = c.z_range * bias as f64;
+        let z_a = wp * wp * (z_target - self.z) - wp / c.piezo_q * self.z_v;
+        self.z_v += z_a * self.dt;
+        self.z += self.z_v * self.dt;
+
+        // cantilever, 4th order Runge-Kutta
+        let (x, v, t, dt) = (self.x, self.v, self.t, self.dt);
+        let (k1x, k1v) = (v, self.acceleration(t, x, v));
+        let (k2x, k2v) = {
+            let (x, v) = (x + 0.5 * dt * k1x, v + 0.5 * dt * k1v);
+            (v, self.acceleration(t + 0.5 * dt, x, v))
+        };
+        let (k3x, k3v) = {
+            let (x, v) = (x + 0.5 * dt * k2x, v + 0.5 * dt * k2v);
+            (v, self.acceleration(t + 0.5 * dt, x, v))
+        };
+        let (k4x, k4v) = {
+            let (x, v) = (x + dt * k3x, v + dt * k3v);
+            (v, self.acceleration(t + dt, x, v))
+        };
+        self.x += dt / 6.0 * (k1x + 2.0 * k2x + 2.0 * k3x + k4x);
+        self.v += dt / 6.0 * (k1v + 2.0 * k2v + 2.0 * k3v + k4v);
+        self.t += dt;
+    }
+
+    /// Phase of the drive at time `t`, the drive is `cos(phase)`.
+    ///
+    /// Only valid from the last change of the drive frequency on.
+    pub fn drive_phase(&self, t: f64) -> f64 {
+        self.phase_drive + TAU * self.f_drive * (t - self.t_drive)
+    }
+
+    fn acceleration(&self, t: f64, x: f64, v: f64) -> f64 {
+        let c = &self.config;
+        let w0 = TAU * c.f0;
+        // on resonance, the drive amplitude times Q gives the free amplitude
+        let drive = self.a_drive * c.free_amplitude / c.q * self.drive_phase(t).cos();
+        let gap = self.z + x - self.height;
+        w0 * w0 * (drive + self.interaction(gap) - x) - w0 / c.q * v
+    }
+
+    /// Tip-sample force for a tip `gap` above the surface: van der Waals attraction, plus
+    /// linear repulsion in contact.
+    fn interaction(&self, gap: f64) -> f64 {
+        let c = &self.config;
+        if gap > 0.0 {
+            -c.hamaker / ((gap + c.a0) * (gap + c.a0))
+        } else {
+            -c.hamaker / (c.a0 * c.a0) - c.contact_stiffness * gap
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Amplitude of the last full oscillation period.
+    fn amplitude(plant: &mut Plant, bias: f32, periods: u32) -> f64 {
+        let steps = plant.config().steps_per_period;
+        let mut peak: f64 = 0.0;
+        for n in 0..periods * steps {
+            plant.step(bias);
+            if n >= (periods - 1) * steps {
+                peak = peak.max(plant.deflection().abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn free_oscillation_reaches_free_amplitude() {
+        let mut plant = Plant::new(PlantConfig::default(), 1.0);
+        plant.set_height(-1e-6);
+        let a = amplitude(&mut plant, 1.0, 2000);
+        assert!((a / 10e-9 - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn surface_limits_amplitude() {
+        let mut plant = Plant::new(PlantConfig::default(), 0.5);
+        // base 5 nm above the surface
+        plant.set_height(45e-9);
+        let a = amplitude(&mut plant, 0.5, 2000);
+        assert!(a > 4e-9 && a < 7e-9);
+    }
+}
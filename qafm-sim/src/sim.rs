@@ -0,0 +1,254 @@
+use std::io::{self, Write};
+
+use qafm_control::autotune::RelayAutotune;
+use qafm_control::average::SlidingAverage;
+use qafm_control::controller::{Controller, ControllerParams, Mode};
+use qafm_control::feedback::ZFeedback;
+use qafm_control::mailbox::{Command, Response};
+use qafm_control::pid::PidController;
+use qafm_control::pll::PhaseLockedLoop;
+use qafm_control::scan::ScanArea;
+use qafm_control::signal::{amplitude, phase};
+
+use crate::lockin::Lockin;
+use crate::plant::{Plant, PlantConfig};
+
+/// Number of biquad sections of the error and output filters, as in the firmware.
+pub const FILTER_SECTIONS: usize = 4;
+
+/// Maximum window length of the sliding average, as in the firmware.
+pub const AVERAGE_PIXELS: usize = 1024;
+
+/// One iteration of the closed loop, as seen by the firmware and by the plant.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    /// time at the end of the pixel, in seconds
+    pub time: f64,
+    /// lockin I, sliding sum of the lockin or sliding average of the firmware
+    pub i: f32,
+    /// lockin Q, sliding sum of the lockin or sliding average of the firmware
+    pub q: f32,
+    /// error signal, as reported by the firmware in param slot 1
+    pub amp2: f32,
+    /// Z bias, as reported by the firmware in param slot 1
+    pub bias: f32,
+    /// frequency shift of the phase-locked loop, 0.0 without one
+    pub shift: f32,
+    /// relative drive amplitude set by the amplitude control, 1.0 without one
+    pub drive: f32,
+    /// extension of the Z piezo, in meters
+    pub z: f64,
+    /// height of the sample surface, in meters
+    pub height: f64,
+}
+
+/// Closed-loop simulation of the Z feedback: one iteration per lockin pixel, as on the RPU.
+///
+/// Each iteration follows the firmware: the lockin data is averaged by the [`SlidingAverage`],
+/// off unless configured, and the [`ZFeedback`] of the firmware turns it into the Z bias that
+/// drives the plant during the next pixel: error signal, control law in the current [`Mode`]
+/// or relay autotuning, perturbation of the network analyzer, and output path with the
+/// slew-rate limit of the controller. The set point of the controller is ramped to `setpoint`
+/// between iterations, and commands are carried out with [`Simulation::execute`]. The
+/// [`PhaseLockedLoop`], if any, computes the drive frequency from the phase of the lockin data
+/// before the error signal is computed, and the amplitude control, if any, the drive amplitude
+/// that keeps the oscillation amplitude constant.
+///
+/// As on the hardware, the drive is not retuned by the firmware itself: every `apu_period`
+/// iterations the APU applies the published frequency shift and drive amplitude to the plant
+/// and reports them back, and the PLL and the amplitude control hold until then.
+///
+/// # Examples
+///
+/// ```
+/// # use qafm_sim::{PlantConfig, Simulation};
+/// # use qafm_control::pid::PidController;
+/// let pid_c = PidController::builder()
+///     .setpoint(0.5)
+///     .gain_i(0.001)
+///     .limit_output(0.0, 1.0)
+///     .build();
+/// let mut sim = Simulation::new(PlantConfig::default(), 4, 16, Box::new(pid_c));
+/// let samples = sim.run(100);
+/// assert_eq!(samples.len(), 100);
+/// ```
+pub struct Simulation {
+    /// the simulated microscope
+    pub plant: Plant,
+    /// lockin measuring the plant
+    pub lockin: Lockin,
+    /// sliding average of the lockin data, off unless configured
+    pub average: SlidingAverage<AVERAGE_PIXELS>,
+    /// from lockin data to Z bias, as in the firmware
+    pub feedback: ZFeedback<FILTER_SECTIONS>,
+    /// the control law
+    pub controller: Box<dyn Controller>,
+    /// operating mode requested of the controller
+    pub mode: Mode,
+    /// relay autotuning settings while autotuning, see [`RelayAutotune::load_settings`]
+    pub autotune: Option<[f32; RelayAutotune::NR_SETTINGS]>,
+    /// set point the controller is ramped to, the one of the controller to start with
+    pub setpoint: f32,
+    /// maximum change of the set point per iteration, not positive for no limit
+    pub setpoint_rate: f32,
+    /// last response to a command, see [`Simulation::execute`]
+    pub response: Option<Response>,
+    /// phase-locked loop on the drive frequency, the drive stays at resonance without one
+    pub pll: Option<PhaseLockedLoop>,
+    /// amplitude control on the drive amplitude, from the scaled lockin amplitude to the
+    /// relative drive amplitude; the drive amplitude stays at 1.0 without one
+    pub agc: Option<PidController>,
+    /// iterations between two updates of the drive by the APU, 0 if the APU does not follow
+    pub apu_period: usize,
+    apu_wait: usize,
+    applied_shift: f32,
+    applied_drive: f32,
+    scale: f32,
+    bias: f32,
+}
+impl Simulation {
+    /// Create a new simulation, with the lockin summing `nsw` pixels of `periods_per_pixel`
+    /// oscillation periods.
+    ///
+    /// The lockin scaling factor is chosen such that the free amplitude reads as 1.0, and the
+    /// Z bias starts from the controller output, with the surface far away. The Z bias is limited
+    /// to the output range and slew rate of the controller, as in the firmware.
+    pub fn new(
+        config: PlantConfig,
+        periods_per_pixel: u32,
+        nsw: usize,
+        controller: Box<dyn Controller>,
+    ) -> Self {
+        let scale = (1.0 / (config.free_amplitude * nsw as f64)) as f32;
+        let bias = controller.output();
+        let mut plant = Plant::new(config, bias);
+        plant.set_height(-1e-6);
+        let limits = controller.limits();
+        let setpoint = controller.setpoint();
+        let mut feedback = ZFeedback::new(scale, setpoint, limits.range());
+        let output_path = feedback.output_path_mut();
+        output_path.set_slew_rate(limits.slew_rate());
+        output_path.reset(bias);
+        Simulation {
+            plant,
+            lockin: Lockin::new(periods_per_pixel, nsw, 0.0),
+            average: SlidingAverage::new(),
+            feedback,
+            controller,
+            mode: Mode::Auto,
+            autotune: None,
+            setpoint,
+            setpoint_rate: 0.0,
+            response: None,
+            pll: None,
+            agc: None,
+            apu_period: 1,
+            apu_wait: 0,
+            applied_shift: 0.0,
+            applied_drive: 0.0,
+            scale,
+            bias,
+        }
+    }
+
+    /// Current Z bias.
+    pub fn bias(&self) -> f32 {
+        self.bias
+    }
+
+    /// Run one iteration of the loop.
+    pub fn iterate(&mut self) -> Sample {
+        let (i, q) = self.lockin.pixel(&mut self.plant, self.bias);
+        let (i, q) = self.average.update(i, q);
+        let mut shift = 0.0;
+        if let Some(pll) = &mut self.pll {
+            pll.update_applied(phase(i, q), self.applied_shift);
+            shift = pll.shift();
+            self.feedback.error_path_mut().set_frequency_shift(shift);
+        }
+        let mut drive = 1.0;
+        if let Some(agc) = &mut self.agc {
+            let amplitude = amplitude(i * self.scale, q * self.scale);
+            drive = agc.update_applied(amplitude, self.applied_drive);
+        }
+        let tune = self.autotune.as_ref().map(|settings| &settings[..]);
+        let controller = &mut *self.controller;
+        self.bias = self.feedback.update(controller, (i, q), self.mode, tune);
+        if let Some(response) = self.feedback.poll(controller.limits().range()) {
+            self.response = Some(response);
+        }
+        let setpoint = self
+            .feedback
+            .ramp_setpoint(self.setpoint, self.setpoint_rate);
+        controller.load_params(&ControllerParams {
+            setpoint,
+            slew: controller.limits().slew_rate(),
+            coefficients: &[],
+        });
+        self.follow();
+        Sample {
+            time: self.plant.time(),
+            i,
+            q,
+            amp2: self.feedback.error(),
+            bias: self.bias,
+            shift,
+            drive,
+            z: self.plant.z(),
+            height: self.plant.height(),
+        }
+    }
+
+    /// Carry out a command sent by the APU before the next iteration, see
+    /// [`ZFeedback::execute`], and keep the response.
+    ///
+    /// The simulated microscope has no X and Y scanner, so a raster scan is rejected, and the
+    /// self-test has no checks.
+    pub fn execute(&mut self, command: Command) -> Response {
+        let area = || ScanArea::from_coefficients(&[0.0; ScanArea::NR_COEFFICIENTS]);
+        let response = self
+            .feedback
+            .execute(command, &mut *self.controller, area, || 0);
+        self.response = Some(response);
+        response
+    }
+
+    /// The APU side: apply the drive published by the firmware, once every `apu_period`
+    /// iterations, and report it back.
+    fn follow(&mut self) {
+        if self.apu_period == 0 {
+            return;
+        }
+        self.apu_wait = self.apu_wait.saturating_sub(1);
+        if self.apu_wait > 0 {
+            return;
+        }
+        self.apu_wait = self.apu_period;
+        if let Some(pll) = &self.pll {
+            self.plant.set_drive_frequency(pll.frequency() as f64);
+            self.applied_shift = pll.shift();
+        }
+        if let Some(agc) = &self.agc {
+            self.plant.set_drive_amplitude(agc.output() as f64);
+            self.applied_drive = agc.output();
+        }
+    }
+
+    /// Run `iterations` iterations of the loop, returning the time series.
+    pub fn run(&mut self, iterations: usize) -> Vec<Sample> {
+        (0..iterations).map(|_| self.iterate()).collect()
+    }
+}
+
+/// Write a time series as CSV, with a header line.
+pub fn write_csv(samples: &[Sample], mut writer: impl Write) -> io::Result<()> {
+    writeln!(writer, "time,i,q,amp2,bias,shift,drive,z,height")?;
+    for s in samples {
+        writeln!(
+            writer,
+            "{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e}",
+            s.time, s.i, s.q, s.amp2, s.bias, s.shift, s.drive, s.z, s.height
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,268 @@
+use qafm_control::analyzer::Sweep;
+use qafm_control::autotune::{RelayAutotune, TuneState};
+use qafm_control::average::Averaging;
+use qafm_control::controller::Mode;
+use qafm_control::mailbox::{Command, Completion, Request};
+use qafm_control::pid::{AntiWindup, PidController};
+use qafm_control::pll::PhaseLockedLoop;
+use qafm_sim::{PlantConfig, Sample, Simulation};
+use std::f32::consts::FRAC_PI_2;
+
+/// Set point: 70 % of the free amplitude.
+const SETPOINT: f32 = 0.49;
+
+/// Simulation far from a surface 45 nm above the fully retracted piezo.
+fn approach() -> Simulation {
+    let pid_c = PidController::builder()
+        .setpoint(SETPOINT)
+        .gain_p(0.002)
+        .gain_i(0.002)
+        .limit_output(0.0, 1.0)
+        .anti_windup(AntiWindup::BackCalculation { kt: 0.5 })
+        .build();
+    let mut sim = Simulation::new(PlantConfig::default(), 4, 16, Box::new(pid_c));
+    sim.controller.reset(1.0);
+    sim.plant.set_height(45e-9);
+    sim
+}
+
+fn settled(samples: &[Sample]) -> bool {
+    samples.iter().all(|s| (s.amp2 - SETPOINT).abs() < 1e-3)
+}
+
+#[test]
+fn approach_settles_at_setpoint() {
+    let mut sim = approach();
+    let samples = sim.run(1000);
+    // free oscillation at the start
+    assert!(samples[100].amp2 > 0.95);
+    assert!(settled(&samples[600..]));
+    // the tip never crashed into the surface on the way
+    assert!(samples[200..].iter().all(|s| s.amp2 > 0.45));
+    assert!(samples.iter().all(|s| (0.0..=1.0).contains(&s.bias)));
+}
+
+#[test]
+fn sliding_average_replaces_lockin_sum() {
+    let mut summed = approach();
+    // single pixels from the lockin, averaged over as many pixels instead
+    let pid_c = PidController::builder()
+        .setpoint(SETPOINT)
+        .gain_p(0.002)
+        .gain_i(0.002)
+        .limit_output(0.0, 1.0)
+        .anti_windup(AntiWindup::BackCalculation { kt: 0.5 })
+        .build();
+    let mut averaged = Simulation::new(PlantConfig::default(), 4, 1, Box::new(pid_c));
+    averaged.controller.reset(1.0);
+    averaged.plant.set_height(45e-9);
+    averaged.average.configure(Averaging::Boxcar, 16);
+    let samples = averaged.run(1000);
+    assert!(settled(&samples[600..]));
+    summed.run(1000);
+    assert!((averaged.plant.z() - summed.plant.z()).abs() < 0.05e-9);
+}
+
+#[test]
+fn tracks_topography_step() {
+    let mut sim = approach();
+    sim.run(1000);
+    let z_before = sim.plant.z();
+    sim.plant.set_height(47e-9);
+    let samples = sim.run(1000);
+    assert!(settled(&samples[500..]));
+    let dz = sim.plant.z() - z_before;
+    assert!((dz - 2e-9).abs() < 0.05e-9);
+}
+
+#[test]
+fn hold_keeps_z_bias() {
+    let mut sim = approach();
+    sim.run(1000);
+    let bias = sim.bias();
+    sim.mode = Mode::Hold;
+    sim.plant.set_height(46e-9);
+    let samples = sim.run(200);
+    assert!(samples.iter().all(|s| s.bias == bias));
+    // feedback continues from the held bias
+    sim.mode = Mode::Auto;
+    let samples = sim.run(1000);
+    assert!((samples[0].bias - bias).abs() < 1e-3);
+    assert!(settled(&samples[500..]));
+}
+
+#[test]
+fn retract_command_until_released() {
+    let mut sim = approach();
+    sim.run(1000);
+    let retract = Command::decode((1 << 16) | Request::Retract.code(), 1.0);
+    assert_eq!(sim.execute(retract).completion, Completion::Running);
+    let samples = sim.run(10);
+    assert_eq!(samples[9].bias, 1.0);
+    let response = sim.response.unwrap();
+    assert_eq!(
+        (response.sequence, response.completion),
+        (1, Completion::Done)
+    );
+    // the feedback approaches again once released
+    let release = Command::decode((2 << 16) | Request::Hold.code(), 0.0);
+    sim.execute(release);
+    let samples = sim.run(1000);
+    assert!(settled(&samples[800..]));
+}
+
+#[test]
+fn track_is_rate_limited_at_the_dac() {
+    let pid_c = PidController::builder()
+        .setpoint(SETPOINT)
+        .gain_i(0.002)
+        .limit_output(0.0, 1.0)
+        .limit_slew_rate(0.01)
+        .build();
+    let mut sim = Simulation::new(PlantConfig::default(), 4, 16, Box::new(pid_c));
+    sim.mode = Mode::Track(0.2);
+    let samples = sim.run(100);
+    // the controller jumps to the tracked value, the Z bias ramps
+    assert_eq!(sim.controller.output(), 0.2);
+    assert!((samples[0].bias - 0.01).abs() < 1e-6);
+    assert_eq!(samples[99].bias, 0.2);
+    assert!(samples
+        .windows(2)
+        .all(|pair| (pair[1].bias - pair[0].bias).abs() <= 0.01 + 1e-6));
+}
+
+#[test]
+fn relay_is_rate_limited_at_the_dac() {
+    let mut sim = approach();
+    let mut amp2 = sim.run(1000)[999].amp2;
+    let slew = 0.0005;
+    sim.feedback.output_path_mut().set_slew_rate(slew);
+    let mut tuner = RelayAutotune::new(0.005, 0.005, 4, 20_000);
+    tuner.start(SETPOINT, sim.controller.output());
+    let mut bias = sim.bias();
+    while tuner.state() == TuneState::Running {
+        sim.mode = Mode::Track(tuner.update(amp2));
+        let sample = sim.iterate();
+        assert!((sample.bias - bias).abs() <= slew + 1e-6);
+        (amp2, bias) = (sample.amp2, sample.bias);
+    }
+    assert_eq!(tuner.state(), TuneState::Done);
+}
+
+#[test]
+fn loop_has_phase_margin() {
+    let mut sim = approach();
+    sim.run(1000);
+    sim.feedback.analyzer_mut().start(Sweep {
+        f_start: 1e-3,
+        f_stop: 0.1,
+        points: 12,
+        amplitude: 1e-3,
+        settle_cycles: 2.0,
+        measure_cycles: 4.0,
+    });
+    let mut points = Vec::new();
+    while sim.feedback.analyzer_mut().is_running() {
+        sim.iterate();
+        points.extend(sim.feedback.analyzer_mut().completed());
+    }
+    assert_eq!(points.len(), 12);
+    let gain = |l: [f32; 2]| l[0].hypot(l[1]);
+    // integral action at low frequencies, roll-off at high frequencies
+    assert!(gain(points[0].1.open_loop) > 1.0);
+    assert!(gain(points[11].1.open_loop) < 1.0);
+    // at the crossover, L stays away from -1
+    let (_, crossover) = points
+        .iter()
+        .find(|(_, p)| gain(p.open_loop) < 1.0)
+        .unwrap();
+    let phase = crossover.open_loop[1].atan2(crossover.open_loop[0]);
+    assert!(phase.abs() < 150_f32.to_radians());
+}
+
+#[test]
+fn pll_tracks_resonance() {
+    let pid_c = PidController::builder().limit_output(0.0, 1.0).build();
+    let mut sim = Simulation::new(PlantConfig::default(), 4, 16, Box::new(pid_c));
+    sim.mode = Mode::Hold;
+    // start 50 Hz above the free resonance
+    let mut pll = PhaseLockedLoop::new(300_050.0, 500.0);
+    pll.set_setpoint(-FRAC_PI_2);
+    pll.set_gains(50.0, 5.0);
+    sim.pll = Some(pll);
+    let samples = sim.run(1500);
+    let last = samples.last().unwrap();
+    assert!((last.q.atan2(last.i) + FRAC_PI_2).abs() < 0.01);
+    // the integration of the plant lowers its resonance by a few Hz
+    assert!((last.shift + 50.0).abs() < 5.0);
+    assert!(samples[1000..]
+        .iter()
+        .all(|s| (s.shift - last.shift).abs() < 0.1));
+}
+
+#[test]
+fn pll_holds_without_apu() {
+    let pid_c = PidController::builder().limit_output(0.0, 1.0).build();
+    let mut sim = Simulation::new(PlantConfig::default(), 4, 16, Box::new(pid_c));
+    sim.mode = Mode::Hold;
+    let mut pll = PhaseLockedLoop::new(300_050.0, 500.0);
+    pll.set_setpoint(-FRAC_PI_2);
+    pll.set_gains(50.0, 5.0);
+    sim.pll = Some(pll);
+
+    // nobody applies the drive frequency: one step, then no windup
+    sim.apu_period = 0;
+    let samples = sim.run(1000);
+    assert!(samples.iter().all(|s| s.shift == samples[0].shift));
+    assert!(samples[0].shift.abs() < 500.0);
+
+    // the APU follows every 10 iterations, the PLL locks at that rate
+    sim.apu_period = 10;
+    let samples = sim.run(15_000);
+    let last = samples.last().unwrap();
+    assert!((last.shift + 50.0).abs() < 5.0);
+}
+
+#[test]
+fn agc_keeps_amplitude() {
+    let pid_c = PidController::builder().limit_output(0.0, 1.0).build();
+    let mut sim = Simulation::new(PlantConfig::default(), 4, 16, Box::new(pid_c));
+    sim.mode = Mode::Hold;
+    let agc = PidController::builder()
+        .setpoint(0.8)
+        .gain_p(0.2)
+        .gain_i(0.05)
+        .limit_output(0.0, 2.0)
+        .anti_windup(AntiWindup::BackCalculation { kt: 0.5 })
+        .build();
+    sim.agc = Some(agc);
+    let samples = sim.run(1000);
+    // far from the surface, the amplitude is proportional to the drive
+    assert!(samples[500..]
+        .iter()
+        .all(|s| (s.amp2.sqrt() - 0.8).abs() < 1e-3 && (s.drive - 0.8).abs() < 0.01));
+}
+
+#[test]
+fn agc_holds_without_apu() {
+    let pid_c = PidController::builder().limit_output(0.0, 1.0).build();
+    let mut sim = Simulation::new(PlantConfig::default(), 4, 16, Box::new(pid_c));
+    sim.mode = Mode::Hold;
+    let agc = PidController::builder()
+        .setpoint(0.8)
+        .gain_p(0.2)
+        .gain_i(0.05)
+        .limit_output(0.0, 2.0)
+        .build();
+    sim.agc = Some(agc);
+    sim.apu_period = 0;
+    let samples = sim.run(1000);
+    // one step from the initial drive, then no windup although the amplitude stays off
+    assert!(samples.iter().all(|s| s.drive == samples[0].drive));
+    assert!(samples[0].drive < 2.0);
+    sim.apu_period = 5;
+    let samples = sim.run(5000);
+    assert!(samples[4000..]
+        .iter()
+        .all(|s| (s.amp2.sqrt() - 0.8).abs() < 1e-3));
+}
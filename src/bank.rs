@@ -0,0 +1,73 @@
+use crate::registry::{Read, Slot, SlotType, Write, U32F32};
+use crate::Params;
+use core::ops::Range;
+use qafm_control::coefficients::store;
+use qafm_control::validate::Rejection;
+
+/// Number of coefficients in the bank.
+pub const BANK_SIZE: usize = 256;
+
+/// A bank of coefficients in RPU memory, written by the APU one at a time through a parameter
+/// slot.
+///
+/// Too many coefficients are needed (e.g. by filters and compensators) to give each of them its
+/// own parameter slot. Instead, the APU writes one coefficient at a time to the port slot:
+/// - low 32 bits: index of the coefficient in bits 0-15, and a tag in bits 16-31 that the APU
+///   changes on every write, so that writing the same value twice is noticed
+/// - high 32 bits: the value of the coefficient, as f32
+///
+/// The RPU acknowledges each write by echoing the low 32 bits to the acknowledge slot, together
+/// with the stored value in the high 32 bits. An index outside the bank (out of range), or a
+/// value that is NaN or infinite (not finite), is acknowledged with a NaN value and otherwise
+/// ignored: the coefficients keep their last values, and the rejection is kept until the next
+/// write, see [`CoefficientBank::rejection`]. The APU should wait for the acknowledgement before
+/// writing the next coefficient.
+pub struct CoefficientBank {
+    values: [f32; BANK_SIZE],
+    port: Slot<U32F32, Read>,
+    ack: Slot<U32F32, Write>,
+    last: u64,
+    rejection: Option<Rejection>,
+}
+impl CoefficientBank {
+    /// Create a new bank with all coefficients zero, using parameter slots `port` and `ack`.
+    ///
+    /// Whatever is left in the port slot from before is not applied.
+    pub fn new(params: &Params, port: Slot<U32F32, Read>, ack: Slot<U32F32, Write>) -> Self {
+        CoefficientBank {
+            values: [0.0; BANK_SIZE],
+            last: port.read_raw(params),
+            port,
+            ack,
+            rejection: None,
+        }
+    }
+
+    /// Apply and acknowledge a new write from the APU, if any.
+    pub fn poll(&mut self, params: &Params) {
+        let word = self.port.read_raw(params);
+        if word == self.last {
+            return;
+        }
+        self.last = word;
+
+        let (tag_index, value) = U32F32::unpack(word);
+        let index = (tag_index & 0xffff) as usize;
+        self.rejection = store(&mut self.values, index, value).err();
+        let stored = match self.rejection {
+            None => value,
+            Some(_) => f32::NAN,
+        };
+        self.ack.write(params, (tag_index, stored));
+    }
+
+    /// Why the last coefficient written was rejected, if it was.
+    pub fn rejection(&self) -> Option<Rejection> {
+        self.rejection
+    }
+
+    /// A block of coefficients.
+    pub fn get(&self, range: Range<usize>) -> &[f32] {
+        &self.values[range]
+    }
+}
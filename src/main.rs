@@ -8,10 +8,13 @@ use core::sync::atomic::{AtomicBool, Ordering};
 use cortex_r::gic::{ICC, ICD};
 use zup_rt::{entry, interrupt};
 
-mod pid;
+mod bank;
+mod registry;
+mod slots;
 mod types;
 use types::{BiasDac, Data, Params};
 mod user;
+mod validate;
 
 static GOT_IRQ: AtomicBool = AtomicBool::new(false);
 
@@ -36,6 +39,9 @@ fn PL_PS_04() {
     GOT_IRQ.store(true, Ordering::Relaxed);
 }
 
+/// Approximate frequency of the RPU clock, see [`read_cycle_counter`].
+const RPU_CLOCK_HZ: f32 = 500e6;
+
 /// Read current value of CPU cycle counter.
 ///
 /// This is the number of RPU clock cycles elapsed since the start of the program.
@@ -145,7 +151,7 @@ fn main() -> ! {
     let params = unsafe { types::ParamsMapPtr::from_ptr(ADDR_PARAMS as *mut _) };
 
     // clear RPU status
-    params.inner().idx(0).write(0);
+    slots::IRQ_COUNT.write(&params.inner(), (0, 0));
 
     // initialize GOT_IRQ to false to avoid processing initial spurious IRQ (if any)
     GOT_IRQ.store(false, Ordering::Relaxed);
@@ -0,0 +1,339 @@
+//! Declarative registry of the parameter map.
+//!
+//! Each slot of the parameter map is declared once, with [`param_map!`], giving its index,
+//! name, direction and the types of its two 32-bit halves. From the declaration follow:
+//! - a typed [`Slot`] constant per slot, which can only be read if the RPU reads it, and only be
+//!   written if the RPU writes it
+//! - a compile-time check that no two slots share an index, and that all fit in the map
+//! - a JSON descriptor of the whole map in the `.qafm.params` section of the ELF file, for
+//!   host tooling to pack and unpack values without its own copy of the table
+//!
+//! The descriptor looks like:
+//!
+//! ```json
+//! {"magic":"QAFM","version":1,"slots":[
+//!   {"idx":0,"name":"IRQ_COUNT","dir":"write","type":"u32x2","fields":["iterations","cycles"]},
+//!   ...
+//! ]}
+//! ```
+//!
+//! Slots read by the RPU are read from a [`Snapshot`], a copy of the parameter map taken under
+//! a sequence lock, so that a group of slots written by the APU is never applied half-written.
+//! Groups of slots written by the RPU are guarded the same way with a [`Sequence`].
+//!
+//! In the descriptor, `dir` is from the point of view of the RPU, and `type` one of `f32x2`,
+//! `u32x2`, `u32_f32` and `f32_u32`, low half first. The section is not loaded into RPU memory,
+//! see `link.x`.
+
+use crate::types::NR_PARAMS;
+use crate::Params;
+use core::marker::PhantomData;
+use qafm_control::seqlock;
+
+pub use qafm_control::seqlock::Sequence;
+
+/// Magic number published in the parameter map at boot, "QAFM" in ASCII.
+pub const PARAMS_MAGIC: u32 = u32::from_le_bytes(*b"QAFM");
+
+/// Direction of a slot: written by the APU, read by the RPU.
+pub struct Read;
+/// Direction of a slot: written by the RPU, read by the APU.
+pub struct Write;
+
+/// Interpretation of the 64 bits of a slot.
+pub trait SlotType {
+    /// Value of the slot, low half first.
+    type Value;
+    /// Pack a value into a slot.
+    fn pack(value: Self::Value) -> u64;
+    /// Unpack a value from a slot.
+    fn unpack(word: u64) -> Self::Value;
+}
+
+/// Two f32 values.
+pub struct F32x2;
+impl SlotType for F32x2 {
+    type Value = (f32, f32);
+    fn pack((low, high): (f32, f32)) -> u64 {
+        f32x2_to_u64(low, high)
+    }
+    fn unpack(word: u64) -> (f32, f32) {
+        u64_to_f32x2(word)
+    }
+}
+
+/// Two u32 values.
+pub struct U32x2;
+impl SlotType for U32x2 {
+    type Value = (u32, u32);
+    fn pack((low, high): (u32, u32)) -> u64 {
+        u32x2_to_u64(low, high)
+    }
+    fn unpack(word: u64) -> (u32, u32) {
+        u64_to_u32x2(word)
+    }
+}
+
+/// A u32 value in the low half, and an f32 value in the high half.
+pub struct U32F32;
+impl SlotType for U32F32 {
+    type Value = (u32, f32);
+    fn pack((low, high): (u32, f32)) -> u64 {
+        u32x2_to_u64(low, high.to_bits())
+    }
+    fn unpack(word: u64) -> (u32, f32) {
+        let (low, high) = u64_to_u32x2(word);
+        (low, f32::from_bits(high))
+    }
+}
+
+/// An f32 value in the low half, and a u32 value in the high half.
+pub struct F32U32;
+impl SlotType for F32U32 {
+    type Value = (f32, u32);
+    fn pack((low, high): (f32, u32)) -> u64 {
+        u32x2_to_u64(low.to_bits(), high)
+    }
+    fn unpack(word: u64) -> (f32, u32) {
+        let (low, high) = u64_to_u32x2(word);
+        (f32::from_bits(low), high)
+    }
+}
+
+/// A slot of the parameter map, holding a `T` in direction `D`, see [`param_map!`].
+pub struct Slot<T, D> {
+    idx: usize,
+    _type: PhantomData<(T, D)>,
+}
+impl<T: SlotType, D> Slot<T, D> {
+    /// Slot at index `idx` of the parameter map.
+    pub const fn new(idx: usize) -> Self {
+        Slot {
+            idx,
+            _type: PhantomData,
+        }
+    }
+
+    /// Index of the slot in the parameter map.
+    pub const fn idx(&self) -> usize {
+        self.idx
+    }
+
+    /// Raw contents of the slot.
+    pub fn read_raw(&self, params: &Params) -> u64 {
+        params.idx(self.idx).read()
+    }
+}
+impl<T: SlotType> Slot<T, Read> {
+    /// Read the value written by the APU, as of the last consistent snapshot.
+    pub fn read(&self, snapshot: &Snapshot) -> T::Value {
+        T::unpack(snapshot.words[self.idx])
+    }
+}
+impl<T: SlotType> Slot<T, Write> {
+    /// Write a value for the APU.
+    pub fn write(&self, params: &Params, value: T::Value) {
+        params.idx(self.idx).write(T::pack(value));
+    }
+}
+
+/// Copy of the parameter map, taken while the APU is not writing a group of slots.
+///
+/// Reader side of a sequence lock: the APU makes the generation odd before writing a group of
+/// slots, and even again after, see [`seqlock::read`]. An APU that never changes the
+/// generation gets every write applied as soon as it is seen, as without a lock.
+#[derive(Clone)]
+pub struct Snapshot {
+    words: [u64; NR_PARAMS],
+}
+impl Snapshot {
+    /// Take a first snapshot, waiting as long as the APU is writing.
+    pub fn new(params: &Params, generation: impl Fn(&Params) -> u32) -> Self {
+        let mut snapshot = Snapshot {
+            words: [0; NR_PARAMS],
+        };
+        while !snapshot.update(params, &generation) {
+            core::hint::spin_loop();
+        }
+        snapshot
+    }
+
+    /// Take a new snapshot if the parameter map is consistent, i.e. the `generation` is even
+    /// and the same before and after copying. Otherwise, keep the previous snapshot and return
+    /// `false`.
+    pub fn update(&mut self, params: &Params, generation: impl Fn(&Params) -> u32) -> bool {
+        let copy = || core::array::from_fn(|idx| params.idx(idx).read());
+        match seqlock::read(|| generation(params), copy) {
+            Some(words) => {
+                self.words = words;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Go back to the value of `slot` in an earlier snapshot.
+    pub fn restore<T: SlotType>(&mut self, earlier: &Snapshot, slot: &Slot<T, Read>) {
+        self.words[slot.idx] = earlier.words[slot.idx];
+    }
+
+    /// Go back to the high half of `slot` in an earlier snapshot, keeping the low half.
+    pub fn restore_high<T: SlotType>(&mut self, earlier: &Snapshot, slot: &Slot<T, Read>) {
+        let (word, earlier) = (self.words[slot.idx], earlier.words[slot.idx]);
+        self.words[slot.idx] = (earlier & 0xffff_ffff_0000_0000) | (word & 0xffff_ffff);
+    }
+}
+
+/// Check that the slot indices are distinct and within the parameter map.
+pub const fn check_slots(idx: &[usize]) -> bool {
+    let mut i = 0;
+    while i < idx.len() {
+        if idx[i] >= NR_PARAMS {
+            return false;
+        }
+        let mut j = i + 1;
+        while j < idx.len() {
+            if idx[i] == idx[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Copy a descriptor into a byte array, to be placed in its own section.
+pub const fn descriptor_bytes<const N: usize>(descriptor: &str) -> [u8; N] {
+    let bytes = descriptor.as_bytes();
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < N {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+}
+
+/// Declare the parameter map, see the [module documentation](self).
+///
+/// ```ignore
+/// param_map! {
+///     version: 1;
+///     /// nr of processed iterations, CPU cycle counter
+///     IRQ_COUNT = 0: write u32x2 (iterations, cycles);
+///     /// control word, manual / tracked Z bias
+///     CONTROL = 7: read u32_f32 (control, z_bias);
+/// }
+/// ```
+///
+/// Also defines `LAYOUT_VERSION`, the version of the layout, and `DESCRIPTOR`, the JSON
+/// descriptor.
+macro_rules! param_map {
+    (
+        version: $version:literal;
+        $(#[$meta0:meta])*
+        $name0:ident = $idx0:literal: $dir0:ident $ty0:ident ($low0:ident, $high0:ident);
+        $(
+            $(#[$meta:meta])*
+            $name:ident = $idx:literal: $dir:ident $ty:ident ($low:ident, $high:ident);
+        )*
+    ) => {
+        /// Version of the layout of the parameter map, the control word and the coefficient
+        /// bank, published at boot.
+        ///
+        /// Bump on every incompatible change, i.e. whenever a slot, field or bank block is
+        /// moved or changes meaning.
+        pub const LAYOUT_VERSION: u32 = $version;
+
+        $(#[$meta0])*
+        pub const $name0: $crate::registry::Slot<
+            param_map!(@type $ty0),
+            param_map!(@dir $dir0),
+        > = $crate::registry::Slot::new($idx0);
+        $(
+            $(#[$meta])*
+            pub const $name: $crate::registry::Slot<
+                param_map!(@type $ty),
+                param_map!(@dir $dir),
+            > = $crate::registry::Slot::new($idx);
+        )*
+
+        const _: () = assert!(
+            $crate::registry::check_slots(&[$idx0, $($idx),*]),
+            "parameter slots must be distinct and within the parameter map"
+        );
+
+        /// JSON descriptor of the parameter map, for host tooling.
+        pub const DESCRIPTOR: &str = concat!(
+            "{\"magic\":\"QAFM\",\"version\":",
+            $version,
+            ",\"slots\":[",
+            param_map!(@json $name0, $idx0, $dir0, $ty0, $low0, $high0),
+            $(",", param_map!(@json $name, $idx, $dir, $ty, $low, $high),)*
+            "]}"
+        );
+
+        #[used]
+        #[link_section = ".qafm.params"]
+        static DESCRIPTOR_SECTION: [u8; DESCRIPTOR.len()] =
+            $crate::registry::descriptor_bytes(DESCRIPTOR);
+    };
+
+    (@json $name:ident, $idx:literal, $dir:ident, $ty:ident, $low:ident, $high:ident) => {
+        concat!(
+            "{\"idx\":",
+            $idx,
+            ",\"name\":\"",
+            stringify!($name),
+            "\",\"dir\":\"",
+            stringify!($dir),
+            "\",\"type\":\"",
+            stringify!($ty),
+            "\",\"fields\":[\"",
+            stringify!($low),
+            "\",\"",
+            stringify!($high),
+            "\"]}"
+        )
+    };
+
+    (@type f32x2) => { $crate::registry::F32x2 };
+    (@type u32x2) => { $crate::registry::U32x2 };
+    (@type u32_f32) => { $crate::registry::U32F32 };
+    (@type f32_u32) => { $crate::registry::F32U32 };
+
+    (@dir read) => { $crate::registry::Read };
+    (@dir write) => { $crate::registry::Write };
+}
+pub(crate) use param_map;
+
+/// Convenience function to extract two f32 values from one u64 value
+pub fn u64_to_f32x2(val: u64) -> (f32, f32) {
+    let low = f32::from_bits(val as u32);
+    let high = f32::from_bits((val >> 32) as u32);
+    (low, high)
+}
+
+/// Convenience function to extract two u32 values from one u64 value
+pub fn u64_to_u32x2(val: u64) -> (u32, u32) {
+    let low = val as u32;
+    let high = (val >> 32) as u32;
+    (low, high)
+}
+
+/// Convenience function to pack two f32 values into one u64 value
+pub fn f32x2_to_u64(low: f32, high: f32) -> u64 {
+    let low = low.to_bits();
+    let high = high.to_bits();
+    let mut val = low as u64;
+    val |= (high as u64) << 32;
+    val
+}
+
+/// Convenience function to pack two u32 values into one u64 value
+pub fn u32x2_to_u64(low: u32, high: u32) -> u64 {
+    let mut val = low as u64;
+    val |= (high as u64) << 32;
+    val
+}
@@ -0,0 +1,52 @@
+//! The parameter map: slots shared with the APU, see [`param_map!`].
+//!
+//! Directions are from the point of view of the RPU. See [`user_logic`] for the meaning of
+//! the values.
+//!
+//! [`user_logic`]: crate::user::user_logic
+
+use crate::registry::param_map;
+
+param_map! {
+    version: 5;
+    /// nr of processed iterations, CPU cycle counter
+    IRQ_COUNT = 0: write u32x2 (iterations, cycles);
+    /// error signal, Z bias (control signal)
+    ERROR_CONTROL = 1: write f32x2 (error, z_bias);
+    /// lockin amplitude scale, feedforward on Z bias
+    SCALE_FEEDFORWARD = 2: read f32x2 (scale, feedforward);
+    /// feedback set point, proportional gain
+    SETPOINT_KP = 3: read f32x2 (setpoint, kp);
+    /// feedback integral gain, derivative gain
+    KI_KD = 4: read f32x2 (ki, kd);
+    /// scanner X bias, scanner Y bias
+    SCANNER_XY = 5: read f32x2 (x, y);
+    /// Z bias low limit, Z bias high limit
+    Z_LIMITS = 6: read f32x2 (low, high);
+    /// control word, manual / tracked Z bias
+    CONTROL = 7: read u32_f32 (control, z_bias);
+    /// command sequence number and request, command argument
+    COMMAND = 8: read u32_f32 (seq_request, argument);
+    /// proportional set point weight, derivative set point weight
+    SETPOINT_WEIGHTS = 9: read f32x2 (b, c);
+    /// command sequence number and completion, command result
+    RESPONSE = 10: write u32_f32 (seq_completion, result);
+    /// coefficient index and tag, coefficient value
+    BANK_PORT = 11: read u32_f32 (tag_index, value);
+    /// coefficient index and tag, stored coefficient value
+    BANK_ACK = 12: write u32_f32 (tag_index, value);
+    /// autotuned proportional gain, autotuned integral gain
+    AUTOTUNE_KP_KI = 13: write f32x2 (kp, ki);
+    /// autotuned derivative gain, autotuning state
+    AUTOTUNE_KD_STATE = 14: write f32_u32 (kd, state);
+    /// PLL frequency shift, AGC drive amplitude
+    DRIVE = 15: write f32x2 (shift, drive);
+    /// lockin configuration, IF phase advance per pixel
+    LOCKIN_CONFIG = 16: read u32x2 (config, if_step);
+    /// magic number "QAFM", parameter layout version
+    IDENTITY = 17: write u32x2 (magic, version);
+    /// firmware build ID, telemetry sequence
+    BUILD_SEQUENCE = 18: write u32x2 (build_id, telemetry_seq);
+    /// reason the parameters were rejected, index of the slot rejected
+    PARAM_STATUS = 19: write u32x2 (reason, slot);
+}
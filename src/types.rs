@@ -8,12 +8,15 @@ pub struct DataMap {
 }
 pub type Data = reg_map::RegArray<'static, Reg, 4096>;
 
+/// Number of slots in the parameter map, see [`crate::slots`].
+pub const NR_PARAMS: usize = 20;
+
 #[repr(C)]
 #[derive(RegMap)]
 pub struct ParamsMap {
-    inner: [u64; 20], // 60 B
+    inner: [u64; NR_PARAMS], // 160 B
 }
-pub type Params = reg_map::RegArray<'static, Reg, 20>;
+pub type Params = reg_map::RegArray<'static, Reg, NR_PARAMS>;
 
 #[repr(C)]
 #[derive(RegMap)]
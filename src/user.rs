@@ -1,8 +1,191 @@
-use crate::pid::PidController;
+use crate::bank::{CoefficientBank, BANK_SIZE};
 use crate::read_cycle_counter;
+use crate::registry::PARAMS_MAGIC;
+use crate::registry::{f32x2_to_u64, u32x2_to_u64, Sequence, SlotType, Snapshot, U32F32};
 use crate::set_dc_bias;
+use crate::slots;
+use crate::validate::{status_word, validate, Status};
 use crate::wait_for_new_data;
+use crate::RPU_CLOCK_HZ;
 use crate::{BiasDac, Data, Params};
+use core::ops::Range;
+use qafm_control::analyzer::{Point, Sweep};
+use qafm_control::autotune::RelayAutotune;
+use qafm_control::average::{Averaging, SlidingAverage};
+use qafm_control::coefficients::{code, count};
+use qafm_control::compensator::{IirCompensator, LeadLag};
+use qafm_control::controller::{Controller, ControllerParams, Mode};
+use qafm_control::feedback::ZFeedback;
+use qafm_control::filter::Cascade;
+use qafm_control::frame::{unpack, Combination, Derotator, FrameLayout, LockinMode, Spectrum};
+use qafm_control::mailbox::Mailbox;
+use qafm_control::pid::{
+    discretize_gains, AntiWindup, DerivativeMode, PidController, DEFAULT_TAU,
+};
+use qafm_control::pll::PhaseLockedLoop;
+use qafm_control::ramp::RateLimiter;
+use qafm_control::scan::ScanArea;
+use qafm_control::schedule::GainSchedule;
+use qafm_control::seqlock::generation_field;
+use qafm_control::signal::{amplitude, phase, ErrorSignal};
+use qafm_control::timing::PeriodEstimator;
+
+// firmware build ID, `BUILD_ID`, generated by the build script
+include!(concat!(env!("OUT_DIR"), "/build_id.rs"));
+
+/// Control word flag: hold the Z output.
+const CTRL_HOLD: u32 = 1 << 0;
+/// Control word flag: derivative on error instead of on measurement.
+const CTRL_DERIV_ON_ERROR: u32 = 1 << 1;
+/// Control word flag: PID gains and derivative filter given in physical units.
+const CTRL_PHYSICAL_UNITS: u32 = 1 << 2;
+/// Control word field: control law.
+const CTRL_LAW_SHIFT: u32 = 3;
+const CTRL_LAW_MASK: u32 = 0b11 << CTRL_LAW_SHIFT;
+/// Control word field: operating mode.
+const CTRL_MODE_SHIFT: u32 = 5;
+const CTRL_MODE_MASK: u32 = 0b11 << CTRL_MODE_SHIFT;
+/// Control word flag: relay autotuning.
+const CTRL_TUNE: u32 = 1 << 7;
+/// Control word field: operating point of the gain schedule.
+const CTRL_SCHED_SHIFT: u32 = 8;
+const CTRL_SCHED_MASK: u32 = 0b11 << CTRL_SCHED_SHIFT;
+/// Control word flag: network analyzer.
+const CTRL_ANALYZER: u32 = 1 << 10;
+/// Control word field: error signal.
+const CTRL_SIGNAL_SHIFT: u32 = 11;
+const CTRL_SIGNAL_MASK: u32 = 0b11 << CTRL_SIGNAL_SHIFT;
+/// Control word flag: phase-locked loop on the drive frequency.
+const CTRL_PLL: u32 = 1 << 13;
+/// Control word flag: amplitude control on the drive amplitude.
+const CTRL_AGC: u32 = 1 << 14;
+/// Control word flag: error signal from the intermodulation spectrum.
+const CTRL_IMOD: u32 = 1 << 15;
+/// Control word field: generation of the parameters written by the APU.
+const CTRL_GEN_SHIFT: u32 = 24;
+
+/// Coefficient bank: lead-lag compensator.
+const BANK_LEAD_LAG: Range<usize> = 0..3;
+/// Coefficient bank: IIR compensator.
+const BANK_IIR: Range<usize> = 8..(8 + IirCompensator::<IIR_SECTIONS>::NR_COEFFICIENTS);
+/// Number of biquad sections of the IIR compensator.
+const IIR_SECTIONS: usize = 2;
+/// Coefficient bank: filter on amp^2, before the controller.
+const BANK_AMP2_FILTER: Range<usize> = 32..(32 + Cascade::<FILTER_SECTIONS>::NR_DESIGN_PARAMS);
+/// Coefficient bank: filter on Z bias, after the controller.
+const BANK_Z_FILTER: Range<usize> = 64..(64 + Cascade::<FILTER_SECTIONS>::NR_DESIGN_PARAMS);
+/// Number of biquad sections of the amp^2 and Z bias filters.
+const FILTER_SECTIONS: usize = 4;
+/// Coefficient bank: relay autotuning settings.
+const BANK_AUTOTUNE: Range<usize> = 96..(96 + RelayAutotune::NR_SETTINGS);
+/// Coefficient bank: PID error deadband, integrator leak and anti-windup strategy.
+const BANK_PID_OPTIONS: Range<usize> = 100..(100 + PidController::NR_OPTIONS);
+/// Coefficient bank: gain schedule, number of entries followed by the entries.
+const BANK_SCHEDULE: Range<usize> =
+    104..(105 + SCHEDULE_ENTRIES * GainSchedule::<SCHEDULE_ENTRIES>::NR_PARAMS);
+/// Maximum number of entries in the gain schedule.
+const SCHEDULE_ENTRIES: usize = 8;
+/// Coefficient bank: network analyzer sweep.
+const BANK_ANALYZER: Range<usize> = 140..(140 + Sweep::NR_COEFFICIENTS);
+/// Coefficient bank: phase reference of the phase error signal.
+const BANK_PHASE_REFERENCE: Range<usize> = 146..147;
+/// Coefficient bank: phase-locked loop, followed by the frequency shift applied by the APU.
+const BANK_PLL: Range<usize> = 148..(149 + PhaseLockedLoop::NR_SETTINGS);
+/// Coefficient bank: amplitude control.
+const BANK_AGC: Range<usize> = 156..162;
+/// Coefficient bank: layout of the lockin frame, number of input groups followed by the number
+/// of frequencies per group.
+const BANK_FRAME: Range<usize> = 164..(164 + FrameLayout::<FRAME_GROUPS>::NR_COEFFICIENTS);
+/// Maximum number of input groups in the lockin frame.
+const FRAME_GROUPS: usize = 8;
+/// Coefficient bank: intermodulation signal, combination followed by two weights per tone.
+const BANK_IMOD: Range<usize> = 176..(177 + 2 * IMOD_TONES);
+/// Maximum number of tones in the intermodulation spectrum.
+const IMOD_TONES: usize = 32;
+/// Coefficient bank: X and Y scanner slew-rate limit.
+const BANK_SCANNER_SLEW: Range<usize> = 243..244;
+/// Coefficient bank: number of iterations between telemetry updates.
+const BANK_TELEMETRY: Range<usize> = 244..245;
+/// Coefficient bank: area of the raster scan.
+const BANK_SCAN: Range<usize> = 245..(245 + ScanArea::NR_COEFFICIENTS);
+/// Coefficient bank: derivative filter constant and sample rate.
+const BANK_DERIVATIVE_FILTER: Range<usize> = 252..254;
+/// Coefficient bank: set point ramp rate and Z bias slew-rate limit.
+const BANK_RATE_LIMITS: Range<usize> = 254..256;
+
+// all blocks must fit in the coefficient bank
+const _: () = assert!(
+    BANK_SCHEDULE.end <= BANK_SIZE
+        && BANK_ANALYZER.end <= BANK_SIZE
+        && BANK_PHASE_REFERENCE.end <= BANK_SIZE
+        && BANK_PLL.end <= BANK_SIZE
+        && BANK_AGC.end <= BANK_SIZE
+        && BANK_FRAME.end <= BANK_SIZE
+        && BANK_IMOD.end <= BANK_SIZE
+        && BANK_SCANNER_SLEW.end <= BANK_SIZE
+        && BANK_TELEMETRY.end <= BANK_SIZE
+        && BANK_SCAN.end <= BANK_SIZE
+        && BANK_DERIVATIVE_FILTER.end <= BANK_SIZE
+        && BANK_RATE_LIMITS.end <= BANK_SIZE
+);
+
+/// Lockin configuration field: kind of lockin.
+const LOCKIN_MODE_MASK: u32 = 0b11;
+/// Lockin configuration field: averaging of the carrier.
+const LOCKIN_AVERAGE_SHIFT: u32 = 2;
+const LOCKIN_AVERAGE_MASK: u32 = 0b11 << LOCKIN_AVERAGE_SHIFT;
+/// Lockin configuration field: averaging window length in pixels.
+const LOCKIN_WINDOW_SHIFT: u32 = 16;
+/// Maximum window length of the boxcar average, in pixels.
+const AVERAGE_PIXELS: usize = 1024;
+
+/// Self-test result: a parameter was rejected, see slot 19.
+const TEST_PARAMS: u32 = 1 << 0;
+/// Self-test result: the lockin data is NaN or infinite.
+const TEST_LOCKIN: u32 = 1 << 1;
+/// Self-test result: the iteration period is not measured yet.
+const TEST_PERIOD: u32 = 1 << 2;
+/// Self-test result: the Z bias is at one of its limits.
+const TEST_Z_LIMIT: u32 = 1 << 3;
+
+/// Data area: network analyzer progress, followed by the measured points.
+const DATA_ANALYZER: usize = 2048;
+/// Words in the data area per point measured by the network analyzer.
+const ANALYZER_POINT_WORDS: usize = 4;
+/// Maximum number of points measured by the network analyzer.
+const ANALYZER_MAX_POINTS: u32 = 256;
+
+/// Control law of the Z feedback.
+#[derive(Clone, Copy, PartialEq)]
+enum Law {
+    Pid,
+    LeadLag,
+    Iir,
+}
+
+/// Operating point the gain schedule is indexed by.
+#[derive(Clone, Copy, PartialEq)]
+enum Sched {
+    Off,
+    Setpoint,
+    ZBias,
+}
+
+/// All control laws the Z feedback can switch between.
+struct Controllers {
+    pid: PidController,
+    lead_lag: LeadLag,
+    iir: IirCompensator<IIR_SECTIONS>,
+}
+impl Controllers {
+    fn get(&mut self, law: Law) -> &mut dyn Controller {
+        match law {
+            Law::Pid => &mut self.pid,
+            Law::LeadLag => &mut self.lead_lag,
+            Law::Iir => &mut self.iir,
+        }
+    }
+}
 
 /// Function implementing the user logic, including setup and main loop.
 ///
@@ -12,152 +195,900 @@ use crate::{BiasDac, Data, Params};
 /// - DC bias port 3 (channel 2): Y piezo
 ///
 /// # Parameter map
-/// | idx | dir   | low 32 bits                 | high 32 bits                |
-/// |-----|-------|-----------------------------|-----------------------------|
-/// |  0  | write | nr of processed iterations  | CPU cycle counter           |
-/// |  1  | write | amp^2 (error signal)        | Z bias (control signal)     |
-/// |  2  | read  | lockin amplitude scale      | (unused)                    |
-/// |  3  | read  | feedback set point          | proportional gain           |
-/// |  4  | read  | feedback integral gain      | derivative gain             |
-/// |  5  | read  | scanner X bias              | scanner Y bias              |
-/// |  6  | read  | Z bias low limit            | Z bias high limit           |
+/// The slots of the parameter map, their direction and the types of their two halves are
+/// declared once in [`slots`], from which the firmware gets typed accessors and host tooling
+/// gets a JSON descriptor in the `.qafm.params` section of the ELF file, see [`registry`].
+/// Slots are referred to by index below.
+///
+/// [`registry`]: crate::registry
+///
+/// # Handshake
+/// At boot, before reading any parameter, the firmware publishes in slot 17 the magic number
+/// `0x4d464151` ("QAFM" in little-endian ASCII) and the version of the layout of the parameter
+/// map, control word and coefficient bank, and in the low half of slot 18 the build ID of the
+/// firmware: the first 8 hex digits of the git commit, or `QAFM_BUILD_ID` at build time, see
+/// `build.rs`. The APU should clear slot 17 before starting the firmware, wait for the magic
+/// number, and compare the layout version with the one it was written for before relying on
+/// any other slot.
+///
+/// # Consistent updates
+/// Groups of slots are guarded by sequence locks, so that neither side acts on a group that is
+/// half-written, e.g. a new set point with the old gains:
+/// - APU to RPU: the APU makes the generation in bits 24-31 of the control word odd before
+///   writing a group of slots, and even again after. The firmware reads all slots once per
+///   iteration into a snapshot, and keeps the previous snapshot while the generation is odd or
+///   changed during the copy, see [`Snapshot`]. An APU that never changes the generation gets
+///   each write applied as soon as it is seen. At boot, the firmware waits for an even
+///   generation.
+/// - RPU to APU: the firmware makes the telemetry sequence in the high half of slot 18 odd
+///   while writing slots 1, 13, 14 and 15, and even again after, see [`Sequence`]. The APU
+///   should read the sequence before and after the group, and retry unless both are the same
+///   and even. The telemetry is written every iteration, or every N iterations as given in the
+///   coefficient bank, e.g. so that a slow link to the APU can read a whole group in between.
+///
+/// # Validation
+/// Each time a new snapshot is taken, the slots read by the firmware are checked, and a slot with
+/// an invalid value keeps its last good value, see [`validate`]: NaN and infinities are
+/// rejected everywhere, as are negative gains, a lockin
+/// scale that is not positive, normalized biases outside 0 to 1, and Z limits with low not below
+/// high. Slot 19 reports the first slot rejected, lowest index first:
+/// - low half: 0 all valid, 1 NaN or infinity, 2 out of range, 3 low limit not below high limit
+/// - high half: index of the slot rejected
+///
+/// The coefficient bank rejects NaN and infinite values, and indices outside the bank, as they
+/// are written: the coefficients keep their last values and the write is acknowledged with NaN,
+/// see [`CoefficientBank`]. Unless a slot is rejected, slot 19 then reports slot 11 as not finite
+/// or out of range, until the next coefficient is accepted.
+///
+/// The coefficients of the control law are checked by the control law before every iteration,
+/// see [`Controller::check_coefficients`]: PID gains from slots 3 and 4 or from the gain
+/// schedule must not be negative, and the lead-lag and IIR compensators must not have poles
+/// outside the unit circle. Rejected coefficients are not
+/// loaded, the control law keeps its last ones, and unless a slot or a write to the bank is
+/// rejected, slot 19 reports slot 11 as well.
+///
+/// At boot, the firmware waits for a valid set of parameters before starting the feedback.
+///
+/// # Control word
+/// | bit | name | description                                                         |
+/// |-----|------|---------------------------------------------------------------------|
+/// |  0  | HOLD | hold Z output, re-seed integrator to continue bumplessly on release |
+/// |  1  | DERR | derivative on error instead of on measurement                       |
+/// |  2  | PHYS | PID gains and derivative filter in physical units, see below        |
+/// | 3-4 | LAW  | control law: 0 PID, 1 lead-lag, 2 IIR compensator                   |
+/// | 5-6 | MODE | operating mode: 0 auto, 1 hold, 2 manual, 3 track, see below        |
+/// |  7  | TUNE | relay autotuning, overrides the operating mode, see below           |
+/// | 8-9 |SCHED | gain schedule: 0 off, 1 by set point, 2 by Z bias, see below        |
+/// | 10  |  NA  | network analyzer, see below                                         |
+/// |11-12| SIG  | error signal: 0 amp^2, 1 amplitude, 2 phase, 3 PLL frequency shift  |
+/// | 13  | PLL  | phase-locked loop on the drive frequency, see below                 |
+/// | 14  | AGC  | amplitude control on the drive amplitude, see below                 |
+/// | 15  | IMOD | error signal from the intermodulation spectrum, overrides SIG       |
+/// |24-31| GEN  | generation of the parameters, odd while the APU writes, see below   |
+///
+/// # Operating modes
+/// - auto: normal feedback
+/// - hold: Z bias frozen, same as the HOLD flag which takes precedence over the mode field
+/// - manual: Z bias moves to the value in slot 7, no faster than the Z bias slew-rate limit
+/// - track: the control law follows the value in slot 7 directly, e.g. while the APU steps Z
+///   itself; the Z bias follows it at the slew-rate limit of the DAC stage, see below
+///
+/// In all modes the control law is kept ready to continue bumplessly when back in auto. A
+/// manual or tracked value that is NaN, infinite or outside 0 to 1 is rejected, see
+/// [Validation](#validation): the last good value is used instead, while the flags and fields of
+/// the control word still apply.
+///
+/// Whatever the mode, the Z bias sent to the DAC stays within the Z limits of slot 6 and moves
+/// no faster than the Z bias slew-rate limit in bank entry 255: the final stage of
+/// [`OutputPath`](qafm_control::signal::OutputPath) limits it after the network analyzer
+/// perturbation and the Z bias filter.
+///
+/// The control law can be switched while the feedback is running, the new law continues from
+/// the last Z bias. The feedback always starts with the PID controller.
+///
+/// # Coefficient bank
+/// Slots 11 and 12 give access to a bank of coefficients, see [`CoefficientBank`]:
+///
+/// | idx     | description                                                             |
+/// |---------|-------------------------------------------------------------------------|
+/// |  0 -  2 | lead-lag compensator, see [`LeadLag`]                                   |
+/// |  8 - 17 | IIR compensator, 2 biquad sections, see [`IirCompensator`]              |
+/// | 32 - 55 | amp^2 filter, 4 biquad sections, see [`Design::from_params`]            |
+/// | 64 - 87 | Z bias filter, 4 biquad sections, see [`Design::from_params`]           |
+/// | 96 - 99 | autotuning: relay amplitude, hysteresis, nr of cycles, max iterations   |
+/// |100 -103 | PID error deadband, integrator leak per iteration, anti-windup strategy |
+/// |         | (0 clamp, 1 back-calculation, 2 conditional integration) and its kt     |
+/// |104 -136 | gain schedule: nr of entries, then up to 8 entries `[x, kp, ki, kd]`    |
+/// |140 -145 | network analyzer: f start, f stop, nr of points, amplitude, settle and  |
+/// |         | measure cycles, see [`Sweep`]                                           |
+/// |  146    | phase reference in radians, for the phase error signal                  |
+/// |148 -153 | PLL: center frequency, phase set point, kp, ki, max frequency shift,    |
+/// |         | frequency shift applied by the APU                                      |
+/// |156 -161 | AGC: amplitude set point, kp, ki, max drive, initial drive amplitude,   |
+/// |         | drive amplitude applied by the APU                                      |
+/// |164 -172 | lockin frame: nr of input groups, then up to 8 nr of frequencies        |
+/// |176 -240 | intermodulation: combination, then up to 32 weights `[w, wq]` per tone  |
+/// |   243   | X and Y scanner slew-rate limit, per iteration                          |
+/// |   244   | nr of iterations between telemetry updates, 0 for every iteration       |
+/// |245 -251 | raster scan: X, Y of the first pixel, width, height, nr of pixels per   |
+/// |         | line, nr of lines, iterations per pixel, see [`ScanArea`]               |
+/// |252 -253 | derivative filter constant, sample rate in Hz, see below                |
+/// |254 -255 | set point ramp rate, Z bias slew-rate limit, per iteration              |
+///
+/// The bank is cleared when the firmware starts, i.e. all filter sections bypassed.
+///
+/// # Error signal
+/// The SIG field selects the quantity of the lockin data that is fed back, see
+/// [`ErrorSignal`]:
+/// - amp^2: `(scale I)^2 + (scale Q)^2`, the default
+/// - amplitude: `sqrt(amp^2)`, linear in the oscillation amplitude
+/// - phase: `atan2(Q, I)` minus the phase reference, in radians wrapped to -pi..=pi
+/// - frequency shift: the frequency shift of the phase-locked loop in Hz, for FM-AFM
+///
+/// The set point, deadband and gains are in units of the selected quantity. The phase
+/// reference should be chosen such that the set point stays well away from +-pi, where the
+/// phase wraps around. Switching the error signal resets the amp^2 filter, and should be done
+/// while holding the Z output.
+///
+/// # Lockin data
+/// The feedback acts on the carrier in the lockin frame, selected by the kind of lockin in bits
+/// 0-1 of the lockin configuration in slot 16, see [`LockinMode`]:
+/// - 0: `Lockin`, the carrier is the first frequency of the first input group
+/// - 1: `SymmetricLockin`, the carrier is the middle frequency of the first input group; the
+///   layout of the lockin frame must be in the coefficient bank, see below
+///
+/// With a non-zero intermediate frequency, the phase of I and Q advances from one pixel to the
+/// next. The phase advance per pixel, `IF / df` modulo 1 cycle, is given in units of 2^-32
+/// cycles in slot 16, and is undone on all tones of the frame, see [`Derotator`]. It must be 0
+/// with zero IF or perfectly tuned frequencies. The phase of the de-rotated data is arbitrary
+/// but constant, and starts over whenever the phase advance is changed. The de-rotation
+/// assumes that the RPU keeps up with the lockin, i.e. every pixel is processed.
+///
+/// The de-rotated carrier can be averaged over a sliding window of pixels on the RPU, instead
+/// of summing pixels in the lockin, so the lockin can stream single pixels (`nsum = 1`) and
+/// the window can be changed while running, see [`SlidingAverage`]. The averaging is selected
+/// in bits 2-3 of the lockin configuration, and the window length in pixels in bits 16-31:
+/// - 0: no averaging, e.g. with the sliding sum of the lockin
+/// - 1: boxcar, the mean of the last pixels, at most 1024
+/// - 2: exponential moving average, with the window length as time constant
+///
+/// The averages are means, not sums, so the lockin amplitude scale should not include the
+/// number of pixels summed. The last 1024 pixels are always kept, so switching on the boxcar
+/// or changing its length takes effect immediately. The tones of the intermodulation error
+/// signal are not averaged.
+///
+/// # Intermodulation
+/// With the IMOD flag set, the error signal is computed from many tones of the lockin frame
+/// instead, e.g. an intermodulation spectrum. The layout of the frame in the coefficient bank
+/// gives the number of frequencies of each input group, in the order the groups were added to
+/// the lockin; see [`FrameLayout`]. The first 32 tones are scaled with the lockin amplitude
+/// scale and combined into the error signal with the weights of each tone, as selected by the
+/// combination, see [`Combination`]:
+/// - 0: weighted amplitudes `sum w_k A_k`
+/// - 1: weighted quadratures `sum (w_k I_k + wq_k Q_k)`
+/// - 2: weighted phases `sum w_k phi_k`, wrapped to -pi..=pi
+///
+/// Tones without weights are left out, so e.g. the amplitude of tone 3 alone is selected with
+/// combination 0 and a weight of 1.0 for tone 3. The amp^2 filter and the controller act on
+/// this signal as for the other error signals.
+///
+/// # Phase-locked loop
+/// Setting the PLL flag starts a phase-locked loop at the center frequency, see
+/// [`PhaseLockedLoop`]: a PI controller on the phase `atan2(Q, I)` of the lockin data keeps it
+/// at the phase set point, typically -pi/2 on resonance, by shifting the drive frequency. The
+/// gains are in Hz per radian, the integral gain per applied step. With positive gains a phase
+/// above the set point raises the frequency; use negative gains if the phase lag of the
+/// cantilever grows the other way with the lockin phase convention. The frequency shift is
+/// limited to the max frequency shift, read when the PLL starts, and reported in slot 15.
+/// Clearing the PLL flag goes back to the center frequency.
+///
+/// The PLL only takes a step once the APU has applied the last frequency shift and written it
+/// back to bank entry 153, see below. Until then it holds, integral term included, so the
+/// PLL does not wind up without the APU following it.
+///
+/// # Amplitude control
+/// Setting the AGC flag starts a PI controller keeping the oscillation amplitude at the
+/// amplitude set point by adjusting the drive amplitude, starting from the initial drive
+/// amplitude. The amplitude is `sqrt(amp^2)`, independent of the error signal of the Z
+/// feedback, and the drive amplitude is limited to 0.0..=max drive. The gains are in drive
+/// amplitude per amplitude, the integral gain per applied step. The drive amplitude is
+/// reported in slot 15, it is the dissipation signal; it is NaN while the AGC is off.
+///
+/// As the PLL, the AGC only takes a step once the APU has applied the last drive amplitude and
+/// written it back to bank entry 161, and holds until then.
+///
+/// The registers of the Presto output generator are not accessible from the RPU in this
+/// firmware, only the DC bias DAC is. The PLL and the AGC therefore do not retune the drive on
+/// the RPU alone: the APU must apply the frequency and amplitude from slot 15, and the loops
+/// are only as fast as that round trip. The drive and lockin frequencies are the PLL center
+/// frequency plus the frequency shift; once applied, the APU writes the shift back to bank
+/// entry 153, and the drive amplitude to bank entry 161.
+///
+/// # Filters
+/// The amp^2 filter acts on the error signal before the control law, e.g. to reject a
+/// disturbance. The Z bias filter acts on the control signal after the control law, e.g. to
+/// notch out a resonance of the Z piezo; its output is clamped to the Z bias limits. Filter
+/// frequencies are normalized to the iteration rate. The filtered values are reported in
+/// slot 1.
+///
+/// [`Design::from_params`]: qafm_control::filter::Design::from_params
+///
+/// # Autotuning
+/// Setting the TUNE flag starts a relay autotuning around the current set point, see
+/// [`RelayAutotune`]: the Z bias swings by the relay amplitude around its current value, and
+/// the oscillation of amp^2 is measured. The state in slot 14 is 0 idle, 1 running, 2 done or
+/// 3 failed. When done, the Z bias returns to its starting value and the suggested PID gains
+/// are in slots 13 and 14; they are per iteration, also when the PHYS flag is set. The gains
+/// are not applied, the APU decides whether to program them. Clearing the TUNE flag stops the
+/// autotuning at any time, and the feedback continues bumplessly from the current Z bias. A
+/// hold or retract command stops it as well, and it starts over once released if the TUNE flag
+/// is still set.
+///
+/// The relay amplitude must be positive, and should be small compared to the Z bias range.
+/// The number of cycles defaults to 4 and the maximum number of iterations to 100000 if zero.
+///
+/// The relay steps are limited at the DAC like any other Z bias, to the Z limits and the Z
+/// bias slew rate; a slew rate that takes a sizeable part of the oscillation period to cover
+/// twice the relay amplitude makes the suggested gains too high.
+///
+/// # Gain schedule and feedforward
+/// With the SCHED field set, the PID gains are taken from the gain schedule instead of slots 3
+/// and 4, interpolated at the current set point or Z bias, see [`GainSchedule`]. The entries
+/// must have increasing `x`, and the gains are in the same units as slots 3 and 4. The
+/// schedule is off while it has no entries. Scheduled gains are checked like the gains in slots
+/// 3 and 4, see [Validation](#validation).
+///
+/// The feedforward in slot 2 is added to the output of the PID controller, e.g. the
+/// expected topography during a scan, leaving only the residual to the feedback. It is
+/// ignored by the other control laws.
+///
+/// # Network analyzer
+/// Setting the NA flag starts a sweep of the network analyzer, see
+/// [`NetworkAnalyzer`](qafm_control::analyzer::NetworkAnalyzer): a sine perturbation is added to
+/// the output of the control law, and the loop transfer functions are measured at up to 256
+/// frequencies. The feedback must be in auto mode for the results to be meaningful. Frequencies
+/// are normalized to the iteration rate. Clearing the NA flag stops the sweep at any time, and
+/// has to be done before the next sweep can start. The sweep is paused, without perturbation,
+/// while a hold or retract command is in effect.
+///
+/// The results are written to the data area, starting at index 2048, which is beyond the
+/// lockin data:
+///
+/// | idx          | low 32 bits                 | high 32 bits                |
+/// |--------------|-----------------------------|-----------------------------|
+/// | 2048         | nr of points measured       | total nr of points, 0 if invalid |
+/// | 2049 + 4 k   | frequency of point k        | (unused)                    |
+/// | 2050 + 4 k   | open loop, real part        | open loop, imaginary part   |
+/// | 2051 + 4 k   | closed loop, real part      | closed loop, imaginary part |
+/// | 2052 + 4 k   | amp^2 response, real part   | amp^2 response, imag. part  |
+///
+/// See [`Point`] for the definition of the transfer functions.
+///
+/// # Commands
+/// One-shot actions are sent as commands in slot 8, and answered in slot 10, see
+/// [`qafm_control::mailbox`]:
+/// - slot 8: request code in bits 0-15 and sequence number in bits 16-31 of the low half, the
+///   argument in the high half; the APU changes the sequence number for every command
+/// - slot 10: completion code in bits 0-15 and the sequence number of the command answered in
+///   bits 16-31 of the low half, the result in the high half; completion codes are 0 done,
+///   1 running, 2 unknown request, 3 rejected, 4 failed
+///
+/// Commands that take a while are first answered as running, and again once done, unless a
+/// new command has been sent in the meantime. The firmware handles at most one command per
+/// iteration:
+///
+/// | code | command          | argument                 | result                         |
+/// |------|------------------|--------------------------|--------------------------------|
+/// |  1   | reset integrator | Z bias, NaN for current  | Z bias continued from          |
+/// |  2   | hold             | 1 hold, 0 release        | Z bias                         |
+/// |  3   | retract          | Z bias                   | Z bias, once reached           |
+/// |  4   | start scan       | (unused)                 | nr of lines, once done         |
+/// |  5   | stop scan        | (unused)                 | nr of lines done               |
+/// |  6   | self-test        | (unused)                 | bit mask of failed checks      |
+///
+/// Hold and retract override the operating mode in the control word and autotuning, until
+/// released by a hold command with argument 0; the feedback then continues bumplessly. The
+/// retract command runs until the Z bias on the DAC is within 1e-5 of the argument, clamped to
+/// the Z limits, and is rejected for a Z bias outside 0 to 1. See
+/// [`Supervisor`](qafm_control::supervisor::Supervisor).
+///
+/// The raster scan moves the X and Y scanner back and forth over the area in the coefficient
+/// bank, see [`Raster`](qafm_control::scan::Raster), ignoring slot 5 until done or stopped. A
+/// scan of an invalid area is rejected. The X and Y bias move no faster than the scanner
+/// slew-rate limit in bank entry 243, whether from slot 5 or from the scan, so that the
+/// scanner does not jump to the first pixel when a scan starts, nor back to slot 5 when it
+/// ends; a limit that is not positive disables it.
+///
+/// The self-test fails if any of the following, given as bits of the result:
+/// - bit 0: a parameter is rejected, see slot 19
+/// - bit 1: the lockin data is NaN or infinite
+/// - bit 2: the iteration period is not measured yet
+/// - bit 3: the Z bias is at one of its limits
+///
+/// # Physical units
+/// By default the integral and derivative gains and the derivative filter constant are per
+/// iteration, and must be retuned whenever the iteration rate changes. With the PHYS flag set:
+/// - the proportional gain is unchanged
+/// - the integral gain is replaced by the integral time Ti in seconds, 0.0 disables the integral
+/// - the derivative gain is replaced by the derivative time Td in seconds
+/// - the derivative filter constant is in seconds
+///
+/// These are converted to per-iteration values using the sample rate in Hz, or using the
+/// iteration period measured with the CPU cycle counter when the sample rate is 0.0. The
+/// derivative filter constant and the sample rate are in bank entries 252 and 253, a negative
+/// sample rate counts as 0.0.
+///
+/// A derivative filter constant that is not positive, as until the APU writes one, is the
+/// default of 3.5 iterations, whatever the PHYS flag. A constant of 0.5 iterations or less
+/// turns the filter off.
 ///
 pub fn user_logic(data: Data, bias_dac: BiasDac, params: Params) -> ! {
+    // tell the APU which layout to expect
+    slots::IDENTITY.write(&params, (PARAMS_MAGIC, slots::LAYOUT_VERSION));
+    slots::BUILD_SEQUENCE.write(&params, (BUILD_ID, 0));
+
+    // wait for a consistent and valid set of parameters, there are no good values to keep yet
+    let mut snapshot = Snapshot::new(&params, read_generation);
+    loop {
+        let status = validate(&mut snapshot.clone(), &snapshot);
+        slots::PARAM_STATUS.write(&params, status_word(status));
+        if status.is_none() {
+            break;
+        }
+        while !snapshot.update(&params, read_generation) {
+            core::hint::spin_loop();
+        }
+    }
+    let mut good = snapshot.clone();
+    let mut param_status: Status = None;
+    // coefficients of the control law rejected in the last iteration, if any
+    let mut coefficient_rejection = None;
+
     // read lockin scale
-    let scale = read_scale(&params);
+    let (scale, _) = slots::SCALE_FEEDFORWARD.read(&snapshot);
+
+    // read feedback set point and limits
+    let (sp, _) = slots::SETPOINT_KP.read(&snapshot);
+    let (low_lim, high_lim) = slots::Z_LIMITS.read(&snapshot);
+    let (weight_p, weight_d) = slots::SETPOINT_WEIGHTS.read(&snapshot);
+    let (control, _) = slots::CONTROL.read(&snapshot);
 
-    // read feedback set point and gain parameters
-    let (sp, kp, ki, kd) = read_pid_params(&params);
-    let (low_lim, high_lim) = read_z_limits(&params);
+    // coefficients, all zero until written by the APU
+    let mut bank = CoefficientBank::new(&params, slots::BANK_PORT, slots::BANK_ACK);
+    let (_, z_slew) = read_rate_limits(&bank);
 
     // initialize PID controller
     let mut pid_c = PidController::builder()
         .setpoint(sp)
-        .gain_p(kp)
-        .gain_i(ki)
-        .gain_d(kd)
         .limit_output(low_lim, high_lim)
+        .limit_slew_rate(z_slew)
+        .derivative_mode(derivative_mode(control))
+        .setpoint_weights(weight_p, weight_d)
         .build();
 
+    // gains are set separately, since they may depend on the iteration period
+    let mut period = PeriodEstimator::new(RPU_CLOCK_HZ);
+    let ([kp, ki, kd], tau) = read_pid_gains(&snapshot, &bank, control, &period, None);
+    pid_c.set_gains(kp, ki, kd);
+    pid_c.set_derivative_filter(tau);
+
+    // alternative control laws, coefficients are loaded once selected
+    let mut ctrls = Controllers {
+        pid: pid_c,
+        lead_lag: LeadLag::new(low_lim, high_lim),
+        iir: IirCompensator::new(low_lim, high_lim),
+    };
+    let mut law = Law::Pid;
+
+    // Z feedback around the control law, filters bypassed until configured, and relay
+    // autotuning configured from the bank when started
+    let mut feedback = ZFeedback::<FILTER_SECTIONS>::new(scale, sp, (low_lim, high_lim));
+    feedback.output_path_mut().set_slew_rate(z_slew);
+
+    // X and Y scanner, slew-rate limited from the current position
+    let (x, y) = slots::SCANNER_XY.read(&snapshot);
+    let mut scanner = [RateLimiter::new(x, 0.0), RateLimiter::new(y, 0.0)];
+
+    // gain schedule, loaded from the bank
+    let mut schedule = GainSchedule::<SCHEDULE_ENTRIES>::new();
+
+    // network analyzer, started on the rising edge of the NA flag
+    let mut analyzer_armed = true;
+
+    // phase-locked loop, configured from the bank when started
+    let mut pll = PhaseLockedLoop::new(0.0, 0.0);
+    let mut pll_running = false;
+
+    // phase of the intermediate frequency, configured in slot 16
+    let (_, if_step) = read_lockin_config(&snapshot);
+    let mut derotator = Derotator::new(if_step);
+
+    // sliding average of the carrier, configured in slot 16
+    let mut average = SlidingAverage::<AVERAGE_PIXELS>::new();
+
+    // tones of the lockin frame, for the intermodulation error signal
+    let mut spectrum = Spectrum::<IMOD_TONES>::new();
+
+    // amplitude control, configured from the bank when started
+    let mut agc = start_agc(&bank);
+    let mut agc_running = false;
+
+    // commands from the APU, carried out by the feedback
+    let (command_word, _) = slots::COMMAND.read(&snapshot);
+    let mut mailbox = Mailbox::new(command_word);
+
     // no iterations processed yet
     let mut irq_count: u32 = 0;
-    write_irq_count(&params, irq_count);
+    slots::IRQ_COUNT.write(&params, (irq_count, read_cycle_counter()));
+    let mut telemetry = Sequence::new();
+    let mut telemetry_wait: u32 = 0;
 
     // main loop
     loop {
-        // wait until new lockin data is available, then
-        // read new data, assume intermediate frequency is zero
-        let (data_i, data_q) = get_new_data(&data);
+        // layout of the lockin frame, which ends before the results of the network analyzer
+        let layout =
+            FrameLayout::<FRAME_GROUPS>::from_coefficients(bank.get(BANK_FRAME), DATA_ANALYZER);
+
+        // wait until new lockin data is available, then read the carrier of the lockin mode,
+        // de-rotated by the phase of the intermediate frequency
+        let (lockin_mode, if_step) = read_lockin_config(&snapshot);
+        derotator.set_step(if_step);
+        let carrier = lockin_mode.carrier(&layout);
+        let (data_i, data_q) = get_new_data(&data, &mut derotator, carrier);
+
+        // parameters for this iteration, unless the APU is writing; rejected slots keep their
+        // last good value
+        if snapshot.update(&params, read_generation) {
+            param_status = validate(&mut snapshot, &good);
+            good = snapshot.clone();
+        }
+
+        // average over the last pixels
+        let (averaging, window) = read_averaging(&snapshot);
+        average.configure(averaging, window);
+        let (data_i, data_q) = average.update(data_i, data_q);
+        period.update(read_cycle_counter());
+        let (control, _) = slots::CONTROL.read(&snapshot);
+
+        // retune the drive on the phase of the cantilever, started on the rising edge
+        if control & CTRL_PLL != 0 {
+            if !pll_running {
+                pll_running = true;
+                pll = PhaseLockedLoop::from_settings(bank.get(BANK_PLL));
+            }
+            pll.update_applied(phase(data_i, data_q), read_pll_applied(&bank));
+        } else {
+            pll_running = false;
+            pll.reset();
+        }
+        feedback.error_path_mut().set_frequency_shift(pll.shift());
+
+        // combine the tones of the full lockin frame
+        if control & CTRL_IMOD != 0 {
+            read_spectrum(&data, &layout, &derotator, scale, &mut spectrum);
+            let (combination, weights) = read_intermodulation(&bank);
+            let error_path = feedback.error_path_mut();
+            error_path.set_intermodulation(spectrum.combine(combination, weights));
+        }
 
-        // rescale
-        let data_i = data_i * scale;
-        let data_q = data_q * scale;
+        // keep the oscillation amplitude constant with the drive, started on the rising edge
+        let drive = if control & CTRL_AGC != 0 {
+            if !agc_running {
+                agc_running = true;
+                agc = start_agc(&bank);
+            }
+            load_agc(&mut agc, &bank);
+            let applied = read_agc_applied(&bank);
+            agc.update_applied(amplitude(data_i * scale, data_q * scale), applied)
+        } else {
+            agc_running = false;
+            f32::NAN
+        };
 
-        // calculate amplitude A^2 = I^2 + Q^2
-        let amp2 = (data_i * data_i) + (data_q * data_q);
+        // perturbation of the network analyzer, started on the rising edge of the NA flag
+        let analyzer = feedback.analyzer_mut();
+        if control & CTRL_ANALYZER != 0 {
+            if analyzer_armed {
+                analyzer_armed = false;
+                let sweep = read_analyzer_sweep(&bank);
+                analyzer.start(sweep);
+                let total = if analyzer.is_running() {
+                    sweep.points
+                } else {
+                    0
+                };
+                write_analyzer_progress(&data, 0, total);
+            }
+        } else {
+            analyzer.stop();
+            analyzer_armed = true;
+        }
 
-        // new feedback value
-        let bias_norm = pid_c.update(amp2);
+        // new feedback value, in the mode selected by the APU: error signal, by default
+        // amplitude A^2 = I^2 + Q^2, control law and perturbation
+        let tune = (control & CTRL_TUNE != 0).then(|| bank.get(BANK_AUTOTUNE));
+        let mode = read_mode(&snapshot, control);
+        let bias_z = feedback.update(ctrls.get(law), (data_i, data_q), mode, tune);
+        let (error, bias_norm) = (feedback.error(), feedback.output());
 
-        // set new DC bias: Z piezo
-        set_dc_bias(&bias_dac, 0, bias_norm); // port 1
+        // set new DC bias: Z piezo, within the Z limits and slew rate whatever the mode
+        set_dc_bias(&bias_dac, 0, bias_z); // port 1
 
-        // let APU know current amp^2 (error) and bias (control) values
-        write_pid_error_control(&params, amp2, bias_norm);
+        // let APU know current error and bias (control) values, autotuning results and drive,
+        // as a consistent group
+        telemetry_wait = telemetry_wait.saturating_sub(1);
+        if telemetry_wait == 0 {
+            telemetry_wait = read_telemetry_period(&bank);
+            let publish = |seq| slots::BUILD_SEQUENCE.write(&params, (BUILD_ID, seq));
+            telemetry.write(publish, || {
+                slots::ERROR_CONTROL.write(&params, (error, bias_z));
+                write_autotune(&params, feedback.tuner());
+                slots::DRIVE.write(&params, (pll.shift(), drive));
+            });
+        }
+        let analyzer = feedback.analyzer_mut();
+        if let Some((index, point)) = analyzer.completed() {
+            write_analyzer_point(&data, index, &point);
+            let (done, total) = analyzer.progress();
+            write_analyzer_progress(&data, done, total);
+        }
+
+        // new coefficients from the APU
+        bank.poll(&params);
+
+        // one-shot commands from the APU, a new command replaces the one still running
+        let (command_word, argument) = slots::COMMAND.read(&snapshot);
+        if let Some(command) = mailbox.receive(command_word, argument) {
+            let limits = ctrls.get(law).limits().range();
+            let area = || ScanArea::from_coefficients(bank.get(BANK_SCAN));
+            let self_test =
+                || self_test(param_status, (data_i, data_q), &period, bias_norm, limits);
+            let response = feedback.execute(command, ctrls.get(law), area, self_test);
+            slots::RESPONSE.write(&params, response.encode());
+        }
+
+        // report the first slot rejected, or else a coefficient rejected by the bank or by the
+        // control law
+        let bank_status = bank
+            .rejection()
+            .or(coefficient_rejection)
+            .map(|rejection| (slots::BANK_PORT.idx(), rejection));
+        slots::PARAM_STATUS.write(&params, status_word(param_status.or(bank_status)));
+
+        // answer the command still running once done
+        if let Some(response) = feedback.poll(ctrls.get(law).limits().range()) {
+            slots::RESPONSE.write(&params, response.encode());
+        }
 
         // update feedback parameters for next iteration
-        (pid_c.setpoint, pid_c.kp, pid_c.ki, pid_c.kd) = read_pid_params(&params);
+        let error_path = feedback.error_path_mut();
+        error_path.set_signal(error_signal(control));
+        error_path.set_phase_reference(bank.get(BANK_PHASE_REFERENCE)[0]);
+        error_path
+            .filter_mut()
+            .load_designs(bank.get(BANK_AMP2_FILTER));
+        pll.load_settings(bank.get(BANK_PLL));
+        let (sp_rate, z_slew) = read_rate_limits(&bank);
+        let output_path = feedback.output_path_mut();
+        output_path
+            .filter_mut()
+            .load_designs(bank.get(BANK_Z_FILTER));
+        output_path.set_slew_rate(z_slew);
+        let (sp, _) = slots::SETPOINT_KP.read(&snapshot);
+        let setpoint = feedback.ramp_setpoint(sp, sp_rate);
+
+        // PID-specific parameters, gains are loaded below as for any other law
+        schedule.load_counted(bank.get(BANK_SCHEDULE));
+        let scheduled = match sched_source(control) {
+            Sched::Off => None,
+            Sched::Setpoint => schedule.lookup(setpoint),
+            Sched::ZBias => schedule.lookup(bias_norm),
+        };
+        let (pid_gains, tau) = read_pid_gains(&snapshot, &bank, control, &period, scheduled);
+        let (_, feedforward) = slots::SCALE_FEEDFORWARD.read(&snapshot);
+        ctrls.pid.set_feedforward(feedforward);
+        ctrls.pid.load_options(bank.get(BANK_PID_OPTIONS));
+        let (weight_p, weight_d) = slots::SETPOINT_WEIGHTS.read(&snapshot);
+        ctrls.pid.set_derivative_filter(tau);
+        ctrls.pid.set_setpoint_weights(weight_p, weight_d);
+        ctrls.pid.set_derivative_mode(derivative_mode(control));
 
-        // set X and Y scanner bias
-        let (bias_x, bias_y) = read_scanner_xy(&params);
-        set_dc_bias(&bias_dac, 1, bias_x); // port 2
-        set_dc_bias(&bias_dac, 2, bias_y); // port 3
+        // switch control law, continue from the current Z bias
+        let new_law = control_law(control);
+        if new_law != law {
+            law = new_law;
+            ctrls.get(law).reset(bias_norm);
+        }
+        let coefficients = match law {
+            Law::Pid => &pid_gains,
+            Law::LeadLag => bank.get(BANK_LEAD_LAG),
+            Law::Iir => bank.get(BANK_IIR),
+        };
+        // rejected coefficients are not loaded, the control law keeps its last ones
+        coefficient_rejection = ctrls.get(law).check_coefficients(coefficients).err();
+        let coefficients = match coefficient_rejection {
+            None => coefficients,
+            Some(_) => &[],
+        };
+        ctrls.get(law).load_params(&ControllerParams {
+            setpoint,
+            slew: z_slew,
+            coefficients,
+        });
 
-        // let APU know how many iterations we have processed
+        // set X and Y scanner bias, from the raster scan if running, no faster than the
+        // slew-rate limit also when a scan starts or ends
+        let (target_x, target_y) = feedback
+            .scan()
+            .unwrap_or_else(|| slots::SCANNER_XY.read(&snapshot));
+        let [ramp_x, ramp_y] = &mut scanner;
+        let xy_slew = bank.get(BANK_SCANNER_SLEW)[0];
+        ramp_x.set_max_step(xy_slew);
+        ramp_y.set_max_step(xy_slew);
+        set_dc_bias(&bias_dac, 1, ramp_x.update(target_x)); // port 2
+        set_dc_bias(&bias_dac, 2, ramp_y.update(target_y)); // port 3
+
+        // let APU know how many iterations we have processed, and the CPU cycle count so it's
+        // possible to calculate a rate
         irq_count += 1;
-        write_irq_count(&params, irq_count);
+        slots::IRQ_COUNT.write(&params, (irq_count, read_cycle_counter()));
     }
 }
 
-/// Wait until new data is available, then return I and Q quadrature of first frequency.
+/// Read per-iteration PID gains `[kp, ki, kd]` and derivative filter constant, converting from
+/// physical units if requested by the control word. A filter constant that is not positive,
+/// e.g. not written yet, is the default of the PID controller.
 ///
-/// Assumes:
-/// - using Lockin (not SymmetricLockin)
-/// - using digital downconversion (adc_mode=AdcMode.Mixed)
-/// - using zero IF
-fn get_new_data(data: &Data) -> (f32, f32) {
-    wait_for_new_data();
-    let (data_i, data_q) = u64_to_f32x2(data.idx(0).read());
-    (data_i, data_q)
+/// `scheduled` gains from the gain schedule take precedence over the gains in the parameter map.
+fn read_pid_gains(
+    snapshot: &Snapshot,
+    bank: &CoefficientBank,
+    control: u32,
+    period: &PeriodEstimator,
+    scheduled: Option<[f32; 3]>,
+) -> ([f32; 3], f32) {
+    let (_, kp) = slots::SETPOINT_KP.read(snapshot);
+    let (ki, kd) = slots::KI_KD.read(snapshot);
+    let [kp, ki, kd] = scheduled.unwrap_or([kp, ki, kd]);
+    let filter = bank.get(BANK_DERIVATIVE_FILTER);
+    let (tau, sample_rate) = (Some(filter[0]).filter(|&tau| tau > 0.0), filter[1]);
+    if control & CTRL_PHYSICAL_UNITS != 0 {
+        // configured sample rate takes precedence over the measured one
+        let ts = if sample_rate > 0.0 {
+            1.0 / sample_rate
+        } else {
+            period.seconds()
+        };
+        // ki and kd are the integral and derivative time
+        let (kp, ki, kd) = discretize_gains(kp, ki, kd, ts);
+        // no filter until the sample period is known, kd is zero anyway
+        let tau = match tau {
+            Some(tau) if ts > 0.0 => tau / ts,
+            Some(_) => 0.5,
+            None => DEFAULT_TAU,
+        };
+        ([kp, ki, kd], tau)
+    } else {
+        ([kp, ki, kd], tau.unwrap_or(DEFAULT_TAU))
+    }
 }
 
-/// Read PID controller parameters from memory:
-/// - set point
-/// - proportional gain
-/// - integral gain
-/// - derivative gain
-fn read_pid_params(params: &Params) -> (f32, f32, f32, f32) {
-    let (sp, kp) = u64_to_f32x2(params.idx(3).read());
-    let (ki, kd) = u64_to_f32x2(params.idx(4).read());
-    (sp, kp, ki, kd)
+/// Control law selected by the control word
+fn control_law(control: u32) -> Law {
+    match (control & CTRL_LAW_MASK) >> CTRL_LAW_SHIFT {
+        1 => Law::LeadLag,
+        2 => Law::Iir,
+        _ => Law::Pid,
+    }
 }
 
-/// Read normalized bias for X and Y piezo
-fn read_scanner_xy(params: &Params) -> (f32, f32) {
-    let (x, y) = u64_to_f32x2(params.idx(5).read());
-    (x, y)
+/// Error signal selected by the control word
+fn error_signal(control: u32) -> ErrorSignal {
+    if control & CTRL_IMOD != 0 {
+        return ErrorSignal::Intermodulation;
+    }
+    match (control & CTRL_SIGNAL_MASK) >> CTRL_SIGNAL_SHIFT {
+        1 => ErrorSignal::Amplitude,
+        2 => ErrorSignal::Phase,
+        3 => ErrorSignal::FrequencyShift,
+        _ => ErrorSignal::AmplitudeSquared,
+    }
 }
 
-/// Read output limits for normalized bias for Z piezo
-fn read_z_limits(params: &Params) -> (f32, f32) {
-    let (low, high) = u64_to_f32x2(params.idx(6).read());
+/// Read the frequency shift the APU applied to the drive from the coefficient bank
+fn read_pll_applied(bank: &CoefficientBank) -> f32 {
+    bank.get(BANK_PLL)[5]
+}
 
-    // low should be at least 0.0 and high at most 1.0
-    let low = f32::max(low, 0.0);
-    let high = f32::min(high, 1.0);
+/// Create the amplitude control from the settings in the coefficient bank, starting from the
+/// initial drive amplitude
+fn start_agc(bank: &CoefficientBank) -> PidController {
+    let settings = bank.get(BANK_AGC);
+    let mut agc = PidController::builder()
+        .limit_output(0.0, settings[3].max(0.0))
+        .anti_windup(AntiWindup::BackCalculation { kt: 0.5 })
+        .build();
+    agc.reset(settings[4]);
+    agc
+}
 
-    (low, high)
+/// Read the drive amplitude the APU applied from the coefficient bank
+fn read_agc_applied(bank: &CoefficientBank) -> f32 {
+    bank.get(BANK_AGC)[5]
 }
 
-/// Read scaling factor for lockin data
-fn read_scale(params: &Params) -> f32 {
-    let (scale, _) = u64_to_f32x2(params.idx(2).read());
-    scale
+/// Load amplitude set point and gains of the amplitude control from the coefficient bank
+fn load_agc(agc: &mut PidController, bank: &CoefficientBank) {
+    let settings = bank.get(BANK_AGC);
+    agc.setpoint = settings[0];
+    agc.set_gains(settings[1], settings[2], 0.0);
 }
 
-/// Write number of processed IRQs back to APU.
-///
-/// Write also current count of CPU cycles, so it's possible to calculate a rate.
-fn write_irq_count(params: &Params, count: u32) {
-    let val = u32x2_to_u64(count, read_cycle_counter());
-    params.idx(0).write(val);
+/// Read number of iterations between telemetry updates from the coefficient bank, at least 1
+fn read_telemetry_period(bank: &CoefficientBank) -> u32 {
+    count(bank.get(BANK_TELEMETRY)[0]).unwrap_or(1)
 }
 
-/// Write error signal and control signal from PID controller back to APU
-fn write_pid_error_control(params: &Params, error: f32, control: f32) {
-    let val = f32x2_to_u64(error, control);
-    params.idx(1).write(val);
+/// Write autotuning results and state back to APU, gains are NaN until done
+fn write_autotune(params: &Params, tuner: &RelayAutotune) {
+    let [kp, ki, kd] = match tuner.result() {
+        Some(result) => result.pid_gains(),
+        None => [f32::NAN; 3],
+    };
+    slots::AUTOTUNE_KP_KI.write(params, (kp, ki));
+    slots::AUTOTUNE_KD_STATE.write(params, (kd, tuner.state().code()));
 }
 
-/// Convenience function to extract two f32 values from one u64 value
-fn u64_to_f32x2(val: u64) -> (f32, f32) {
-    let low = f32::from_bits(val as u32);
-    let high = f32::from_bits((val >> 32) as u32);
-    (low, high)
+/// Operating mode selected by the control word, with the manual or tracked Z bias
+fn read_mode(snapshot: &Snapshot, control: u32) -> Mode {
+    if control & CTRL_HOLD != 0 {
+        return Mode::Hold;
+    }
+    let (_, value) = slots::CONTROL.read(snapshot);
+    match (control & CTRL_MODE_MASK) >> CTRL_MODE_SHIFT {
+        1 => Mode::Hold,
+        2 => Mode::Manual(value),
+        3 => Mode::Track(value),
+        _ => Mode::Auto,
+    }
 }
 
-/// Convenience function to pack two f32 values into one u64 value
-fn f32x2_to_u64(low: f32, high: f32) -> u64 {
-    let low = low.to_bits();
-    let high = high.to_bits();
-    let mut val = low as u64;
-    val |= (high as u64) << 32;
-    val
+/// Read network analyzer sweep from the coefficient bank
+fn read_analyzer_sweep(bank: &CoefficientBank) -> Sweep {
+    let sweep = Sweep::from_coefficients(bank.get(BANK_ANALYZER));
+    Sweep {
+        points: sweep.points.min(ANALYZER_MAX_POINTS),
+        ..sweep
+    }
+}
+
+/// Checks of the self-test command, as a bit mask of the checks failed
+fn self_test(
+    param_status: Status,
+    (data_i, data_q): (f32, f32),
+    period: &PeriodEstimator,
+    bias_norm: f32,
+    (low, high): (f32, f32),
+) -> u32 {
+    let mut failed = 0;
+    if param_status.is_some() {
+        failed |= TEST_PARAMS;
+    }
+    if !(data_i.is_finite() && data_q.is_finite()) {
+        failed |= TEST_LOCKIN;
+    }
+    if period.seconds() <= 0.0 {
+        failed |= TEST_PERIOD;
+    }
+    if bias_norm <= low || bias_norm >= high {
+        failed |= TEST_Z_LIMIT;
+    }
+    failed
+}
+
+/// Write number of points measured by the network analyzer to the data area
+fn write_analyzer_progress(data: &Data, done: u32, total: u32) {
+    data.idx(DATA_ANALYZER).write(u32x2_to_u64(done, total));
+}
+
+/// Write a point measured by the network analyzer to the data area
+fn write_analyzer_point(data: &Data, index: u32, point: &Point) {
+    let start = DATA_ANALYZER + 1 + ANALYZER_POINT_WORDS * index as usize;
+    let values = [
+        [point.f, 0.0],
+        point.open_loop,
+        point.closed_loop,
+        point.error,
+    ];
+    for (i, [low, high]) in values.into_iter().enumerate() {
+        data.idx(start + i).write(f32x2_to_u64(low, high));
+    }
+}
+
+/// Operating point of the gain schedule selected by the control word
+fn sched_source(control: u32) -> Sched {
+    match (control & CTRL_SCHED_MASK) >> CTRL_SCHED_SHIFT {
+        1 => Sched::Setpoint,
+        2 => Sched::ZBias,
+        _ => Sched::Off,
+    }
+}
+
+/// Read set point ramp rate and Z bias slew-rate limit from the coefficient bank, a rate that
+/// is not positive disables the limit
+fn read_rate_limits(bank: &CoefficientBank) -> (f32, f32) {
+    let limits = bank.get(BANK_RATE_LIMITS);
+    (limits[0], limits[1])
+}
+
+/// Derivative mode selected by the control word
+fn derivative_mode(control: u32) -> DerivativeMode {
+    if control & CTRL_DERIV_ON_ERROR != 0 {
+        DerivativeMode::OnError
+    } else {
+        DerivativeMode::OnMeasurement
+    }
+}
+
+/// Read the first tones of the lockin frame with `layout`, de-rotated and scaled by `scale`
+fn read_spectrum(
+    data: &Data,
+    layout: &FrameLayout<FRAME_GROUPS>,
+    derotator: &Derotator,
+    scale: f32,
+    spectrum: &mut Spectrum<IMOD_TONES>,
+) {
+    spectrum.clear();
+    for k in 0..layout.len().min(IMOD_TONES) {
+        let (i, q) = unpack(data.idx(k).read());
+        let (i, q) = derotator.apply(i, q);
+        spectrum.push(i * scale, q * scale);
+    }
+}
+
+/// Read lockin configuration:
+/// - kind of lockin
+/// - phase advance of the intermediate frequency per pixel, in units of 2^-32 cycles
+fn read_lockin_config(snapshot: &Snapshot) -> (LockinMode, u32) {
+    let (config, if_step) = slots::LOCKIN_CONFIG.read(snapshot);
+    let mode = LockinMode::from_code(config & LOCKIN_MODE_MASK).unwrap_or_default();
+    (mode, if_step)
+}
+
+/// Read averaging of the carrier from the lockin configuration:
+/// - averaging, off if invalid
+/// - window length in pixels
+fn read_averaging(snapshot: &Snapshot) -> (Averaging, usize) {
+    let (config, _) = slots::LOCKIN_CONFIG.read(snapshot);
+    let code = (config & LOCKIN_AVERAGE_MASK) >> LOCKIN_AVERAGE_SHIFT;
+    let averaging = Averaging::from_code(code).unwrap_or_default();
+    (averaging, (config >> LOCKIN_WINDOW_SHIFT) as usize)
+}
+
+/// Read the combination of the intermodulation signal, and the weights of the tones, from the
+/// coefficient bank
+fn read_intermodulation(bank: &CoefficientBank) -> (Combination, &[f32]) {
+    let settings = bank.get(BANK_IMOD);
+    let combination = code(settings[0])
+        .and_then(Combination::from_code)
+        .unwrap_or_default();
+    (combination, &settings[1..])
+}
+
+/// Wait until new data is available, then return I and Q quadrature of the `carrier` word of
+/// the lockin frame, de-rotated by the phase of the intermediate frequency.
+///
+/// Assumes every pixel is processed, since the phase of the intermediate frequency advances
+/// once per call.
+fn get_new_data(data: &Data, derotator: &mut Derotator, carrier: usize) -> (f32, f32) {
+    wait_for_new_data();
+    derotator.advance();
+    let (data_i, data_q) = unpack(data.idx(carrier).read());
+    derotator.apply(data_i, data_q)
 }
 
-/// Convenience function to pack two u32 values into one u64 value
-fn u32x2_to_u64(low: u32, high: u32) -> u64 {
-    let mut val = low as u64;
-    val |= (high as u64) << 32;
-    val
+/// Read generation of the parameters from the control word, odd while the APU is writing
+fn read_generation(params: &Params) -> u32 {
+    let (control, _) = U32F32::unpack(slots::CONTROL.read_raw(params));
+    generation_field(control, CTRL_GEN_SHIFT)
 }
@@ -0,0 +1,104 @@
+//! Validation of the parameters written by the APU.
+//!
+//! A typo on the APU, e.g. a NaN gain or swapped Z limits, must never reach the Z piezo. Every
+//! slot read by the RPU is checked whenever a new [`Snapshot`] is taken, and a slot that is
+//! rejected keeps its last good value, as a whole. The control word is the exception: a NaN
+//! manual Z bias keeps the previous Z bias, but the flags next to it still apply, so that e.g.
+//! a hold or a new generation is never lost. The first slot rejected is reported to the APU,
+//! see [`status_word`].
+//!
+//! Values written to the coefficient bank are not checked here: the bank rejects values that
+//! are not finite as they are written, see [`CoefficientBank`](crate::bank::CoefficientBank),
+//! and the control laws check their coefficients before they are loaded, see
+//! [`Controller::check_coefficients`](qafm_control::controller::Controller::check_coefficients).
+//! The rules themselves are in [`qafm_control::validate`].
+
+use crate::registry::{Read, Slot, SlotType, Snapshot};
+use crate::slots;
+use qafm_control::validate::{at_least, finite, increasing, normalized, positive, Rejection};
+
+/// Outcome of a validation: the index of the first slot rejected and why, if any.
+pub type Status = Option<(usize, Rejection)>;
+
+/// Status word for the APU: the code of the rejection, 0 if all slots are valid, and the index
+/// of the slot.
+pub fn status_word(status: Status) -> (u32, u32) {
+    match status {
+        Some((idx, rejection)) => (rejection.code(), idx as u32),
+        None => (0, 0),
+    }
+}
+
+/// Check all slots read by the RPU in `snapshot`, going back to the values in `good` for the
+/// slots rejected.
+pub fn validate(snapshot: &mut Snapshot, good: &Snapshot) -> Status {
+    let mut validator = Validator {
+        snapshot,
+        good,
+        status: None,
+    };
+
+    validator.check(slots::SCALE_FEEDFORWARD, |(scale, feedforward)| {
+        finite(&[scale, feedforward])?;
+        positive(scale)
+    });
+    validator.check(slots::SETPOINT_KP, |(setpoint, kp)| {
+        finite(&[setpoint, kp])?;
+        at_least(kp, 0.0)
+    });
+    validator.check(slots::KI_KD, |(ki, kd)| {
+        finite(&[ki, kd])?;
+        at_least(ki, 0.0)?;
+        at_least(kd, 0.0)
+    });
+    validator.check(slots::SCANNER_XY, |(x, y)| {
+        finite(&[x, y])?;
+        normalized(x)?;
+        normalized(y)
+    });
+    validator.check(slots::Z_LIMITS, |(low, high)| {
+        finite(&[low, high])?;
+        normalized(low)?;
+        normalized(high)?;
+        increasing(low, high)
+    });
+    validator.check_high(slots::CONTROL, |(_, z_bias)| {
+        finite(&[z_bias])?;
+        normalized(z_bias)
+    });
+    validator.check(slots::SETPOINT_WEIGHTS, |(b, c)| finite(&[b, c]));
+
+    validator.status
+}
+
+struct Validator<'a> {
+    snapshot: &'a mut Snapshot,
+    good: &'a Snapshot,
+    status: Status,
+}
+impl Validator<'_> {
+    /// Check the value of `slot`, and go back to the good value if rejected.
+    fn check<T: SlotType>(
+        &mut self,
+        slot: Slot<T, Read>,
+        check: impl FnOnce(T::Value) -> Result<(), Rejection>,
+    ) {
+        if let Err(rejection) = check(slot.read(self.snapshot)) {
+            self.snapshot.restore(self.good, &slot);
+            self.status = self.status.or(Some((slot.idx(), rejection)));
+        }
+    }
+
+    /// Like [`Validator::check`], but only the high half of `slot` goes back to the good value
+    /// if rejected, the low half is kept.
+    fn check_high<T: SlotType>(
+        &mut self,
+        slot: Slot<T, Read>,
+        check: impl FnOnce(T::Value) -> Result<(), Rejection>,
+    ) {
+        if let Err(rejection) = check(slot.read(self.snapshot)) {
+            self.snapshot.restore_high(self.good, &slot);
+            self.status = self.status.or(Some((slot.idx(), rejection)));
+        }
+    }
+}
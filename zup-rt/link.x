@@ -37,6 +37,12 @@ SECTIONS
     KEEP(*(.resource_table));
   } > BTCM0
 
+  /* Descriptors for host tooling, kept in the ELF file but not loaded */
+  .qafm.params (INFO) :
+  {
+    KEEP(*(.qafm.params));
+  }
+
   /* Discarded sections */
   /DISCARD/ :
   {